@@ -0,0 +1,10 @@
+# defines a function
+def add(a, b):
+    return a + b
+"""this is multi line comment"""
+# main function to execute the code
+def main():
+    print("Hello, World!")
+    print(add(5, 10))
+if (__name__ == "__main__"):
+    main()
@@ -1,5 +1,9 @@
+use nwparser::Diagnostic;
+use nwtranspiler::TranspileOptions;
+use nwtranspiler::options::OPTION_NAMES;
 use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
 
 fn main() {
@@ -14,11 +18,17 @@ fn main() {
         print_help();
         process::exit(1);
     }
+    if let Some(flag) = unknown_flag(&args[2..]) {
+        eprintln!("Error: unknown option `--{}` (see --help)", flag);
+        process::exit(1);
+    }
     let filename = &args[1];
     let run_flag = args.iter().any(|a| a == "--run");
     let reverse_flag = args.iter().any(|a| a == "--reverse-transpile");
     let format_flag = args.iter().any(|a| a == "--format");
     let in_place_flag = args.iter().any(|a| a == "--in-place");
+    let json_errors = args.iter().any(|a| a == "--error-format=json");
+    let c_style_flag = args.iter().any(|a| a == "--c-style");
     let source = match fs::read_to_string(filename) {
         Ok(s) => s,
         Err(e) => {
@@ -30,12 +40,11 @@ fn main() {
 
     // Formatter-only mode: format NWPython source and print or write
     if format_flag && !is_py_input && !reverse_flag {
-        let formatted = nwformatter::format_nwpython(&source);
-        if in_place_flag {
-            if let Err(e) = fs::write(filename, &formatted) {
-                eprintln!("Error writing formatted file: {}", e);
-                process::exit(1);
-            }
+        let (formatted, diagnostics) = nwformatter::format_nwpython_checked(&source);
+        report(&diagnostics, &source, filename, json_errors);
+        if in_place_flag && let Err(e) = fs::write(filename, &formatted) {
+            eprintln!("Error writing formatted file: {}", e);
+            process::exit(1);
         }
         println!("{}", formatted);
         return;
@@ -43,7 +52,10 @@ fn main() {
 
     if reverse_flag || is_py_input {
         // Reverse transpile Python -> NWPython
-        let mut nw_code = nwtranspiler::reverse_transpiler::reverse_transpile(&source);
+        let opts = nwtranspiler::reverse_transpiler::ReverseOptions {
+            c_style: c_style_flag,
+        };
+        let mut nw_code = nwtranspiler::reverse_transpiler::reverse_transpile_with(&source, &opts);
         if format_flag {
             nw_code = nwformatter::format_nwpython(&nw_code);
         }
@@ -63,8 +75,21 @@ fn main() {
     }
 
     // Regular transpile: NWPython -> Python
-    let tokens = nwparser::tokenize(&source);
-    let py = nwtranspiler::transpile(&tokens);
+    let options = match transpile_options(&args, filename) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    let tokens = nwparser::tokenize_spanned(&source);
+    let py = match nwtranspiler::transpile_with(&tokens, &options) {
+        Ok(py) => py,
+        Err(diagnostics) => {
+            report(&diagnostics, &source, filename, json_errors);
+            process::exit(1);
+        }
+    };
     println!("{}", py);
     // Write to a .py file with the same base name as the input file
     let py_path = if let Some(pos) = filename.rfind('.') {
@@ -105,15 +130,115 @@ fn main() {
     }
 }
 
+/// Flags read by `main` itself; any other `--flag` must be a transpiler option
+const CLI_FLAGS: &[&str] = &[
+    "help",
+    "run",
+    "reverse-transpile",
+    "c-style",
+    "format",
+    "in-place",
+    "config",
+    "error-format",
+];
+
+/// The first `--flag` in `args` that is neither a CLI flag nor a transpiler
+/// option, without its `--` and value
+fn unknown_flag(args: &[String]) -> Option<&str> {
+    args.iter()
+        .filter_map(|a| a.strip_prefix("--"))
+        .map(|flag| flag.split_once('=').map_or(flag, |(name, _)| name))
+        .find(|name| !CLI_FLAGS.contains(name) && !OPTION_NAMES.contains(name))
+}
+
+/// Transpiler options from the config file (`--config=PATH`, or `nwpython.toml`
+/// next to the source file), overridden by `--name=value` and `--name` flags
+fn transpile_options(args: &[String], filename: &str) -> Result<TranspileOptions, String> {
+    let config_path = match args.iter().find_map(|a| a.strip_prefix("--config=")) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            let path = Path::new(filename).with_file_name("nwpython.toml");
+            path.exists().then_some(path)
+        }
+    };
+    let mut options = match config_path {
+        Some(path) => {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+            TranspileOptions::from_config(&text)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        None => TranspileOptions::default(),
+    };
+    for arg in &args[1..] {
+        let Some(flag) = arg.strip_prefix("--") else {
+            continue;
+        };
+        let (name, value) = flag.split_once('=').unwrap_or((flag, "true"));
+        if OPTION_NAMES.contains(&name) {
+            options.set(name, value)?;
+        }
+    }
+    Ok(options)
+}
+
+/// Print diagnostics to stderr, rustc-style or as a JSON array
+fn report(diagnostics: &[Diagnostic], source: &str, filename: &str, json: bool) {
+    if diagnostics.is_empty() {
+        return;
+    }
+    if json {
+        eprintln!(
+            "{}",
+            nwparser::diagnostic::to_json_array(diagnostics, filename)
+        );
+    } else {
+        for diagnostic in diagnostics {
+            eprintln!("{}", diagnostic.render(source, filename));
+        }
+    }
+}
+
 fn print_help() {
     println!("nwcli — NWPython toolchain CLI\n");
     println!("Usage: nwcli <source.nwpy|source.py> [options]");
     println!("Options:");
     println!("  -h, --help                Show this help message and exit");
-    println!("      --run                 After transpiling NWPython -> Python, run the generated Python");
+    println!(
+        "      --run                 After transpiling NWPython -> Python, run the generated Python"
+    );
     println!("      --reverse-transpile   Convert Python -> NWPython (saves .nwpy next to input)");
-    println!("      --format              Run the NWPython formatter on reverse-transpile output or on .nwpy input");
-    println!("      --in-place            When used with --format, overwrite the input file with formatted output");
+    println!(
+        "      --c-style             With --reverse-transpile, write &&, ||, !, true, false and null"
+    );
+    println!(
+        "      --format              Run the NWPython formatter on reverse-transpile output or on .nwpy input"
+    );
+    println!(
+        "      --in-place            When used with --format, overwrite the input file with formatted output"
+    );
+    println!(
+        "      --target-version=3.X  Python version the output must run on (default 3.10; before 3.10 `switch` never uses `match` and `match` is an error)"
+    );
+    println!(
+        "      --indent=N|tab        Indent the generated Python with N spaces (default 4) or tabs"
+    );
+    println!(
+        "      --comments=STYLE      docstring (default: /* */ becomes \"\"\"...\"\"\"), hash (all # comments) or strip"
+    );
+    println!("      --implicit-return     Return a function's last expression when it has no `;`");
+    println!(
+        "      --strictness=lossy    Write the Python even when there are errors, listing them as # ERROR: comments"
+    );
+    println!(
+        "      --banner              Start the generated Python with a \"do not edit\" comment"
+    );
+    println!(
+        "      --config=PATH         Read these options from PATH instead of nwpython.toml next to the source"
+    );
+    println!(
+        "      --error-format=json   Print diagnostics as a JSON array instead of rustc-style text"
+    );
     println!("\nExamples:");
     println!("  nwcli source.nwpy           # transpile to Python and write source.py");
     println!("  nwcli source.nwpy --run     # transpile and run the generated Python");
@@ -1,12 +1,49 @@
 //! Core NWPython formatter logic
 
+use nwparser::diagnostic::{codes, Diagnostic};
+use nwparser::span::Span;
+
 pub fn format_nwpython(code: &str) -> String {
+    format_nwpython_checked(code).0
+}
+
+/// Format NWPython source, also reporting unbalanced braces and unterminated comments
+pub fn format_nwpython_checked(code: &str) -> (String, Vec<Diagnostic>) {
     let mut result = String::new();
     let mut indent = 0;
     let mut in_multiline_comment = false;
-    let lines: Vec<&str> = code.lines().collect();
-    for line in lines {
+    let mut diagnostics = Vec::new();
+    // Where each currently open `{` and the pending `/*` are in the source
+    let mut open_braces: Vec<Span> = Vec::new();
+    // For each open block, whether it holds `case` labels, which indent the
+    // statements after them one more level
+    let mut has_cases: Vec<bool> = Vec::new();
+    // For each open block, whether a label such as `case 1: {` opened it;
+    // its statements are already indented by the label
+    let mut label_blocks: Vec<bool> = Vec::new();
+    // Brackets and dict/set braces left open by the lines so far, and whether
+    // the statement they belong to is a decorator, which takes no `;`, or a
+    // block header, whose last `{` opens a block
+    let mut paren_depth = 0;
+    // The bracket depth before each line that left brackets open; lines
+    // inside them are indented one level per entry
+    let mut hanging: Vec<i32> = Vec::new();
+    let mut in_decorator = false;
+    let mut in_header = false;
+    let mut comment_start = Span::default();
+    let mut line_start = 0;
+    for (idx, raw) in code.split_inclusive('\n').enumerate() {
+        let line = raw.trim_end_matches(['\n', '\r']);
+        let offset = line_start;
+        line_start += raw.len();
         let trimmed = line.trim();
+        let indent_len = line.len() - line.trim_start().len();
+        // Span of the byte at `pos` within `trimmed`
+        let span_at = |pos: usize| {
+            let start = offset + indent_len + pos;
+            let col = line[..indent_len + pos].chars().count() + 1;
+            Span::new(start, start + 1, idx + 1, col)
+        };
         if trimmed.is_empty() {
             result.push('\n');
             continue;
@@ -22,6 +59,7 @@ pub fn format_nwpython(code: &str) -> String {
             }
             // Print the opening line as-is and enter multi-line mode
             in_multiline_comment = true;
+            comment_start = span_at(0);
             result.push_str(&"    ".repeat(indent));
             result.push_str(trimmed);
             result.push('\n');
@@ -36,15 +74,80 @@ pub fn format_nwpython(code: &str) -> String {
             }
             continue;
         }
-    // Handle inline or full-line single-line comments starting with // or #
-    if let Some(pos) = trimmed.find("//").or_else(|| trimmed.find('#')) {
+        let continuation = paren_depth > 0;
+        let depth_before = paren_depth;
+        let hang = hanging.len();
+        let mut change = paren_change(trimmed);
+        if !continuation {
+            in_decorator = trimmed.starts_with('@');
+            in_header = is_block_header(trimmed);
+            // A `}` starting a statement closes a block, not a literal
+            if trimmed.starts_with('}') {
+                change += 1;
+            }
+        }
+        let opens_block = in_header && paren_depth + change == 1 && code_of(trimmed).ends_with('{');
+        if opens_block {
+            change -= 1;
+        }
+        paren_depth = (paren_depth + change).max(0);
+        while hanging.last().is_some_and(|&depth| depth >= paren_depth) {
+            hanging.pop();
+        }
+        if paren_depth > depth_before {
+            hanging.push(depth_before);
+        }
+        // `case 1:` and `default:` sit one level left of the statements they label
+        let is_label = is_case_label(trimmed);
+        if is_label {
+            if let Some(cased) = has_cases.last_mut() {
+                if !*cased {
+                    *cased = true;
+                    indent += 1;
+                }
+            }
+        }
+        // A label alone on its line takes no `;`
+        let bare_label = is_label
+            && trimmed
+                .split("//")
+                .next()
+                .unwrap_or("")
+                .trim_end()
+                .ends_with(':');
+        let no_semicolon = bare_label || in_decorator;
+        let mut line_indent = if is_label {
+            indent.saturating_sub(1)
+        } else {
+            indent
+        };
+        // Lines inside an open `(`, `[` or literal `{` continue the statement
+        // above them, one level deeper for each line that left brackets open
+        if continuation {
+            line_indent += hang;
+            if trimmed.starts_with([')', ']', '}']) {
+                line_indent -= 1;
+            }
+        }
+        if paren_depth > 0 {
+            result.push_str(&"    ".repeat(line_indent));
+            result.push_str(trimmed);
+            result.push('\n');
+            continue;
+        }
+        // Handle inline or full-line single-line comments starting with // or #
+        if let Some(pos) = trimmed.find("//").or_else(|| trimmed.find('#')) {
             let (left, right_with_slashes) = trimmed.split_at(pos);
             let code_part = left.trim_end();
             let comment_part = right_with_slashes.trim_start(); // keeps leading //
-            result.push_str(&"    ".repeat(indent));
+            result.push_str(&"    ".repeat(line_indent));
             if !code_part.is_empty() {
                 result.push_str(code_part);
-                if !code_part.ends_with(';') && !code_part.ends_with('{') && code_part != "}" {
+                if !code_part.ends_with(';')
+                    && !code_part.ends_with('{')
+                    && code_part != "}"
+                    && !no_semicolon
+                {
                     result.push(';');
                 }
                 result.push(' ');
@@ -53,28 +156,155 @@ pub fn format_nwpython(code: &str) -> String {
             result.push('\n');
             continue;
         }
+        // Handle `} else {` style lines that close one block and open the next
+        if !continuation && trimmed.starts_with('}') && trimmed.ends_with('{') {
+            if open_braces.pop().is_none() {
+                diagnostics.push(
+                    Diagnostic::error(codes::UNMATCHED_BRACE, "unmatched `}` with no open block")
+                        .with_span(span_at(0)),
+                );
+            }
+            if has_cases.pop() == Some(true) {
+                indent = indent.saturating_sub(1);
+            }
+            let labelled = label_blocks.pop() == Some(true);
+            if !labelled {
+                indent = indent.saturating_sub(1);
+            }
+            result.push_str(&"    ".repeat(indent.saturating_sub(labelled as usize)));
+            result.push_str(trimmed);
+            result.push('\n');
+            if !labelled {
+                indent += 1;
+            }
+            open_braces.push(span_at(trimmed.len() - 1));
+            has_cases.push(false);
+            label_blocks.push(labelled);
+            continue;
+        }
         // Handle block open
         if trimmed.ends_with("{") {
-            result.push_str(&"    ".repeat(indent));
+            result.push_str(&"    ".repeat(line_indent));
             result.push_str(trimmed);
             result.push('\n');
-            indent += 1;
+            if !is_label {
+                indent += 1;
+            }
+            open_braces.push(span_at(trimmed.len() - 1));
+            has_cases.push(false);
+            label_blocks.push(is_label);
             continue;
         }
-        // Handle block close
-        if trimmed == "}" {
-            indent = indent.saturating_sub(1);
-            result.push_str(&"    ".repeat(indent));
-            result.push_str("}\n");
+        // Handle block close, possibly followed by the rest of a statement as
+        // in `} while (x);`
+        if !continuation && trimmed.starts_with('}') {
+            if open_braces.pop().is_none() {
+                diagnostics.push(
+                    Diagnostic::error(codes::UNMATCHED_BRACE, "unmatched `}` with no open block")
+                        .with_span(span_at(0)),
+                );
+            }
+            if has_cases.pop() == Some(true) {
+                indent = indent.saturating_sub(1);
+            }
+            if label_blocks.pop() == Some(true) {
+                result.push_str(&"    ".repeat(indent.saturating_sub(1)));
+            } else {
+                indent = indent.saturating_sub(1);
+                result.push_str(&"    ".repeat(indent));
+            }
+            result.push_str(trimmed);
+            if trimmed != "}" && !trimmed.ends_with(';') {
+                result.push(';');
+            }
+            result.push('\n');
             continue;
         }
         // Handle statements
-        result.push_str(&"    ".repeat(indent));
+        result.push_str(&"    ".repeat(line_indent));
         result.push_str(trimmed);
-        if !trimmed.ends_with(';') && !trimmed.ends_with("{") && trimmed != "}" && !trimmed.is_empty() {
+        if !trimmed.ends_with(';')
+            && !trimmed.ends_with("{")
+            && (trimmed != "}" || continuation)
+            && !trimmed.is_empty()
+            && !no_semicolon
+        {
             result.push(';');
         }
         result.push('\n');
     }
-    result
+    if in_multiline_comment {
+        diagnostics.push(
+            Diagnostic::error(codes::UNTERMINATED_COMMENT, "unterminated block comment")
+                .with_primary(comment_start, "comment starts here"),
+        );
+    }
+    for open in open_braces {
+        diagnostics.push(
+            Diagnostic::error(codes::UNCLOSED_BLOCK, "unclosed block: expected `}`")
+                .with_primary(open, "this block is never closed"),
+        );
+    }
+    (result, diagnostics)
+}
+
+/// How many more `(`, `[` and `{` than `)`, `]` and `}` `line` has outside strings and comments
+fn paren_change(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, quote) {
+            ('\\', Some(_)) => {
+                chars.next();
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('\'' | '"', None) => quote = Some(ch),
+            ('#', None) => break,
+            ('/', None) if matches!(chars.peek(), Some('/') | Some('*')) => break,
+            ('(' | '[' | '{', None) => depth += 1,
+            (')' | ']' | '}', None) => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// The code of `line` without its trailing `//` or `#` comment
+fn code_of(line: &str) -> &str {
+    line.find("//")
+        .or_else(|| line.find('#'))
+        .map_or(line, |pos| &line[..pos])
+        .trim_end()
+}
+
+/// Whether the statement starting at `line` is a block header such as
+/// `if (x) {` or `} else {`, as opposed to one holding a dict or set literal
+/// such as `x = {`; a lone `{` opens the block of the header above it
+fn is_block_header(line: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "if", "elif", "else", "while", "for", "def", "class", "try", "except", "finally", "with",
+        "async", "switch", "do", "match", "case",
+    ];
+    let line = line.trim_start_matches('}').trim_start();
+    let word = line
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or("");
+    line == "{" || KEYWORDS.contains(&word)
+}
+
+/// Whether `line` starts with a `case ...:` or `default:` label of a
+/// `switch`, as opposed to a `case ... => {` of a `match`, which opens a block
+fn is_case_label(line: &str) -> bool {
+    let header = code_of(line).strip_suffix('{').unwrap_or("");
+    if header.trim_end().ends_with("=>") {
+        return false;
+    }
+    let rest = match line.strip_prefix("case") {
+        Some(rest) => rest,
+        None => return line.starts_with("default:") || line.starts_with("default :"),
+    };
+    rest.starts_with([' ', '(', '"', '\'', '-']) && rest.contains(':')
 }
@@ -1,3 +1,6 @@
 //! NWPython code formatter crate
 pub mod formatter;
-pub use formatter::format_nwpython;
+pub use formatter::{format_nwpython, format_nwpython_checked};
+
+#[cfg(test)]
+mod test;
@@ -1,5 +1,7 @@
 #[cfg(test)]
-use super::formatter::format_nwpython;
+use super::formatter::{format_nwpython, format_nwpython_checked};
+#[cfg(test)]
+use nwparser::diagnostic::codes;
 
 #[test]
 fn test_basic_formatting() {
@@ -16,3 +18,93 @@ fn test_multiline_comment() {
     let output = format_nwpython(input);
     assert_eq!(output, expected);
 }
+
+#[test]
+fn test_unbalanced_braces_are_reported() {
+    let (_, diagnostics) = format_nwpython_checked("def f(){\n  x = 1;\n}\n}\nif (x) {\n");
+    let found: Vec<(&str, usize)> = diagnostics
+        .iter()
+        .map(|d| (d.code, d.span().unwrap().line))
+        .collect();
+    assert_eq!(
+        found,
+        vec![(codes::UNMATCHED_BRACE, 4), (codes::UNCLOSED_BLOCK, 5)]
+    );
+}
+
+#[test]
+fn test_else_on_closing_brace_line() {
+    let input = "if (x) {\na();\n} else {\nb();\n}\n";
+    let expected = "if (x) {\n    a();\n} else {\n    b();\n}\n";
+    let (output, diagnostics) = format_nwpython_checked(input);
+    assert_eq!(output, expected);
+    assert!(diagnostics.is_empty());
+}
+
+#[test]
+fn test_switch_case_labels() {
+    let input = "switch (x) {\ncase 1:\nf()\nbreak;\ncase 2, 3: // few\ng();\nif (y) {\nh();\n}\ndefault:\n}\n";
+    let expected = "switch (x) {\n    case 1:\n        f();\n        break;\n    case 2, 3: // few\n        g();\n        if (y) {\n            h();\n        }\n    default:\n}\n";
+    let (output, diagnostics) = format_nwpython_checked(input);
+    assert_eq!(output, expected);
+    assert!(diagnostics.is_empty());
+    assert_eq!(format_nwpython(&output), output);
+}
+
+#[test]
+fn test_match_cases_open_blocks() {
+    let input = "match (p) {\ncase {\"k\": v} if v => {\nf(v) // mapping\n}\ncase Point(x=0, y) => {\n}\n}\n";
+    let expected = "match (p) {\n    case {\"k\": v} if v => {\n        f(v); // mapping\n    }\n    case Point(x=0, y) => {\n    }\n}\n";
+    let (output, diagnostics) = format_nwpython_checked(input);
+    assert_eq!(output, expected);
+    assert!(diagnostics.is_empty());
+    assert_eq!(format_nwpython(&output), output);
+}
+
+#[test]
+fn test_braced_case_bodies() {
+    let input = "switch (x) {\ncase 1: {\nf();\nbreak;\n}\ndefault:\ng();\n}\n";
+    let expected =
+        "switch (x) {\n    case 1: {\n        f();\n        break;\n    }\n    default:\n        g();\n}\n";
+    assert_eq!(format_nwpython(input), expected);
+    assert_eq!(format_nwpython(expected), expected);
+}
+
+#[test]
+fn test_arrow_function_bodies() {
+    let input = "ys = map((x) => {\nif (x < 0) {\nreturn -x;\n}\nreturn x;\n}, xs)\nf = (a, b) => {\nreturn a + b\n}\n";
+    let expected = "ys = map((x) => {\n    if (x < 0) {\n        return -x;\n    }\n    return x;\n}, xs);\nf = (a, b) => {\n    return a + b\n};\n";
+    assert_eq!(format_nwpython(input), expected);
+    assert_eq!(format_nwpython(expected), expected);
+}
+
+#[test]
+fn test_do_while_closing_line() {
+    let input = "do {\nx += 1;\n} while (x < 3)\nf();\n";
+    let expected = "do {\n    x += 1;\n} while (x < 3);\nf();\n";
+    assert_eq!(format_nwpython(input), expected);
+}
+
+#[test]
+fn test_decorators_and_multiline_arguments() {
+    let input = "@app.route(\n\"/\", // root\nmethods=[\"GET\"],\n)\n@login_required\ndef index(\nrequest,\n) {\nx = f(1,\n2)\n}\n";
+    let expected = "@app.route(\n    \"/\", // root\n    methods=[\"GET\"],\n)\n@login_required\ndef index(\n    request,\n) {\n    x = f(1,\n        2);\n}\n";
+    assert_eq!(format_nwpython(input), expected);
+    assert_eq!(format_nwpython(expected), expected);
+}
+
+#[test]
+fn test_dict_and_set_literals() {
+    let input = "x = {\n\"a\": 1,\n\"b\": {\n\"c\": [1, 2],\n},\n}\nif x == {} {\nf({\n\"k\": {1, 2},\n});\n} else {\ny = {}\n}\n";
+    let expected = "x = {\n    \"a\": 1,\n    \"b\": {\n        \"c\": [1, 2],\n    },\n};\nif x == {} {\n    f({\n        \"k\": {1, 2},\n    });\n} else {\n    y = {};\n}\n";
+    assert_eq!(format_nwpython(input), expected);
+    assert_eq!(format_nwpython(expected), expected);
+    assert!(format_nwpython_checked(input).1.is_empty());
+}
+
+#[test]
+fn test_multiline_block_header() {
+    let input = "def f() {\nif (a &&\nb) {\nreturn 1;\n}\n}\n";
+    let expected = "def f() {\n    if (a &&\n        b) {\n        return 1;\n    }\n}\n";
+    assert_eq!(format_nwpython(input), expected);
+}
@@ -0,0 +1,591 @@
+//! Typed syntax tree for NWPython source
+//!
+//! Expressions keep the Python meaning of their operators; NWPython-only
+//! constructs (such as `++`/`--`) get their own variants so the transpiler
+//! can lower them explicitly. Nodes that can fail to lower keep the `span`
+//! of their source so the transpiler's diagnostics can point at it.
+
+use crate::span::Span;
+
+/// A whole `.nwpy` file
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    FunctionDef(FunctionDef),
+    ClassDef(ClassDef),
+    If(If),
+    While(While),
+    For(For),
+    CFor(CFor),
+    /// `do { body } while (test);`, which runs the body before the first test
+    DoWhile(While),
+    Switch(Switch),
+    Match(Match),
+    Try(Try),
+    With(With),
+    Return(Option<Expr>),
+    Assign(Assign),
+    AugAssign(AugAssign),
+    AnnAssign(AnnAssign),
+    /// An expression statement; `terminated` is false when the trailing `;`
+    /// was omitted before the closing `}` of a block
+    Expr {
+        value: Expr,
+        terminated: bool,
+    },
+    Import(Vec<Alias>),
+    ImportFrom(ImportFrom),
+    Global(Vec<String>),
+    Nonlocal(Vec<String>),
+    Del(Vec<Expr>),
+    Assert {
+        test: Expr,
+        msg: Option<Expr>,
+    },
+    /// `raise`, `raise exc` or `raise exc from cause`
+    Raise {
+        exc: Option<Expr>,
+        cause: Option<Expr>,
+    },
+    Pass,
+    Break,
+    Continue,
+    /// A source comment, kept verbatim including its `//`, `#` or `/* */` markers
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    /// `@expr` lines before the definition, outermost first
+    pub decorators: Vec<Expr>,
+    pub is_async: bool,
+    pub name: String,
+    pub params: Vec<Param>,
+    pub returns: Option<Expr>,
+    pub body: Vec<Stmt>,
+}
+
+/// `class Name(Base, metaclass=Meta) { }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDef {
+    pub decorators: Vec<Expr>,
+    pub name: String,
+    /// Base classes and keyword arguments such as `metaclass=Meta`
+    pub args: Vec<Arg>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub kind: ParamKind,
+    /// Empty for the bare `*` and `/` markers
+    pub name: String,
+    pub annotation: Option<Expr>,
+    pub default: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    Normal,
+    /// `*args`, or a bare `*` when the name is empty
+    VarArgs,
+    /// `**kwargs`
+    KwArgs,
+    /// The bare `/` positional-only marker
+    PosOnlyMarker,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct If {
+    pub test: Expr,
+    pub body: Vec<Stmt>,
+    pub elifs: Vec<Elif>,
+    pub orelse: Option<Vec<Stmt>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Elif {
+    pub test: Expr,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct While {
+    pub test: Expr,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct For {
+    pub is_async: bool,
+    pub target: Expr,
+    /// For `for (k: v in d)`, which binds each key of the mapping `iter` to
+    /// `target` and its value to `value`
+    pub value: Option<Expr>,
+    pub iter: Expr,
+    pub body: Vec<Stmt>,
+}
+
+/// C-style `for (init; test; step) { }`; each of the three parts may be empty
+#[derive(Debug, Clone, PartialEq)]
+pub struct CFor {
+    /// Simple statements such as `i = 0`, one for each item of a
+    /// comma-separated list like `i = 0, j = n`
+    pub init: Vec<Stmt>,
+    pub test: Option<Expr>,
+    /// Simple statements such as `i++` or `i += 2`, one for each item of a
+    /// comma-separated list like `i++, j--`
+    pub step: Vec<Stmt>,
+    pub body: Vec<Stmt>,
+}
+
+/// `switch (subject) { case a, b: ... default: ... }`.
+///
+/// Cases never fall through: labels with no statements of their own are
+/// merged into the next case and the trailing `break` of each case is dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Switch {
+    pub subject: Expr,
+    pub cases: Vec<Case>,
+    pub default: Option<Vec<Stmt>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub values: Vec<Expr>,
+    pub body: Vec<Stmt>,
+}
+
+/// `match (subject) { case pattern if guard => { } }`, Python's structural
+/// pattern matching; unlike a `switch` it binds names and `break` leaves
+/// the enclosing loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub subject: Expr,
+    pub cases: Vec<MatchCase>,
+    /// `match (subject)`
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchCase {
+    pub pattern: Pattern,
+    pub guard: Option<Expr>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// `_`
+    Wildcard,
+    /// A bare name, which binds whatever it matches
+    Capture(String),
+    /// A literal or dotted name such as `-1`, `"s"`, `None` or `Color.RED`,
+    /// compared with `==` (or `is` for `None`, `True` and `False`)
+    Value(Expr),
+    /// `[a, *rest]` or `(a, b)`
+    Sequence(Vec<Pattern>),
+    /// `*rest` inside a sequence; `None` for `*_`
+    Star(Option<String>),
+    /// `{"k": v, **rest}`
+    Mapping {
+        items: Vec<(Expr, Pattern)>,
+        rest: Option<String>,
+    },
+    /// `Point(0, y=y)`
+    Class {
+        cls: Expr,
+        args: Vec<Pattern>,
+        kwargs: Vec<(String, Pattern)>,
+    },
+    /// `a | b`
+    Or(Vec<Pattern>),
+    /// `pattern as name`
+    As { pattern: Box<Pattern>, name: String },
+}
+
+/// `with (open(p) as f, lock) { }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct With {
+    pub is_async: bool,
+    pub items: Vec<WithItem>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithItem {
+    pub context: Expr,
+    /// The target after `as`
+    pub target: Option<Expr>,
+}
+
+/// `try { } except (E as e) { } else { } finally { }`; there is always at
+/// least one handler or a `finally` block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Try {
+    pub body: Vec<Stmt>,
+    pub handlers: Vec<ExceptHandler>,
+    /// Runs when the body raised nothing; only allowed after a handler
+    pub orelse: Option<Vec<Stmt>>,
+    pub finalbody: Option<Vec<Stmt>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExceptHandler {
+    /// The exception type, or a tuple of types; `None` catches everything
+    pub typ: Option<Expr>,
+    pub name: Option<String>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assign {
+    /// More than one target for chained assignments like `a = b = 0`
+    pub targets: Vec<Expr>,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AugAssign {
+    pub target: Expr,
+    pub op: BinOp,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnAssign {
+    pub target: Expr,
+    pub annotation: Expr,
+    pub value: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alias {
+    /// Dotted name, e.g. `os.path`
+    pub name: String,
+    pub asname: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportFrom {
+    /// Module path including any leading dots of a relative import
+    pub module: String,
+    /// Empty for `from x import *`
+    pub names: Vec<Alias>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Name(String),
+    /// Numeric literal, kept as written
+    Number(String),
+    /// String literal(s) as written, including prefixes and quotes;
+    /// adjacent literals are kept as separate parts
+    Str(Vec<String>),
+    Ellipsis,
+    /// An expression the source wrapped in parentheses
+    Paren(Box<Expr>),
+    Tuple(Vec<Expr>),
+    List(Vec<Expr>),
+    Set(Vec<Expr>),
+    Dict(Vec<DictItem>),
+    /// The `value?` of `value?.attr` or `value?[index]`; the chain of
+    /// attributes, subscripts and calls it starts is `None` when `value` is
+    Optional {
+        value: Box<Expr>,
+        span: Span,
+    },
+    Attribute {
+        value: Box<Expr>,
+        attr: String,
+    },
+    Subscript {
+        value: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /// `start..stop`, `start..=stop` or either with `step s`; a `range()`
+    /// call, or a slice when it is the whole index of a subscript
+    Range {
+        start: Box<Expr>,
+        stop: Box<Expr>,
+        inclusive: bool,
+        step: Option<Box<Expr>>,
+    },
+    Slice {
+        lower: Option<Box<Expr>>,
+        upper: Option<Box<Expr>>,
+        step: Option<Box<Expr>>,
+    },
+    Call {
+        func: Box<Expr>,
+        args: Vec<Arg>,
+    },
+    UnaryOp {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    BinOp {
+        left: Box<Expr>,
+        op: BinOp,
+        right: Box<Expr>,
+    },
+    BoolOp {
+        left: Box<Expr>,
+        op: BoolOp,
+        right: Box<Expr>,
+    },
+    Compare {
+        left: Box<Expr>,
+        ops: Vec<(CmpOp, Expr)>,
+    },
+    /// `value |> stage`: `stage(value)`, or `stage` with its `_` placeholder
+    /// standing for `value`
+    Pipe {
+        value: Box<Expr>,
+        stage: Box<Expr>,
+        span: Span,
+    },
+    /// `left ?? right`: `left` unless it is `None`, in which case `right`
+    Coalesce {
+        left: Box<Expr>,
+        right: Box<Expr>,
+        span: Span,
+    },
+    /// Python conditional expression `body if test else orelse`
+    IfExp {
+        test: Box<Expr>,
+        body: Box<Expr>,
+        orelse: Box<Expr>,
+    },
+    Lambda {
+        params: Vec<Param>,
+        body: Box<Expr>,
+    },
+    /// A block-bodied arrow function `(x) => { ... }`; `(x) => expr` is a
+    /// plain `Lambda`
+    Function {
+        params: Vec<Param>,
+        body: Vec<Stmt>,
+        span: Span,
+    },
+    Comprehension {
+        kind: ComprehensionKind,
+        elt: Box<Expr>,
+        /// Value expression for dict comprehensions
+        value: Option<Box<Expr>>,
+        generators: Vec<Generator>,
+    },
+    Starred(Box<Expr>),
+    NamedExpr {
+        target: String,
+        value: Box<Expr>,
+    },
+    Yield(Option<Box<Expr>>),
+    YieldFrom(Box<Expr>),
+    Await(Box<Expr>),
+    /// NWPython `++x`, `x++`, `--x` and `x--`
+    IncDec {
+        op: IncDecOp,
+        prefix: bool,
+        target: Box<Expr>,
+        span: Span,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DictItem {
+    Pair(Expr, Expr),
+    /// `**mapping`
+    Unpack(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Positional(Expr),
+    Keyword(String, Expr),
+    /// `*args`
+    Star(Expr),
+    /// `**kwargs`
+    DoubleStar(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    pub is_async: bool,
+    pub target: Expr,
+    pub iter: Expr,
+    pub ifs: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComprehensionKind {
+    List,
+    Set,
+    Dict,
+    Generator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    Pos,
+    Invert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    FloorDiv,
+    Mod,
+    Pow,
+    MatMul,
+    LShift,
+    RShift,
+    BitAnd,
+    BitOr,
+    BitXor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoolOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtE,
+    Gt,
+    GtE,
+    In,
+    NotIn,
+    Is,
+    IsNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncDecOp {
+    Inc,
+    Dec,
+}
+
+impl Expr {
+    /// Whether `self` is a chain of attributes, subscripts and calls with a
+    /// `?.` or `?[` in it
+    pub fn is_optional_chain(&self) -> bool {
+        match self {
+            Expr::Optional { .. } => true,
+            Expr::Attribute { value, .. } | Expr::Subscript { value, .. } => {
+                value.is_optional_chain()
+            }
+            Expr::Call { func, .. } => func.is_optional_chain(),
+            _ => false,
+        }
+    }
+
+    /// The value of an integer literal such as `3` or `-1`
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Expr::Number(n) if n.bytes().all(|b| b.is_ascii_digit() || b == b'_') => {
+                n.replace('_', "").parse().ok()
+            }
+            Expr::UnaryOp {
+                op: UnaryOp::Neg,
+                operand,
+            } => operand.as_int().map(|n| -n),
+            Expr::Paren(inner) => inner.as_int(),
+            _ => None,
+        }
+    }
+}
+
+impl BinOp {
+    /// Python spelling of the operator
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::FloorDiv => "//",
+            BinOp::Mod => "%",
+            BinOp::Pow => "**",
+            BinOp::MatMul => "@",
+            BinOp::LShift => "<<",
+            BinOp::RShift => ">>",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+        }
+    }
+}
+
+impl Pattern {
+    /// Whether the pattern matches every value, like `_`, `x` or `[*_] | _`
+    pub fn is_irrefutable(&self) -> bool {
+        match self {
+            Pattern::Wildcard | Pattern::Capture(_) => true,
+            Pattern::Or(alts) => alts.iter().any(Pattern::is_irrefutable),
+            Pattern::As { pattern, .. } => pattern.is_irrefutable(),
+            _ => false,
+        }
+    }
+
+    /// Names the pattern binds when it matches
+    pub fn bound_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names<'p>(&'p self, out: &mut Vec<&'p str>) {
+        match self {
+            Pattern::Capture(name) | Pattern::Star(Some(name)) => out.push(name),
+            Pattern::Sequence(items) | Pattern::Or(items) => {
+                items.iter().for_each(|p| p.collect_names(out))
+            }
+            Pattern::Mapping { items, rest } => {
+                items.iter().for_each(|(_, p)| p.collect_names(out));
+                out.extend(rest.as_deref());
+            }
+            Pattern::Class { args, kwargs, .. } => {
+                args.iter().for_each(|p| p.collect_names(out));
+                kwargs.iter().for_each(|(_, p)| p.collect_names(out));
+            }
+            Pattern::As { pattern, name } => {
+                pattern.collect_names(out);
+                out.push(name);
+            }
+            Pattern::Wildcard | Pattern::Value(_) | Pattern::Star(None) => {}
+        }
+    }
+}
+
+impl CmpOp {
+    /// Python spelling of the operator
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CmpOp::Eq => "==",
+            CmpOp::NotEq => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::LtE => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::GtE => ">=",
+            CmpOp::In => "in",
+            CmpOp::NotIn => "not in",
+            CmpOp::Is => "is",
+            CmpOp::IsNot => "is not",
+        }
+    }
+}
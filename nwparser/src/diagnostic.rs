@@ -0,0 +1,378 @@
+//! Diagnostics shared by the tokenizer, parser, transpiler and formatter
+//!
+//! A `Diagnostic` carries a stable code (see [`codes`]), a primary span and
+//! optional secondary labels, notes and a suggested fix. It can be rendered
+//! rustc-style against the source with `render`, or as JSON with `to_json`.
+
+use crate::span::{Span, lines_with_breaks};
+use std::fmt;
+
+/// Stable diagnostic codes
+pub mod codes {
+    /// A string literal is missing its closing quote
+    pub const UNTERMINATED_STRING: &str = "NW0001";
+    /// A character that cannot start any token
+    pub const UNEXPECTED_CHARACTER: &str = "NW0002";
+    /// The parser found a token it did not expect
+    pub const UNEXPECTED_TOKEN: &str = "NW0003";
+    /// A `{` is never closed
+    pub const UNCLOSED_BLOCK: &str = "NW0004";
+    /// A `}` has no matching `{`
+    pub const UNMATCHED_BRACE: &str = "NW0005";
+    /// A `/*` comment is never closed
+    pub const UNTERMINATED_COMMENT: &str = "NW0006";
+    /// `elif`/`else` without a preceding `if` block, or `except`/`finally`
+    /// without a preceding `try` block
+    pub const DANGLING_ELSE: &str = "NW0007";
+    /// A Python statement that NWPython has no syntax for
+    pub const UNSUPPORTED_STATEMENT: &str = "NW0008";
+    /// `++`/`--` somewhere its update cannot be placed
+    pub const UNSUPPORTED_INCREMENT: &str = "NW0009";
+    /// A `switch` case runs on into the next one without `break`
+    pub const CASE_FALLTHROUGH: &str = "NW0010";
+    /// A `switch` that cannot be lowered, such as one with two `default`s
+    pub const INVALID_SWITCH: &str = "NW0011";
+    /// A `try` whose handlers Python would reject, such as a bare `except`
+    /// before another handler
+    pub const INVALID_TRY: &str = "NW0012";
+    /// A `match` Python would reject, such as one with a case after a
+    /// pattern that matches everything
+    pub const INVALID_MATCH: &str = "NW0013";
+    /// A block-bodied arrow function that cannot be hoisted into a `def`,
+    /// such as one using a lambda's parameter
+    pub const UNSUPPORTED_ARROW: &str = "NW0014";
+    /// An inclusive range whose step has no sign known before it runs, so
+    /// its end cannot be moved past `stop` in the right direction
+    pub const INVALID_RANGE: &str = "NW0015";
+    /// `?.`, `?[` or `??` where Python has no way to write it, such as an
+    /// optional chain assigned to
+    pub const INVALID_OPTIONAL: &str = "NW0016";
+    /// A pipeline stage whose `_` placeholder would not be evaluated exactly
+    /// once, such as one using `_` twice
+    pub const INVALID_PIPELINE: &str = "NW0017";
+    /// An assignment, `del` or `++`/`--` whose target is not a name,
+    /// attribute or item, such as `f() = 1`
+    pub const INVALID_TARGET: &str = "NW0018";
+    /// `return` or `yield` outside a function, or `break` or `continue`
+    /// outside the loop (or for `break`, the `switch`) it would leave
+    pub const MISPLACED_STATEMENT: &str = "NW0019";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+/// A span with an explanation; the primary label marks where the problem is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+/// A suggested fix: replace the text covered by `span` with `replacement`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub span: Span,
+    pub replacement: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub suggestion: Option<Suggestion>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            suggestion: None,
+        }
+    }
+
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, code, message)
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, code, message)
+    }
+
+    /// Set the primary span without a label message
+    pub fn with_span(self, span: Span) -> Self {
+        self.with_primary(span, "")
+    }
+
+    /// Set the primary span and the message shown under it
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.retain(|l| !l.primary);
+        self.labels.insert(
+            0,
+            Label {
+                span,
+                message: message.into(),
+                primary: true,
+            },
+        );
+        self
+    }
+
+    /// Add a secondary span pointing at related source
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_suggestion(
+        mut self,
+        span: Span,
+        replacement: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        self.suggestion = Some(Suggestion {
+            span,
+            replacement: replacement.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Span of the primary label, if any
+    pub fn span(&self) -> Option<Span> {
+        self.labels.iter().find(|l| l.primary).map(|l| l.span)
+    }
+
+    /// Render rustc-style, quoting the offending lines of `source` with carets
+    pub fn render(&self, source: &str, filename: &str) -> String {
+        let lines: Vec<&str> = lines_with_breaks(source).map(|(line, _)| line).collect();
+        let mut out = format!(
+            "{}[{}]: {}\n",
+            self.severity.as_str(),
+            self.code,
+            self.message
+        );
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|l| (l.span.line, l.span.col));
+        let gutter = labels
+            .iter()
+            .map(|l| l.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(gutter);
+        match self.span() {
+            Some(span) => out.push_str(&format!(
+                "{}--> {}:{}:{}\n",
+                pad, filename, span.line, span.col
+            )),
+            None => out.push_str(&format!("{}--> {}\n", pad, filename)),
+        }
+        if !labels.is_empty() {
+            out.push_str(&format!("{} |\n", pad));
+        }
+        let mut last_line = 0;
+        for label in labels {
+            let line_no = label.span.line;
+            let Some(text) = lines.get(line_no.wrapping_sub(1)) else {
+                continue;
+            };
+            if line_no != last_line {
+                out.push_str(&format!("{:>w$} | {}\n", line_no, text, w = gutter));
+                last_line = line_no;
+            }
+            let col = label.span.col.max(1);
+            let line_start = source[..label.span.start.min(source.len())]
+                .rfind(['\n', '\r'])
+                .map_or(0, |p| p + 1);
+            let line_end = line_start + text.len();
+            let width = source
+                .get(label.span.start..label.span.end.min(line_end))
+                .map_or(1, |s| s.chars().count())
+                .max(1);
+            let marker = if label.primary { "^" } else { "-" };
+            out.push_str(&format!(
+                "{} | {}{}",
+                pad,
+                " ".repeat(col - 1),
+                marker.repeat(width)
+            ));
+            if !label.message.is_empty() {
+                out.push(' ');
+                out.push_str(&label.message);
+            }
+            out.push('\n');
+        }
+        for note in &self.notes {
+            out.push_str(&format!("{} = note: {}\n", pad, note));
+        }
+        if let Some(s) = &self.suggestion {
+            out.push_str(&format!(
+                "{} = help: {}: `{}`\n",
+                pad, s.message, s.replacement
+            ));
+        }
+        out
+    }
+
+    /// Machine-readable form for editors and CI
+    pub fn to_json(&self, filename: &str) -> String {
+        let span_json = |s: &Span| {
+            format!(
+                "{{\"start\":{},\"end\":{},\"line\":{},\"col\":{}}}",
+                s.start, s.end, s.line, s.col
+            )
+        };
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|l| {
+                format!(
+                    "{{\"span\":{},\"message\":{},\"primary\":{}}}",
+                    span_json(&l.span),
+                    json_string(&l.message),
+                    l.primary
+                )
+            })
+            .collect();
+        let notes: Vec<String> = self.notes.iter().map(|n| json_string(n)).collect();
+        let suggestion = match &self.suggestion {
+            Some(s) => format!(
+                "{{\"span\":{},\"replacement\":{},\"message\":{}}}",
+                span_json(&s.span),
+                json_string(&s.replacement),
+                json_string(&s.message)
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"file\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"labels\":[{}],\"notes\":[{}],\"suggestion\":{}}}",
+            json_string(filename),
+            self.severity.as_str(),
+            self.code,
+            json_string(&self.message),
+            labels.join(","),
+            notes.join(","),
+            suggestion
+        )
+    }
+}
+
+/// Render a list of diagnostics as a JSON array
+pub fn to_json_array(diagnostics: &[Diagnostic], filename: &str) -> String {
+    let items: Vec<String> = diagnostics.iter().map(|d| d.to_json(filename)).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}:{}: ", span.line, span.col)?;
+        }
+        write!(
+            f,
+            "{}[{}]: {}",
+            self.severity.as_str(),
+            self.code,
+            self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_with_carets() {
+        let source = "def f() {\n    x = 1 +;\n";
+        let diag = Diagnostic::error(codes::UNEXPECTED_TOKEN, "expected an expression, found `;`")
+            .with_primary(Span::new(21, 22, 2, 12), "expected an expression here")
+            .with_label(Span::new(8, 9, 1, 9), "in this block")
+            .with_note("operators need a right-hand operand");
+        assert_eq!(
+            diag.render(source, "main.nwpy"),
+            "error[NW0003]: expected an expression, found `;`\n \
+             --> main.nwpy:2:12\n  \
+             |\n\
+             1 | def f() {\n  \
+             |         - in this block\n\
+             2 |     x = 1 +;\n  \
+             |            ^ expected an expression here\n  \
+             = note: operators need a right-hand operand\n"
+        );
+    }
+
+    #[test]
+    fn test_render_crlf_source() {
+        let source = "x = 1;\r\ny = ;\r\n";
+        let diag = Diagnostic::error(codes::UNEXPECTED_TOKEN, "expected an expression, found `;`")
+            .with_primary(Span::new(12, 13, 2, 5), "expected an expression here");
+        assert!(
+            diag.render(source, "main.nwpy")
+                .ends_with("2 | y = ;\n  |     ^ expected an expression here\n")
+        );
+    }
+
+    #[test]
+    fn test_json_output() {
+        let diag = Diagnostic::warning(codes::UNMATCHED_BRACE, "unmatched \"}\"")
+            .with_span(Span::new(0, 1, 1, 1));
+        assert_eq!(
+            to_json_array(&[diag], "a.nwpy"),
+            "[{\"file\":\"a.nwpy\",\"severity\":\"warning\",\"code\":\"NW0005\",\"message\":\"unmatched \\\"}\\\"\",\"labels\":[{\"span\":{\"start\":0,\"end\":1,\"line\":1,\"col\":1},\"message\":\"\",\"primary\":true}],\"notes\":[],\"suggestion\":null}]"
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! Splits the tokenizer's text chunks into the fine-grained tokens the parser works on
+
+use crate::diagnostic::{Diagnostic, codes};
+use crate::span::Span;
+use crate::tokenizer::{SpannedToken, Token};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Tok {
+    Name(String),
+    Number(String),
+    Str(String),
+    /// Operators and punctuation, including `{`, `}` and `;`
+    Op(&'static str),
+    Comment(String),
+    Eof,
+}
+
+/// Longest operators first so that greedy matching picks `**=` over `**`.
+/// Floor division is spelled `~/` because `//` starts a comment.
+const OPERATORS: &[&str] = &[
+    "**=", "~/=", ">>=", "<<=", "...", "..=", "..", "->", ":=", "**", "~/", "<<", ">>", "<=", ">=",
+    "=>", "??", "==", "!=", "&&", "||", "|>", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "@=",
+    "++", "--", "+", "-", "*", "/", "%", "@", "&", "|", "^", "~", "<", ">", "(", ")", "[", "]",
+    "{", "}", ",", ":", ".", ";", "=", "!", "?",
+];
+
+/// Flatten tokenizer output into parser tokens and their spans, always ending with `Tok::Eof`.
+/// Characters that cannot be lexed are skipped and reported in the returned diagnostics.
+pub(crate) fn lex(tokens: &[SpannedToken]) -> (Vec<Tok>, Vec<Span>, Vec<Diagnostic>) {
+    let mut out = Lexed::default();
+    let mut errors = Vec::new();
+    for SpannedToken { token, span } in tokens {
+        match token {
+            Token::LBrace => out.push(Tok::Op("{"), *span),
+            Token::RBrace => out.push(Tok::Op("}"), *span),
+            Token::Semicolon => out.push(Tok::Op(";"), *span),
+            Token::Comment(c) => {
+                if c.starts_with("/*") && !(c.len() >= 4 && c.ends_with("*/")) {
+                    let start = Span {
+                        end: span.start + 2,
+                        ..*span
+                    };
+                    errors.push(
+                        Diagnostic::error(
+                            codes::UNTERMINATED_COMMENT,
+                            "unterminated block comment",
+                        )
+                        .with_primary(start, "comment starts here")
+                        .with_note("add `*/` to close the comment"),
+                    );
+                }
+                out.push(Tok::Comment(c.clone()), *span)
+            }
+            Token::Text(text) => lex_text(text, *span, &mut out, &mut errors),
+        }
+    }
+    // End of file sits just past the last token
+    let eof = match tokens.last() {
+        Some(SpannedToken {
+            token: Token::Text(text),
+            span,
+        }) => {
+            let text = text.trim_end();
+            let (line, col) = end_position(text, *span);
+            Span::new(span.start + text.len(), span.start + text.len(), line, col)
+        }
+        Some(SpannedToken {
+            token: Token::Comment(text),
+            span,
+        }) => {
+            let (line, col) = end_position(text, *span);
+            Span::new(span.end, span.end, line, col)
+        }
+        Some(SpannedToken { span, .. }) => Span::new(span.end, span.end, span.line, span.col + 1),
+        None => Span::new(0, 0, 1, 1),
+    };
+    out.push(Tok::Eof, eof);
+    (out.toks, out.spans, errors)
+}
+
+/// Line and column just past `text`, which starts at `start`
+fn end_position(text: &str, start: Span) -> (usize, usize) {
+    let mut chars = text.chars().peekable();
+    let (mut line, mut col) = (start.line, start.col);
+    while let Some(c) = chars.next() {
+        if c == '\n' || (c == '\r' && chars.peek() != Some(&'\n')) {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+    (line, col)
+}
+
+#[derive(Default)]
+struct Lexed {
+    toks: Vec<Tok>,
+    spans: Vec<Span>,
+}
+
+impl Lexed {
+    fn push(&mut self, tok: Tok, span: Span) {
+        self.toks.push(tok);
+        self.spans.push(span);
+    }
+}
+
+/// Lex one text chunk; `base` is the span of the whole chunk
+fn lex_text(text: &str, base: Span, out: &mut Lexed, errors: &mut Vec<Diagnostic>) {
+    let chars: Vec<char> = text.chars().collect();
+    let byte_at: Vec<usize> = text
+        .char_indices()
+        .map(|(b, _)| b)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    // Line and column of every char; a chunk goes on past the end of a line
+    // only inside a triple-quoted string
+    let mut line_col = vec![(base.line, base.col)];
+    for (i, &c) in chars.iter().enumerate() {
+        let (line, col) = line_col[i];
+        let line_break = c == '\n' || (c == '\r' && chars.get(i + 1) != Some(&'\n'));
+        line_col.push(if line_break {
+            (line + 1, 1)
+        } else {
+            (line, col + 1)
+        });
+    }
+    let span = |from: usize, to: usize| {
+        let (line, col) = line_col[from];
+        Span::new(
+            base.start + byte_at[from],
+            base.start + byte_at[to],
+            line,
+            col,
+        )
+    };
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_whitespace() || ch == '\\' {
+            i += 1;
+            continue;
+        }
+        if let Some(end) = string_end(&chars, i) {
+            match end {
+                Ok(end) => {
+                    out.push(Tok::Str(chars[i..end].iter().collect()), span(i, end));
+                    i = end;
+                }
+                Err(()) => {
+                    let literal: String = chars[i..].iter().collect();
+                    errors.push(
+                        Diagnostic::error(
+                            codes::UNTERMINATED_STRING,
+                            format!("unterminated string literal `{}`", literal.trim()),
+                        )
+                        .with_primary(span(i, chars.len()), "missing closing quote"),
+                    );
+                    return;
+                }
+            }
+            continue;
+        }
+        if ch.is_alphabetic() || ch == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            out.push(Tok::Name(name), span(start, i));
+            continue;
+        }
+        if ch.is_ascii_digit()
+            || (ch == '.' && i + 1 < chars.len() && chars[i + 1].is_ascii_digit())
+        {
+            let end = number_end(&chars, i);
+            out.push(Tok::Number(chars[i..end].iter().collect()), span(i, end));
+            i = end;
+            continue;
+        }
+        // `?.` and `?[` continue an optional chain, except in `c ?.5 : x` and
+        // `c ?[1] : [2]`, where `?` after a space starts a conditional's branch
+        if ch == '?' {
+            let chained = match chars.get(i + 1) {
+                Some('.') => !chars.get(i + 2).is_some_and(|c| c.is_ascii_digit()),
+                Some('[') => i > 0 && !chars[i - 1].is_whitespace(),
+                _ => false,
+            };
+            if chained {
+                let op = if chars[i + 1] == '.' { "?." } else { "?[" };
+                out.push(Tok::Op(op), span(i, i + 2));
+                i += 2;
+                continue;
+            }
+        }
+        let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            let len = op.chars().count();
+            out.push(Tok::Op(op), span(i, i + len));
+            i += len;
+            continue;
+        }
+        errors.push(
+            Diagnostic::error(
+                codes::UNEXPECTED_CHARACTER,
+                format!("unexpected character `{}`", ch),
+            )
+            .with_span(span(i, i + 1)),
+        );
+        i += 1;
+    }
+}
+
+/// If a string literal (with optional prefix) starts at `i`, return where it ends
+fn string_end(chars: &[char], i: usize) -> Option<Result<usize, ()>> {
+    let mut j = i;
+    while j < chars.len() && j - i < 2 && "rRbBuUfF".contains(chars[j]) {
+        j += 1;
+    }
+    if j >= chars.len() || (chars[j] != '"' && chars[j] != '\'') {
+        return None;
+    }
+    let quote = chars[j];
+    let triple = j + 2 < chars.len() && chars[j + 1] == quote && chars[j + 2] == quote;
+    let mut k = if triple { j + 3 } else { j + 1 };
+    while k < chars.len() {
+        if chars[k] == '\\' {
+            k += 2;
+            continue;
+        }
+        if chars[k] == quote {
+            if !triple {
+                return Some(Ok(k + 1));
+            }
+            if k + 2 < chars.len() && chars[k + 1] == quote && chars[k + 2] == quote {
+                return Some(Ok(k + 3));
+            }
+        }
+        k += 1;
+    }
+    Some(Err(()))
+}
+
+fn number_end(chars: &[char], start: usize) -> usize {
+    let mut i = start;
+    if chars[i] == '0' && i + 1 < chars.len() && "xXoObB".contains(chars[i + 1]) {
+        i += 2;
+        while i < chars.len() && (chars[i].is_ascii_hexdigit() || chars[i] == '_') {
+            i += 1;
+        }
+        return i;
+    }
+    let digits = |i: &mut usize| {
+        while *i < chars.len() && (chars[*i].is_ascii_digit() || chars[*i] == '_') {
+            *i += 1;
+        }
+    };
+    digits(&mut i);
+    if i < chars.len() && chars[i] == '.' && !(i + 1 < chars.len() && chars[i + 1] == '.') {
+        i += 1;
+        digits(&mut i);
+    }
+    if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+        let mut j = i + 1;
+        if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+            j += 1;
+        }
+        if j < chars.len() && chars[j].is_ascii_digit() {
+            i = j;
+            digits(&mut i);
+        }
+    }
+    if i < chars.len() && (chars[i] == 'j' || chars[i] == 'J') {
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenize_spanned;
+
+    #[test]
+    fn test_lex_operators_and_literals() {
+        let (toks, spans, errors) = lex(&tokenize_spanned("x **= 1.5e-3 + f'{a}'; y++"));
+        assert!(errors.is_empty());
+        assert_eq!(
+            toks,
+            vec![
+                Tok::Name("x".to_string()),
+                Tok::Op("**="),
+                Tok::Number("1.5e-3".to_string()),
+                Tok::Op("+"),
+                Tok::Str("f'{a}'".to_string()),
+                Tok::Op(";"),
+                Tok::Name("y".to_string()),
+                Tok::Op("++"),
+                Tok::Eof,
+            ]
+        );
+        assert_eq!(spans[2], Span::new(6, 12, 1, 7));
+    }
+
+    #[test]
+    fn test_lex_ranges() {
+        let (toks, _, errors) = lex(&tokenize_spanned("1..=2.5..x...3."));
+        assert!(errors.is_empty());
+        assert_eq!(
+            toks,
+            vec![
+                Tok::Number("1".to_string()),
+                Tok::Op("..="),
+                Tok::Number("2.5".to_string()),
+                Tok::Op(".."),
+                Tok::Name("x".to_string()),
+                Tok::Op("..."),
+                Tok::Number("3.".to_string()),
+                Tok::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_optional_chaining() {
+        let ops = |src: &str| -> Vec<&'static str> {
+            lex(&tokenize_spanned(src))
+                .0
+                .into_iter()
+                .filter_map(|t| match t {
+                    Tok::Op(op) => Some(op),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(ops("a?.b?[k] ?? c"), vec!["?.", "?[", "]", "??"]);
+        // After a space, `?` is a conditional's
+        assert_eq!(ops("c ?[1] : [2]"), vec!["?", "[", "]", ":", "[", "]"]);
+        assert_eq!(ops("c?.5:x"), vec!["?", ":"]);
+    }
+
+    #[test]
+    fn test_lex_c_style_logic() {
+        let (toks, _, _) = lex(&tokenize_spanned("!a && b != null || \"true && x\""));
+        assert_eq!(
+            toks,
+            vec![
+                Tok::Op("!"),
+                Tok::Name("a".to_string()),
+                Tok::Op("&&"),
+                Tok::Name("b".to_string()),
+                Tok::Op("!="),
+                Tok::Name("null".to_string()),
+                Tok::Op("||"),
+                Tok::Str("\"true && x\"".to_string()),
+                Tok::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_reports_unterminated_literals() {
+        let (_, _, errors) = lex(&tokenize_spanned("x = 'abc;\n/* never closed\ny = 2;"));
+        let found: Vec<(&str, Option<Span>)> = errors.iter().map(|d| (d.code, d.span())).collect();
+        assert_eq!(
+            found,
+            vec![
+                (codes::UNTERMINATED_STRING, Some(Span::new(4, 9, 1, 5))),
+                (codes::UNTERMINATED_COMMENT, Some(Span::new(10, 12, 2, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_multiline_string_spans() {
+        let (toks, spans, errors) = lex(&tokenize_spanned("f(\"\"\"a\n  b\"\"\", c)"));
+        assert!(errors.is_empty());
+        assert_eq!(toks[2], Tok::Str("\"\"\"a\n  b\"\"\"".to_string()));
+        assert_eq!(spans[2], Span::new(2, 13, 1, 3));
+        // Tokens after the string are on its last line
+        assert_eq!(spans[4], Span::new(15, 16, 2, 9));
+    }
+}
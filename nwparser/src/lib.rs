@@ -1,4 +1,12 @@
+pub mod ast;
+pub mod diagnostic;
+mod lexer;
+pub mod parser;
+pub mod span;
 pub mod tokenizer;
-pub use tokenizer::{Token, tokenize};
+pub use diagnostic::{Diagnostic, Severity};
+pub use parser::{parse, parse_tokens, parse_tokens_recovering};
+pub use span::Span;
+pub use tokenizer::{SpannedToken, Token, tokenize, tokenize_spanned};
 
-// Tests moved to tokenizer.rs
+// Tests live next to the code they cover
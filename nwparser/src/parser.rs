@@ -0,0 +1,3476 @@
+//! Recursive-descent parser building an `ast::Module` from tokenizer output
+
+use crate::ast::*;
+use crate::diagnostic::{Diagnostic, codes};
+use crate::lexer::{Tok, lex};
+use crate::span::Span;
+use crate::tokenizer::{SpannedToken, tokenize_spanned};
+
+/// Python keywords that can never be used as plain names
+const KEYWORDS: &[&str] = &[
+    "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif",
+    "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda",
+    "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+
+/// Parse NWPython source into a syntax tree
+pub fn parse(source: &str) -> Result<Module, Vec<Diagnostic>> {
+    parse_tokens(&tokenize_spanned(source))
+}
+
+/// Parse already tokenized NWPython source
+pub fn parse_tokens(tokens: &[SpannedToken]) -> Result<Module, Vec<Diagnostic>> {
+    let (module, errors) = parse_tokens_recovering(tokens);
+    if errors.is_empty() {
+        Ok(module)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse as much as possible, skipping statements that contain errors.
+/// The returned module holds every statement that parsed cleanly.
+pub fn parse_tokens_recovering(tokens: &[SpannedToken]) -> (Module, Vec<Diagnostic>) {
+    let (toks, spans, mut errors) = lex(tokens);
+    let mut parser = Parser {
+        toks,
+        spans,
+        pos: 0,
+        comments: Vec::new(),
+        errors: Vec::new(),
+        header_arrow: None,
+        scope: Scope::default(),
+    };
+    let module = parser.parse_module();
+    // Tokens lost to a lexing error usually cause a second, confusing parse
+    // error on the same line; only report the lexing one
+    let bad_lines: Vec<usize> = errors
+        .iter()
+        .filter_map(|d| d.span())
+        .map(|s| s.line)
+        .collect();
+    errors.extend(
+        parser
+            .errors
+            .into_iter()
+            .filter(|d| !d.span().is_some_and(|s| bad_lines.contains(&s.line))),
+    );
+    (module, errors)
+}
+
+/// Parse errors are boxed to keep the `Ok` path small
+type PResult<T> = Result<T, Box<Diagnostic>>;
+
+struct Parser {
+    toks: Vec<Tok>,
+    /// Source span of each entry in `toks`
+    spans: Vec<Span>,
+    pos: usize,
+    /// Comments skipped while inside a statement, emitted before it
+    comments: Vec<String>,
+    errors: Vec<Diagnostic>,
+    /// Index in `toks` of the `=>` ending the `case` guard or return
+    /// annotation being parsed, which never starts an arrow function's body
+    header_arrow: Option<usize>,
+    /// What encloses the statement being parsed, for `return`, `yield`,
+    /// `break` and `continue`
+    scope: Scope,
+}
+
+/// The function, loop and `switch` around a statement, up to the nearest
+/// `def`, arrow function or `class`
+#[derive(Clone, Copy, Default)]
+struct Scope {
+    in_function: bool,
+    in_loop: bool,
+    in_switch: bool,
+}
+
+/// The body of a `def` or arrow function, where no loop is open yet
+const FUNCTION: Scope = Scope {
+    in_function: true,
+    in_loop: false,
+    in_switch: false,
+};
+
+impl Parser {
+    // ---- token helpers ----
+
+    fn skip_comments(&mut self) {
+        while let Tok::Comment(c) = &self.toks[self.pos] {
+            self.comments.push(c.clone());
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> &Tok {
+        self.skip_comments();
+        &self.toks[self.pos]
+    }
+
+    fn peek_nth(&mut self, n: usize) -> &Tok {
+        self.skip_comments();
+        self.toks[self.pos..]
+            .iter()
+            .filter(|t| !matches!(t, Tok::Comment(_)))
+            .nth(n)
+            .unwrap_or(self.toks.last().expect("token stream ends with Eof"))
+    }
+
+    fn advance(&mut self) -> Tok {
+        self.skip_comments();
+        let tok = self.toks[self.pos].clone();
+        if tok != Tok::Eof {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn at_op(&mut self, op: &str) -> bool {
+        matches!(self.peek(), Tok::Op(o) if *o == op)
+    }
+
+    fn at_kw(&mut self, kw: &str) -> bool {
+        matches!(self.peek(), Tok::Name(n) if n == kw)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.at_op(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_kw(&mut self, kw: &str) -> bool {
+        if self.at_kw(kw) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> PResult<()> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", op)))
+        }
+    }
+
+    fn expect_name(&mut self) -> PResult<String> {
+        match self.peek().clone() {
+            Tok::Name(n) if !KEYWORDS.contains(&n.as_str()) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    fn unexpected(&mut self, expected: &str) -> Box<Diagnostic> {
+        let found = describe(self.peek());
+        self.error_here(
+            codes::UNEXPECTED_TOKEN,
+            format!("expected {}, found {}", expected, found),
+        )
+    }
+
+    /// Span of the next non-comment token
+    fn span(&mut self) -> Span {
+        self.skip_comments();
+        self.spans[self.pos]
+    }
+
+    /// Span of the last non-comment token consumed
+    fn prev_span(&self) -> Span {
+        (0..self.pos)
+            .rev()
+            .find(|&k| !matches!(self.toks[k], Tok::Comment(_)))
+            .map_or(self.spans[self.pos], |k| self.spans[k])
+    }
+
+    /// An error pointing at the next non-comment token
+    fn error_here(&mut self, code: &'static str, message: impl Into<String>) -> Box<Diagnostic> {
+        let span = self.span();
+        Box::new(Diagnostic::error(code, message).with_span(span))
+    }
+
+    /// Skip to the end of the current statement after an error
+    fn recover(&mut self) {
+        let mut depth = 0usize;
+        loop {
+            match self.peek() {
+                Tok::Eof => return,
+                Tok::Op("(") | Tok::Op("[") | Tok::Op("{") => depth += 1,
+                Tok::Op(")") | Tok::Op("]") => depth = depth.saturating_sub(1),
+                Tok::Op("}") => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return;
+                    }
+                }
+                Tok::Op(";") if depth == 0 => {
+                    self.pos += 1;
+                    return;
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+    }
+
+    // ---- statements ----
+
+    fn parse_module(&mut self) -> Module {
+        let mut body = Vec::new();
+        loop {
+            self.flush_comments(&mut body);
+            match self.peek() {
+                Tok::Eof => break,
+                Tok::Op("}") => {
+                    let err =
+                        self.error_here(codes::UNMATCHED_BRACE, "unmatched `}` with no open block");
+                    self.errors.push(*err);
+                    self.pos += 1;
+                }
+                _ => self.parse_stmt_into(&mut body),
+            }
+        }
+        Module { body }
+    }
+
+    fn flush_comments(&mut self, body: &mut Vec<Stmt>) {
+        self.skip_comments();
+        body.extend(self.comments.drain(..).map(Stmt::Comment));
+    }
+
+    fn parse_stmt_into(&mut self, body: &mut Vec<Stmt>) {
+        match self.parse_stmt() {
+            Ok(Some(stmt)) => {
+                body.extend(self.comments.drain(..).map(Stmt::Comment));
+                body.push(stmt);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                self.errors.push(*err);
+                self.recover();
+                self.comments.clear();
+            }
+        }
+    }
+
+    /// Parse with `scope` in effect, then restore the enclosing one
+    fn within<T>(&mut self, scope: Scope, parse: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = std::mem::replace(&mut self.scope, scope);
+        let result = parse(self);
+        self.scope = outer;
+        result
+    }
+
+    /// A loop's body, where `break` and `continue` belong to that loop
+    fn parse_loop_body(&mut self) -> PResult<Vec<Stmt>> {
+        let scope = Scope {
+            in_loop: true,
+            ..self.scope
+        };
+        self.within(scope, Self::parse_block)
+    }
+
+    /// Parse `{ stmt* }`
+    fn parse_block(&mut self) -> PResult<Vec<Stmt>> {
+        let open = self.span();
+        self.expect_op("{")?;
+        let mut body = Vec::new();
+        loop {
+            self.flush_comments(&mut body);
+            match self.peek() {
+                Tok::Op("}") => {
+                    self.pos += 1;
+                    return Ok(body);
+                }
+                Tok::Eof => {
+                    return Err(Box::new(
+                        Diagnostic::error(codes::UNCLOSED_BLOCK, "unclosed block: expected `}`")
+                            .with_primary(open, "this block is never closed"),
+                    ));
+                }
+                _ => self.parse_stmt_into(&mut body),
+            }
+        }
+    }
+
+    fn parse_stmt(&mut self) -> PResult<Option<Stmt>> {
+        if self.eat_op(";") {
+            return Ok(None);
+        }
+        if self.at_op("@") {
+            return self.parse_decorated().map(Some);
+        }
+        let kw = match self.peek() {
+            Tok::Name(n) => n.clone(),
+            _ => String::new(),
+        };
+        let stmt = match kw.as_str() {
+            "def" => return self.parse_def().map(|f| Some(Stmt::FunctionDef(f))),
+            "class" => return self.parse_class().map(|c| Some(Stmt::ClassDef(c))),
+            "if" => return self.parse_if().map(|s| Some(Stmt::If(s))),
+            "while" => {
+                self.pos += 1;
+                let test = self.parse_named()?;
+                let body = self.parse_loop_body()?;
+                return Ok(Some(Stmt::While(While { test, body })));
+            }
+            "for" => return self.parse_for().map(Some),
+            "do" if matches!(self.peek_nth(1), Tok::Op("{")) => {
+                self.pos += 1;
+                let body = self.parse_loop_body()?;
+                if !self.eat_kw("while") {
+                    return Err(self.unexpected("`while` after the body of `do`"));
+                }
+                let test = self.parse_named()?;
+                Stmt::DoWhile(While { test, body })
+            }
+            "switch" if self.block_follows() => {
+                return self.parse_switch().map(|s| Some(Stmt::Switch(s)));
+            }
+            "match" if self.block_follows() => {
+                return self.parse_match().map(|m| Some(Stmt::Match(m)));
+            }
+            "try" => return self.parse_try().map(|t| Some(Stmt::Try(t))),
+            "with" => {
+                self.pos += 1;
+                return self.parse_with(false).map(|w| Some(Stmt::With(w)));
+            }
+            "async" => return self.parse_async().map(Some),
+            "except" | "finally" => {
+                let err = self.error_here(
+                    codes::DANGLING_ELSE,
+                    format!("`{}` without a preceding `try` block", kw),
+                );
+                return Err(Box::new(err.with_note(
+                    "`except` and `finally` must directly follow the `}` of a `try` or `except` block",
+                )));
+            }
+            "elif" | "else" => {
+                let kw = if self.at_else_if() { "else if" } else { &kw };
+                let err = self.error_here(
+                    codes::DANGLING_ELSE,
+                    format!("`{}` without a preceding `if` block", kw),
+                );
+                return Err(Box::new(err.with_note(
+                    "`elif`, `else if` and `else` must directly follow the `}` of an `if` or `elif` block",
+                )));
+            }
+            "return" => {
+                if !self.scope.in_function {
+                    let err =
+                        self.error_here(codes::MISPLACED_STATEMENT, "`return` outside a function");
+                    self.errors.push(*err);
+                }
+                self.pos += 1;
+                let value = if self.at_stmt_end() {
+                    None
+                } else {
+                    Some(self.parse_exprlist(true)?)
+                };
+                Stmt::Return(value)
+            }
+            "pass" => {
+                self.pos += 1;
+                Stmt::Pass
+            }
+            "break" => {
+                if !self.scope.in_loop && !self.scope.in_switch {
+                    let err = self.error_here(
+                        codes::MISPLACED_STATEMENT,
+                        "`break` outside a loop or `switch`",
+                    );
+                    self.errors.push(*err);
+                }
+                self.pos += 1;
+                Stmt::Break
+            }
+            "continue" => {
+                if !self.scope.in_loop {
+                    let err =
+                        self.error_here(codes::MISPLACED_STATEMENT, "`continue` outside a loop");
+                    self.errors.push(*err);
+                }
+                self.pos += 1;
+                Stmt::Continue
+            }
+            "import" => {
+                self.pos += 1;
+                let mut names = vec![self.parse_alias(true)?];
+                while self.eat_op(",") {
+                    names.push(self.parse_alias(true)?);
+                }
+                Stmt::Import(names)
+            }
+            "from" => self.parse_import_from()?,
+            "global" | "nonlocal" => {
+                self.pos += 1;
+                let mut names = vec![self.expect_name()?];
+                while self.eat_op(",") {
+                    names.push(self.expect_name()?);
+                }
+                if kw == "global" {
+                    Stmt::Global(names)
+                } else {
+                    Stmt::Nonlocal(names)
+                }
+            }
+            "del" => {
+                self.pos += 1;
+                let at = self.span();
+                let mut targets = vec![self.parse_or_expr()?];
+                while self.eat_op(",") {
+                    targets.push(self.parse_or_expr()?);
+                }
+                for target in &targets {
+                    self.check_target(target, at);
+                }
+                Stmt::Del(targets)
+            }
+            "assert" => {
+                self.pos += 1;
+                let test = self.parse_test()?;
+                let msg = if self.eat_op(",") {
+                    Some(self.parse_test()?)
+                } else {
+                    None
+                };
+                Stmt::Assert { test, msg }
+            }
+            "raise" => {
+                self.pos += 1;
+                let exc = if self.at_stmt_end() {
+                    None
+                } else {
+                    Some(self.parse_test()?)
+                };
+                let cause = if exc.is_some() && self.eat_kw("from") {
+                    Some(self.parse_test()?)
+                } else {
+                    None
+                };
+                Stmt::Raise { exc, cause }
+            }
+            "let" if matches!(self.peek_nth(1), Tok::Name(_) | Tok::Op("(") | Tok::Op("[")) => {
+                // `let` is accepted for readability and means a plain assignment
+                self.pos += 1;
+                self.parse_simple_stmt()?
+            }
+            _ => self.parse_simple_stmt()?,
+        };
+        self.end_stmt(stmt).map(Some)
+    }
+
+    fn at_stmt_end(&mut self) -> bool {
+        matches!(self.peek(), Tok::Op(";") | Tok::Op("}") | Tok::Eof)
+    }
+
+    /// Consume the `;` ending a simple statement; it may be omitted before `}`
+    fn end_stmt(&mut self, stmt: Stmt) -> PResult<Stmt> {
+        if self.eat_op(";") {
+            return Ok(stmt);
+        }
+        if matches!(self.peek(), Tok::Op("}") | Tok::Eof) {
+            return Ok(match stmt {
+                Stmt::Expr { value, .. } => Stmt::Expr {
+                    value,
+                    terminated: false,
+                },
+                other => other,
+            });
+        }
+        Err(self.unexpected("`;`"))
+    }
+
+    /// Assignments and expression statements
+    fn parse_simple_stmt(&mut self) -> PResult<Stmt> {
+        let at = self.span();
+        let first = self.parse_exprlist(true)?;
+        if let Some(op) = self.aug_assign_op() {
+            self.pos += 1;
+            self.check_single_target(&first, at);
+            let value = self.parse_rhs()?;
+            return Ok(Stmt::AugAssign(AugAssign {
+                target: first,
+                op,
+                value,
+            }));
+        }
+        if self.eat_op(":") {
+            self.check_single_target(&first, at);
+            let annotation = self.parse_test()?;
+            let value = if self.eat_op("=") {
+                Some(self.parse_rhs()?)
+            } else {
+                None
+            };
+            return Ok(Stmt::AnnAssign(AnnAssign {
+                target: first,
+                annotation,
+                value,
+            }));
+        }
+        if self.at_op("=") {
+            let mut targets = vec![first];
+            let mut value = None;
+            while self.eat_op("=") {
+                if let Some(prev) = value.take() {
+                    targets.push(prev);
+                }
+                value = Some(self.parse_rhs()?);
+            }
+            for target in &targets {
+                self.check_target(target, at);
+            }
+            return Ok(Stmt::Assign(Assign {
+                targets,
+                value: value.expect("assignment has a value"),
+            }));
+        }
+        Ok(Stmt::Expr {
+            value: first,
+            terminated: true,
+        })
+    }
+
+    /// Report a target of an assignment or `del` that Python cannot store to
+    fn check_target(&mut self, target: &Expr, at: Span) {
+        self.check_target_of(target, at, false);
+    }
+
+    /// Like `check_target`, for an augmented or annotated assignment or
+    /// `++`/`--`, which update a single name, attribute or item
+    fn check_single_target(&mut self, target: &Expr, at: Span) {
+        self.check_target_of(target, at, true);
+    }
+
+    /// Report a target Python cannot assign to: anything but a name,
+    /// attribute or item, or unless `single`, a starred target or a tuple or
+    /// list of targets. An optional chain such as `a?.b` has no target either.
+    fn check_target_of(&mut self, target: &Expr, at: Span, single: bool) {
+        fn assignable(e: &Expr, single: bool) -> bool {
+            match e {
+                Expr::Name(n) => python_literal(n).is_none(),
+                Expr::Attribute { .. } | Expr::Subscript { .. } => true,
+                Expr::Paren(inner) => assignable(inner, single),
+                Expr::Tuple(items) | Expr::List(items) => {
+                    !single && items.iter().all(|item| assignable(item, false))
+                }
+                Expr::Starred(inner) => !single && assignable(inner, false),
+                _ => false,
+            }
+        }
+        fn optional(e: &Expr) -> bool {
+            match e {
+                Expr::Tuple(items) | Expr::List(items) => items.iter().any(optional),
+                Expr::Paren(inner) | Expr::Starred(inner) => optional(inner),
+                other => other.is_optional_chain(),
+            }
+        }
+        if optional(target) {
+            self.errors.push(
+                Diagnostic::error(
+                    codes::INVALID_OPTIONAL,
+                    "cannot assign to an optional chain",
+                )
+                .with_primary(at, "this target has a `?.` or `?[` in it")
+                .with_note("check for `None` with `if` and assign to the plain attribute or item"),
+            );
+        } else if !assignable(target, single) {
+            let note = if single {
+                "only a single name, attribute such as `a.b` or item such as `a[i]` can be updated"
+            } else {
+                "only names, attributes such as `a.b`, items such as `a[i]` and tuples or lists of them can be assigned to"
+            };
+            self.errors.push(
+                Diagnostic::error(codes::INVALID_TARGET, "cannot assign to this expression")
+                    .with_primary(at, "not something a value can be stored in")
+                    .with_note(note),
+            );
+        }
+    }
+
+    /// Right-hand side of an assignment: an expression list or a yield
+    fn parse_rhs(&mut self) -> PResult<Expr> {
+        if self.at_kw("yield") {
+            self.parse_yield()
+        } else {
+            self.parse_exprlist(true)
+        }
+    }
+
+    fn aug_assign_op(&mut self) -> Option<BinOp> {
+        let op = match self.peek() {
+            Tok::Op(o) => *o,
+            _ => return None,
+        };
+        Some(match op {
+            "+=" => BinOp::Add,
+            "-=" => BinOp::Sub,
+            "*=" => BinOp::Mul,
+            "/=" => BinOp::Div,
+            "~/=" => BinOp::FloorDiv,
+            "%=" => BinOp::Mod,
+            "**=" => BinOp::Pow,
+            "@=" => BinOp::MatMul,
+            "<<=" => BinOp::LShift,
+            ">>=" => BinOp::RShift,
+            "&=" => BinOp::BitAnd,
+            "|=" => BinOp::BitOr,
+            "^=" => BinOp::BitXor,
+            _ => return None,
+        })
+    }
+
+    fn parse_alias(&mut self, dotted: bool) -> PResult<Alias> {
+        let mut name = self.expect_name()?;
+        while dotted && self.eat_op(".") {
+            name.push('.');
+            name.push_str(&self.expect_name()?);
+        }
+        let asname = if self.eat_kw("as") {
+            Some(self.expect_name()?)
+        } else {
+            None
+        };
+        Ok(Alias { name, asname })
+    }
+
+    fn parse_import_from(&mut self) -> PResult<Stmt> {
+        self.pos += 1;
+        let mut module = String::new();
+        loop {
+            if self.eat_op(".") {
+                module.push('.');
+            } else if self.eat_op("...") {
+                module.push_str("...");
+            } else {
+                break;
+            }
+        }
+        if !self.at_kw("import") {
+            module.push_str(&self.expect_name()?);
+            while self.eat_op(".") {
+                module.push('.');
+                module.push_str(&self.expect_name()?);
+            }
+        }
+        if !self.eat_kw("import") {
+            return Err(self.unexpected("`import`"));
+        }
+        let mut names = Vec::new();
+        if !self.eat_op("*") {
+            let parens = self.eat_op("(");
+            names.push(self.parse_alias(false)?);
+            while self.eat_op(",") {
+                if parens && self.at_op(")") {
+                    break;
+                }
+                names.push(self.parse_alias(false)?);
+            }
+            if parens {
+                self.expect_op(")")?;
+            }
+        }
+        Ok(Stmt::ImportFrom(ImportFrom { module, names }))
+    }
+
+    fn parse_def(&mut self) -> PResult<FunctionDef> {
+        self.pos += 1;
+        let name = self.expect_name()?;
+        self.expect_op("(")?;
+        let params = self.parse_params(")", true)?;
+        self.expect_op(")")?;
+        let returns = if self.eat_op("->") {
+            self.header_arrow = self.find_top_level("=>");
+            let returns = self.parse_test();
+            self.header_arrow = None;
+            Some(returns?)
+        } else {
+            None
+        };
+        // `def sq(x) => x * x;` returns the expression
+        let body = if self.eat_op("=>") {
+            let value = self.within(FUNCTION, |p| p.parse_exprlist(true))?;
+            vec![self.end_stmt(Stmt::Return(Some(value)))?]
+        } else {
+            self.within(FUNCTION, Self::parse_block)?
+        };
+        Ok(FunctionDef {
+            decorators: Vec::new(),
+            is_async: false,
+            name,
+            params,
+            returns,
+            body,
+        })
+    }
+
+    /// `class Name(bases, keyword=value) { body }`; without bases the
+    /// parentheses may be left out
+    fn parse_class(&mut self) -> PResult<ClassDef> {
+        self.pos += 1;
+        let name = self.expect_name()?;
+        let args = if self.eat_op("(") {
+            let args = self.parse_call_args()?;
+            self.expect_op(")")?;
+            args
+        } else {
+            Vec::new()
+        };
+        let body = self.within(Scope::default(), Self::parse_block)?;
+        Ok(ClassDef {
+            decorators: Vec::new(),
+            name,
+            args,
+            body,
+        })
+    }
+
+    /// `@decorator` lines followed by the `def` or `class` they apply to
+    fn parse_decorated(&mut self) -> PResult<Stmt> {
+        let mut decorators = Vec::new();
+        while self.eat_op("@") {
+            // Only a primary such as `app.route("/")`: line breaks are not
+            // tokens, so a full expression would read the next `@` as an operator
+            decorators.push(self.parse_postfix()?);
+        }
+        // Comments among the decorator arguments stay in front of the
+        // definition instead of moving into its body
+        self.skip_comments();
+        let leading = std::mem::take(&mut self.comments);
+        let stmt = if self.at_kw("def") {
+            Stmt::FunctionDef(self.parse_def()?)
+        } else if self.at_kw("class") {
+            Stmt::ClassDef(self.parse_class()?)
+        } else if self.at_kw("async") && matches!(self.peek_nth(1), Tok::Name(n) if n == "def") {
+            self.parse_async()?
+        } else {
+            return Err(self.unexpected("`def` or `class` after a decorator"));
+        };
+        self.comments.splice(0..0, leading);
+        Ok(match stmt {
+            Stmt::FunctionDef(def) => Stmt::FunctionDef(FunctionDef { decorators, ..def }),
+            Stmt::ClassDef(class) => Stmt::ClassDef(ClassDef {
+                decorators,
+                ..class
+            }),
+            other => other,
+        })
+    }
+
+    /// `async def`, `async for` or `async with`
+    fn parse_async(&mut self) -> PResult<Stmt> {
+        self.pos += 1;
+        if self.at_kw("def") {
+            let def = self.parse_def()?;
+            return Ok(Stmt::FunctionDef(FunctionDef {
+                is_async: true,
+                ..def
+            }));
+        }
+        if self.at_kw("with") {
+            self.pos += 1;
+            return self.parse_with(true).map(Stmt::With);
+        }
+        if !self.at_kw("for") {
+            return Err(self.unexpected("`def`, `for` or `with` after `async`"));
+        }
+        let span = self.span();
+        match self.parse_for()? {
+            Stmt::For(f) => Ok(Stmt::For(For {
+                is_async: true,
+                ..f
+            })),
+            _ => Err(Box::new(
+                Diagnostic::error(codes::UNEXPECTED_TOKEN, "a C-style `for` cannot be `async`")
+                    .with_span(span),
+            )),
+        }
+    }
+
+    /// The items and block of a `with` statement. The items may be wrapped
+    /// in parentheses as in `with (open(p) as f) { }`.
+    fn parse_with(&mut self, is_async: bool) -> PResult<With> {
+        let wrapped = self.at_op("(") && self.paren_ends_header();
+        if wrapped {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        loop {
+            let context = self.parse_test()?;
+            let target = if self.eat_kw("as") {
+                Some(self.parse_or_expr()?)
+            } else {
+                None
+            };
+            items.push(WithItem { context, target });
+            if !self.eat_op(",") || (wrapped && self.at_op(")")) {
+                break;
+            }
+        }
+        if wrapped {
+            self.expect_op(")")?;
+        }
+        let body = self.parse_block()?;
+        Ok(With {
+            is_async,
+            items,
+            body,
+        })
+    }
+
+    /// Whether the parenthesized group at the next token is directly
+    /// followed by the `{` of a block
+    fn paren_ends_header(&mut self) -> bool {
+        self.skip_comments();
+        let mut depth = 0usize;
+        let rest = &self.toks[self.pos..];
+        for (i, tok) in rest.iter().enumerate() {
+            match tok {
+                Tok::Op("(") | Tok::Op("[") | Tok::Op("{") => depth += 1,
+                Tok::Op(")") | Tok::Op("]") | Tok::Op("}") => {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        let next = rest[i + 1..].iter().find(|t| !matches!(t, Tok::Comment(_)));
+                        return matches!(next, Some(Tok::Op("{")));
+                    }
+                }
+                Tok::Eof => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Parameter list up to (not including) `close`
+    fn parse_params(&mut self, close: &str, annotations: bool) -> PResult<Vec<Param>> {
+        let mut params = Vec::new();
+        while !self.at_op(close) {
+            let kind = if self.eat_op("**") {
+                ParamKind::KwArgs
+            } else if self.eat_op("*") {
+                ParamKind::VarArgs
+            } else if self.eat_op("/") {
+                ParamKind::PosOnlyMarker
+            } else {
+                ParamKind::Normal
+            };
+            let bare = matches!(kind, ParamKind::PosOnlyMarker)
+                || (kind == ParamKind::VarArgs && (self.at_op(",") || self.at_op(close)));
+            let name = if bare {
+                String::new()
+            } else {
+                self.expect_name()?
+            };
+            let annotation = if !bare && annotations && self.eat_op(":") {
+                Some(self.parse_test()?)
+            } else {
+                None
+            };
+            let default = if kind == ParamKind::Normal && self.eat_op("=") {
+                Some(self.parse_test()?)
+            } else {
+                None
+            };
+            params.push(Param {
+                kind,
+                name,
+                annotation,
+                default,
+            });
+            if !self.eat_op(",") {
+                break;
+            }
+        }
+        Ok(params)
+    }
+
+    fn parse_if(&mut self) -> PResult<If> {
+        self.pos += 1;
+        let test = self.parse_named()?;
+        let body = self.parse_block()?;
+        let mut elifs = Vec::new();
+        let mut orelse = None;
+        loop {
+            // `else if` is what C users type for `elif`
+            let elif = if self.at_else_if() {
+                self.pos += 1;
+                self.eat_kw("if")
+            } else {
+                self.eat_kw("elif")
+            };
+            if elif {
+                let test = self.parse_named()?;
+                let body = self.parse_block()?;
+                elifs.push(Elif { test, body });
+            } else if self.eat_kw("else") {
+                orelse = Some(self.parse_block()?);
+                break;
+            } else {
+                break;
+            }
+        }
+        Ok(If {
+            test,
+            body,
+            elifs,
+            orelse,
+        })
+    }
+
+    fn parse_try(&mut self) -> PResult<Try> {
+        self.pos += 1;
+        let body = self.parse_block()?;
+        let mut handlers: Vec<ExceptHandler> = Vec::new();
+        while self.at_kw("except") {
+            let span = self.span();
+            self.pos += 1;
+            if handlers.last().is_some_and(|h| h.typ.is_none()) {
+                self.errors.push(
+                    Diagnostic::error(
+                        codes::INVALID_TRY,
+                        "a bare `except` must be the last handler",
+                    )
+                    .with_primary(span, "this handler can never run"),
+                );
+            }
+            handlers.push(self.parse_except()?);
+        }
+        let orelse = if self.at_kw("else") && !self.at_else_if() {
+            if handlers.is_empty() {
+                return Err(self.error_here(
+                    codes::INVALID_TRY,
+                    "a `try` needs an `except` block before `else`",
+                ));
+            }
+            self.pos += 1;
+            Some(self.parse_block()?)
+        } else {
+            None
+        };
+        let finalbody = if self.eat_kw("finally") {
+            Some(self.parse_block()?)
+        } else {
+            None
+        };
+        if handlers.is_empty() && finalbody.is_none() {
+            return Err(self.unexpected("`except` or `finally`"));
+        }
+        Ok(Try {
+            body,
+            handlers,
+            orelse,
+            finalbody,
+        })
+    }
+
+    /// The rest of an `except` handler: nothing, `E`, `E as e`, `(E as e)`,
+    /// `(E1, E2)` or `(E1, E2) as e`, then the block
+    fn parse_except(&mut self) -> PResult<ExceptHandler> {
+        let mut typ = None;
+        let mut name = None;
+        if !self.at_op("{") {
+            if self.eat_op("(") {
+                let types = self.parse_exprlist(false)?;
+                typ = Some(match types {
+                    Expr::Tuple(_) => Expr::Paren(Box::new(types)),
+                    _ => types,
+                });
+                if self.eat_kw("as") {
+                    name = Some(self.expect_name()?);
+                }
+                self.expect_op(")")?;
+            } else {
+                typ = Some(self.parse_test()?);
+            }
+            if name.is_none() && self.eat_kw("as") {
+                name = Some(self.expect_name()?);
+            }
+        }
+        let body = self.parse_block()?;
+        Ok(ExceptHandler { typ, name, body })
+    }
+
+    fn at_else_if(&mut self) -> bool {
+        self.at_kw("else") && matches!(self.peek_nth(1), Tok::Name(n) if n == "if")
+    }
+
+    /// `for target in iter { }`, optionally with the header in parentheses,
+    /// or a C-style `for (init; test; step) { }`
+    fn parse_for(&mut self) -> PResult<Stmt> {
+        self.pos += 1;
+        if self.at_op("(") && self.paren_has_semicolon() {
+            return self.parse_c_for().map(Stmt::CFor);
+        }
+        let (target, value, iter) = if self.eat_op("(") {
+            let target = self.parse_target_list()?;
+            let value = self.parse_for_value()?;
+            if self.eat_kw("in") {
+                let iter = self.parse_exprlist(false)?;
+                self.expect_op(")")?;
+                (target, value, iter)
+            } else if value.is_some() {
+                return Err(self.unexpected("`in`"));
+            } else {
+                // `for (a, b) in pairs { }`: the parentheses belong to the target
+                self.expect_op(")")?;
+                let target = Expr::Paren(Box::new(target));
+                if !self.eat_kw("in") {
+                    return Err(self.unexpected("`in`"));
+                }
+                (target, None, self.parse_exprlist(false)?)
+            }
+        } else {
+            let target = self.parse_target_list()?;
+            let value = self.parse_for_value()?;
+            if !self.eat_kw("in") {
+                return Err(self.unexpected("`in`"));
+            }
+            (target, value, self.parse_exprlist(false)?)
+        };
+        let body = self.parse_loop_body()?;
+        Ok(Stmt::For(For {
+            is_async: false,
+            target,
+            value,
+            iter,
+            body,
+        }))
+    }
+
+    /// The `: v` after the key in `for (k: v in d)`
+    fn parse_for_value(&mut self) -> PResult<Option<Expr>> {
+        if self.eat_op(":") {
+            self.parse_target_list().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_c_for(&mut self) -> PResult<CFor> {
+        self.expect_op("(")?;
+        if self.at_kw("let") && matches!(self.peek_nth(1), Tok::Name(_)) {
+            self.pos += 1;
+        }
+        let init = self.parse_for_clause(";")?;
+        self.expect_op(";")?;
+        let test = if self.at_op(";") {
+            None
+        } else {
+            Some(self.parse_named()?)
+        };
+        self.expect_op(";")?;
+        let step = self.parse_for_clause(")")?;
+        self.expect_op(")")?;
+        let body = self.parse_loop_body()?;
+        Ok(CFor {
+            init,
+            test,
+            step,
+            body,
+        })
+    }
+
+    /// The init or step of a C-style `for` up to `close`: simple statements
+    /// separated by commas, which as in C run one after the other, so
+    /// `i = 0, j = n` is two assignments rather than one of a tuple
+    fn parse_for_clause(&mut self, close: &str) -> PResult<Vec<Stmt>> {
+        let mut stmts = Vec::new();
+        while !self.at_op(close) {
+            let at = self.span();
+            let first = self.parse_test()?;
+            let stmt = if let Some(op) = self.aug_assign_op() {
+                self.pos += 1;
+                self.check_single_target(&first, at);
+                Stmt::AugAssign(AugAssign {
+                    target: first,
+                    op,
+                    value: self.parse_test()?,
+                })
+            } else if self.eat_op(":") {
+                self.check_single_target(&first, at);
+                let annotation = self.parse_test()?;
+                let value = if self.eat_op("=") {
+                    Some(self.parse_test()?)
+                } else {
+                    None
+                };
+                Stmt::AnnAssign(AnnAssign {
+                    target: first,
+                    annotation,
+                    value,
+                })
+            } else if self.at_op("=") {
+                let mut targets = vec![first];
+                while self.eat_op("=") {
+                    targets.push(self.parse_test()?);
+                }
+                let value = targets.pop().expect("assignment has a value");
+                for target in &targets {
+                    self.check_target(target, at);
+                }
+                Stmt::Assign(Assign { targets, value })
+            } else {
+                Stmt::Expr {
+                    value: first,
+                    terminated: true,
+                }
+            };
+            stmts.push(stmt);
+            if !self.eat_op(",") {
+                break;
+            }
+        }
+        Ok(stmts)
+    }
+
+    /// Whether the parenthesized group at the next token has a `;` at its own
+    /// level, as in the header of a C-style `for`
+    fn paren_has_semicolon(&mut self) -> bool {
+        self.skip_comments();
+        let mut depth = 0usize;
+        for tok in &self.toks[self.pos..] {
+            match tok {
+                Tok::Op("(") | Tok::Op("[") | Tok::Op("{") => depth += 1,
+                Tok::Op(")") | Tok::Op("]") | Tok::Op("}") => {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        return false;
+                    }
+                }
+                Tok::Op(";") if depth == 1 => return true,
+                Tok::Eof => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Whether the statement starting at the next token is a block header: a
+    /// `{` follows at bracket depth zero before any `;` or `=`
+    fn block_follows(&mut self) -> bool {
+        self.skip_comments();
+        let mut depth = 0usize;
+        for tok in &self.toks[self.pos + 1..] {
+            match tok {
+                Tok::Op("{") if depth == 0 => return true,
+                Tok::Op("(") | Tok::Op("[") | Tok::Op("{") => depth += 1,
+                Tok::Op(")") | Tok::Op("]") | Tok::Op("}") if depth > 0 => depth -= 1,
+                Tok::Op(";") | Tok::Op("=") | Tok::Op("}") | Tok::Eof if depth == 0 => {
+                    return false;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Index in `toks` of the next `op` outside brackets, before the end of
+    /// the statement or enclosing block
+    fn find_top_level(&mut self, op: &str) -> Option<usize> {
+        self.skip_comments();
+        let mut depth = 0usize;
+        for (i, tok) in self.toks.iter().enumerate().skip(self.pos) {
+            match tok {
+                Tok::Op(o) if *o == op && depth == 0 => return Some(i),
+                Tok::Op("(") | Tok::Op("[") | Tok::Op("{") => depth += 1,
+                Tok::Op(")") | Tok::Op("]") | Tok::Op("}") if depth > 0 => depth -= 1,
+                Tok::Op("}") | Tok::Op(";") | Tok::Eof if depth == 0 => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Whether an arrow function `x => ...` or `(params) => ...` starts at
+    /// the next token
+    fn arrow_follows(&mut self) -> bool {
+        self.skip_comments();
+        let mut toks = self.toks[self.pos..]
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| !matches!(t, Tok::Comment(_)));
+        let arrow = match toks.next() {
+            Some((_, Tok::Name(n))) if !KEYWORDS.contains(&n.as_str()) => toks.next(),
+            Some((_, Tok::Op("("))) => {
+                let mut depth = 1usize;
+                loop {
+                    match toks.next() {
+                        Some((_, Tok::Op("(") | Tok::Op("[") | Tok::Op("{"))) => depth += 1,
+                        Some((_, Tok::Op(")") | Tok::Op("]") | Tok::Op("}"))) => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break toks.next();
+                            }
+                        }
+                        Some((_, Tok::Eof)) | None => return false,
+                        _ => {}
+                    }
+                }
+            }
+            _ => return false,
+        };
+        matches!(arrow, Some((i, Tok::Op("=>"))) if Some(self.pos + i) != self.header_arrow)
+    }
+
+    fn at_case_label(&mut self) -> bool {
+        self.at_kw("case") || (self.at_kw("default") && matches!(self.peek_nth(1), Tok::Op(":")))
+    }
+
+    /// `switch (subject) { case a, b: ... default: ... }`.
+    ///
+    /// A case with statements must end with `break`, `return`, `continue` or
+    /// `raise` unless it is the last one; running on into the next case is
+    /// reported instead of silently changing meaning.
+    fn parse_switch(&mut self) -> PResult<Switch> {
+        self.pos += 1;
+        let subject = self.parse_named()?;
+        let open = self.span();
+        self.expect_op("{")?;
+        let mut cases = Vec::new();
+        let mut default = None;
+        // Labels without statements of their own share the next case's body
+        let mut shared_values = Vec::new();
+        let mut shared_default = false;
+        let mut shared_comments = Vec::new();
+        let mut seen_default = false;
+        loop {
+            self.flush_comments(&mut shared_comments);
+            let label = self.span();
+            let values = if self.eat_op("}") {
+                break;
+            } else if self.eat_kw("case") {
+                let mut values = vec![self.parse_test()?];
+                while self.eat_op(",") {
+                    values.push(self.parse_test()?);
+                }
+                self.expect_op(":")?;
+                Some(values)
+            } else if self.at_case_label() {
+                self.pos += 2;
+                if seen_default {
+                    self.errors.push(
+                        Diagnostic::error(
+                            codes::INVALID_SWITCH,
+                            "a switch can only have one `default`",
+                        )
+                        .with_span(label),
+                    );
+                }
+                seen_default = true;
+                None
+            } else if matches!(self.peek(), Tok::Eof) {
+                return Err(Box::new(
+                    Diagnostic::error(codes::UNCLOSED_BLOCK, "unclosed block: expected `}`")
+                        .with_primary(open, "this block is never closed"),
+                ));
+            } else {
+                return Err(self.unexpected("`case`, `default` or `}`"));
+            };
+            let mut body = std::mem::take(&mut shared_comments);
+            let outer = self.scope;
+            self.scope.in_switch = true;
+            loop {
+                self.flush_comments(&mut body);
+                if self.at_case_label() || matches!(self.peek(), Tok::Op("}") | Tok::Eof) {
+                    break;
+                }
+                // As in C, braces around a case's statements only group them
+                if self.at_op("{") {
+                    match self.parse_block() {
+                        Ok(block) => body.extend(block),
+                        Err(err) => {
+                            self.errors.push(*err);
+                            self.recover();
+                        }
+                    }
+                    continue;
+                }
+                self.parse_stmt_into(&mut body);
+            }
+            self.scope = outer;
+            let last = body.iter().rposition(|s| !matches!(s, Stmt::Comment(_)));
+            if last.is_none() && self.at_case_label() {
+                match values {
+                    Some(values) => shared_values.extend(values),
+                    None => shared_default = true,
+                }
+                shared_comments = body;
+                continue;
+            }
+            if let Some(last) = last {
+                let ends = matches!(
+                    body[last],
+                    Stmt::Break | Stmt::Return(_) | Stmt::Continue | Stmt::Raise { .. }
+                );
+                if body[last] == Stmt::Break {
+                    body.remove(last);
+                }
+                if contains_break(&body) {
+                    self.errors.push(
+                        Diagnostic::error(
+                            codes::INVALID_SWITCH,
+                            "`break` can only be the last statement of a case",
+                        )
+                        .with_primary(label, "this case leaves the switch early")
+                        .with_note("Python has no statement that leaves a `match` early"),
+                    );
+                }
+                if !ends && self.at_case_label() {
+                    let next = self.span();
+                    // `break;` goes right after the case's last statement
+                    let last_tok = self.prev_span();
+                    let after_last = Span::new(
+                        last_tok.end,
+                        last_tok.end,
+                        last_tok.line,
+                        last_tok.col + (last_tok.end - last_tok.start),
+                    );
+                    self.errors.push(
+                        Diagnostic::error(
+                            codes::CASE_FALLTHROUGH,
+                            "case falls through into the next case",
+                        )
+                        .with_primary(label, "this case does not end with `break`")
+                        .with_label(next, "and would run on into this case")
+                        .with_note("list values together as `case 1, 2:` to share a body")
+                        .with_suggestion(
+                            after_last,
+                            "break;",
+                            "end the case with `break;`",
+                        ),
+                    );
+                }
+            }
+            match values {
+                Some(mut values) => {
+                    if shared_default {
+                        default.get_or_insert_with(|| body.clone());
+                    }
+                    values.splice(0..0, shared_values.drain(..));
+                    cases.push(Case { values, body });
+                }
+                None => {
+                    // Values sharing the default body need no case of their own
+                    shared_values.clear();
+                    default.get_or_insert(body);
+                }
+            }
+            shared_default = false;
+        }
+        Ok(Switch {
+            subject,
+            cases,
+            default,
+        })
+    }
+
+    /// `match (subject) { case pattern if guard => { ... } }`.
+    ///
+    /// A case after one that matches every value can never run, which Python
+    /// rejects, so it is reported here instead.
+    fn parse_match(&mut self) -> PResult<Match> {
+        let start = self.span();
+        self.pos += 1;
+        let subject = self.parse_named()?;
+        let span = start.to(self.prev_span());
+        let open = self.span();
+        self.expect_op("{")?;
+        let mut cases: Vec<MatchCase> = Vec::new();
+        let mut comments = Vec::new();
+        let mut catch_all = None;
+        loop {
+            self.flush_comments(&mut comments);
+            let label = self.span();
+            if self.eat_op("}") {
+                break;
+            } else if matches!(self.peek(), Tok::Eof) {
+                return Err(Box::new(
+                    Diagnostic::error(codes::UNCLOSED_BLOCK, "unclosed block: expected `}`")
+                        .with_primary(open, "this block is never closed"),
+                ));
+            } else if !self.eat_kw("case") {
+                return Err(self.unexpected("`case` or `}`"));
+            }
+            let pattern = self.parse_case_pattern()?;
+            let guard = if self.eat_kw("if") {
+                self.header_arrow = self.find_top_level("=>");
+                let guard = self.parse_named();
+                self.header_arrow = None;
+                Some(guard?)
+            } else {
+                None
+            };
+            self.expect_op("=>")?;
+            let mut body = std::mem::take(&mut comments);
+            body.extend(self.parse_block()?);
+            if let Some(earlier) = catch_all {
+                self.errors.push(
+                    Diagnostic::error(codes::INVALID_MATCH, "this case can never run")
+                        .with_primary(label, "unreachable case")
+                        .with_label(earlier, "this case already matches every value"),
+                );
+            } else if guard.is_none() && pattern.is_irrefutable() {
+                catch_all = Some(label);
+            }
+            cases.push(MatchCase {
+                pattern,
+                guard,
+                body,
+            });
+        }
+        match cases.last_mut() {
+            Some(last) => last.body.extend(comments),
+            None => self.errors.push(
+                Diagnostic::error(codes::INVALID_MATCH, "a match needs at least one case")
+                    .with_span(start),
+            ),
+        }
+        Ok(Match {
+            subject,
+            cases,
+            span,
+        })
+    }
+
+    /// The pattern after `case`, where a top-level comma makes a sequence as
+    /// in `case a, *rest =>`
+    fn parse_case_pattern(&mut self) -> PResult<Pattern> {
+        let start = self.span();
+        let first = self.parse_pattern_item()?;
+        if !self.at_op(",") && !matches!(first, Pattern::Star(_)) {
+            return Ok(first);
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if self.at_kw("if") || self.at_op("=>") {
+                break;
+            }
+            items.push(self.parse_pattern_item()?);
+        }
+        Ok(self.sequence_pattern(items, start))
+    }
+
+    fn parse_pattern_item(&mut self) -> PResult<Pattern> {
+        if !self.eat_op("*") {
+            return self.parse_pattern();
+        }
+        let name = self.expect_name()?;
+        Ok(Pattern::Star((name != "_").then_some(name)))
+    }
+
+    /// Patterns up to `close`, and whether a comma separated them
+    fn parse_pattern_items(&mut self, close: &str) -> PResult<(Vec<Pattern>, bool)> {
+        let mut items = Vec::new();
+        let mut comma = false;
+        while !self.eat_op(close) {
+            items.push(self.parse_pattern_item()?);
+            if !self.eat_op(",") {
+                self.expect_op(close)?;
+                break;
+            }
+            comma = true;
+        }
+        Ok((items, comma))
+    }
+
+    fn sequence_pattern(&mut self, items: Vec<Pattern>, start: Span) -> Pattern {
+        if items
+            .iter()
+            .filter(|p| matches!(p, Pattern::Star(_)))
+            .count()
+            > 1
+        {
+            self.errors.push(
+                Diagnostic::error(
+                    codes::INVALID_MATCH,
+                    "a sequence pattern can only have one `*` item",
+                )
+                .with_span(start),
+            );
+        }
+        Pattern::Sequence(items)
+    }
+
+    /// `closed | closed ... [as name]`
+    fn parse_pattern(&mut self) -> PResult<Pattern> {
+        let start = self.span();
+        let mut alts = vec![self.parse_closed_pattern()?];
+        while self.eat_op("|") {
+            alts.push(self.parse_closed_pattern()?);
+        }
+        let pattern = if alts.len() == 1 {
+            alts.remove(0)
+        } else {
+            let mut names = alts[0].bound_names();
+            names.sort_unstable();
+            let differ = alts[1..].iter().any(|alt| {
+                let mut other = alt.bound_names();
+                other.sort_unstable();
+                other != names
+            });
+            if differ {
+                self.errors.push(
+                    Diagnostic::error(
+                        codes::INVALID_MATCH,
+                        "the alternatives of `|` must bind the same names",
+                    )
+                    .with_span(start),
+                );
+            }
+            Pattern::Or(alts)
+        };
+        if !self.eat_kw("as") {
+            return Ok(pattern);
+        }
+        let name = self.expect_name()?;
+        Ok(Pattern::As {
+            pattern: Box::new(pattern),
+            name,
+        })
+    }
+
+    /// A pattern that needs no parentheses around it: a literal, name, class,
+    /// sequence or mapping pattern, or a parenthesized one
+    fn parse_closed_pattern(&mut self) -> PResult<Pattern> {
+        let start = self.span();
+        match self.peek().clone() {
+            Tok::Op("[") => {
+                self.pos += 1;
+                let (items, _) = self.parse_pattern_items("]")?;
+                Ok(self.sequence_pattern(items, start))
+            }
+            Tok::Op("(") => {
+                self.pos += 1;
+                let (mut items, comma) = self.parse_pattern_items(")")?;
+                if items.len() == 1 && !comma && !matches!(items[0], Pattern::Star(_)) {
+                    return Ok(items.remove(0));
+                }
+                Ok(self.sequence_pattern(items, start))
+            }
+            Tok::Op("{") => {
+                self.pos += 1;
+                self.parse_mapping_pattern()
+            }
+            Tok::Name(n) if n == "_" => {
+                self.pos += 1;
+                Ok(Pattern::Wildcard)
+            }
+            Tok::Name(n) if python_literal(&n).is_some() => {
+                self.pos += 1;
+                let n = python_literal(&n).unwrap_or(&n).to_string();
+                Ok(Pattern::Value(Expr::Name(n)))
+            }
+            Tok::Name(n) if !KEYWORDS.contains(&n.as_str()) => {
+                self.pos += 1;
+                let mut expr = Expr::Name(n);
+                while self.eat_op(".") {
+                    let attr = self.expect_name()?;
+                    expr = Expr::Attribute {
+                        value: Box::new(expr),
+                        attr,
+                    };
+                }
+                if self.eat_op("(") {
+                    return self.parse_class_pattern(expr);
+                }
+                Ok(match expr {
+                    Expr::Name(n) => Pattern::Capture(n),
+                    expr => Pattern::Value(expr),
+                })
+            }
+            // Literals, including negative numbers and complex ones like `1 + 2j`
+            Tok::Number(_) | Tok::Str(_) | Tok::Op("-") => {
+                Ok(Pattern::Value(self.parse_binary(4)?))
+            }
+            _ => Err(self.unexpected("a pattern")),
+        }
+    }
+
+    /// After `Cls(`: positional patterns, then `attr=pattern` ones. A bare
+    /// name among the latter is short for `name=name`, as in `Point(x=0, y)`.
+    fn parse_class_pattern(&mut self, cls: Expr) -> PResult<Pattern> {
+        let mut args = Vec::new();
+        let mut kwargs = Vec::new();
+        while !self.eat_op(")") {
+            let name = match self.peek().clone() {
+                Tok::Name(n) if !KEYWORDS.contains(&n.as_str()) => Some(n),
+                _ => None,
+            };
+            if let Some(n) = name.clone()
+                && matches!(self.peek_nth(1), Tok::Op("="))
+            {
+                self.pos += 2;
+                kwargs.push((n, self.parse_pattern()?));
+            } else if let Some(n) = name
+                && !kwargs.is_empty()
+                && n != "_"
+                && matches!(self.peek_nth(1), Tok::Op(",") | Tok::Op(")"))
+            {
+                self.pos += 1;
+                kwargs.push((n.clone(), Pattern::Capture(n)));
+            } else if kwargs.is_empty() {
+                args.push(self.parse_pattern()?);
+            } else {
+                return Err(self.error_here(
+                    codes::INVALID_MATCH,
+                    "positional patterns must come before keyword patterns",
+                ));
+            }
+            if !self.eat_op(",") {
+                self.expect_op(")")?;
+                break;
+            }
+        }
+        Ok(Pattern::Class { cls, args, kwargs })
+    }
+
+    /// After `{`: `key: pattern` items, optionally ending with `**rest`
+    fn parse_mapping_pattern(&mut self) -> PResult<Pattern> {
+        let mut items = Vec::new();
+        let mut rest = None;
+        while !self.eat_op("}") {
+            if self.eat_op("**") {
+                rest = Some(self.expect_name()?);
+                self.eat_op(",");
+                self.expect_op("}")?;
+                break;
+            }
+            let start = self.span();
+            let key = self.parse_closed_pattern()?;
+            self.expect_op(":")?;
+            let value = self.parse_pattern()?;
+            match key {
+                Pattern::Value(key) => items.push((key, value)),
+                _ => self.errors.push(
+                    Diagnostic::error(
+                        codes::INVALID_MATCH,
+                        "mapping pattern keys must be literals or dotted names",
+                    )
+                    .with_span(start),
+                ),
+            }
+            if !self.eat_op(",") {
+                self.expect_op("}")?;
+                break;
+            }
+        }
+        Ok(Pattern::Mapping { items, rest })
+    }
+
+    /// Comma separated assignment targets as used by `for` and comprehensions
+    fn parse_target_list(&mut self) -> PResult<Expr> {
+        let mut items = Vec::new();
+        let mut trailing = false;
+        loop {
+            let item = if self.eat_op("*") {
+                Expr::Starred(Box::new(self.parse_or_expr()?))
+            } else {
+                self.parse_or_expr()?
+            };
+            items.push(item);
+            if !self.eat_op(",") {
+                break;
+            }
+            trailing = true;
+            if self.at_kw("in") {
+                break;
+            }
+            trailing = false;
+        }
+        if items.len() == 1 && !trailing {
+            Ok(items.pop().unwrap())
+        } else {
+            Ok(Expr::Tuple(items))
+        }
+    }
+
+    // ---- expressions ----
+
+    /// Comma separated expressions; more than one (or a trailing comma) makes a tuple
+    fn parse_exprlist(&mut self, allow_star: bool) -> PResult<Expr> {
+        let first = self.parse_star_or_test(allow_star)?;
+        if !self.at_op(",") {
+            return Ok(first);
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if !self.starts_expr() {
+                break;
+            }
+            items.push(self.parse_star_or_test(allow_star)?);
+        }
+        Ok(Expr::Tuple(items))
+    }
+
+    fn parse_star_or_test(&mut self, allow_star: bool) -> PResult<Expr> {
+        if allow_star && self.eat_op("*") {
+            return Ok(Expr::Starred(Box::new(self.parse_or_expr()?)));
+        }
+        self.parse_test()
+    }
+
+    fn starts_expr(&mut self) -> bool {
+        match self.peek() {
+            Tok::Name(n) => {
+                !KEYWORDS.contains(&n.as_str())
+                    || matches!(n.as_str(), "not" | "lambda" | "await" | "yield")
+            }
+            Tok::Number(_) | Tok::Str(_) => true,
+            Tok::Op(o) => matches!(
+                *o,
+                "(" | "[" | "{" | "-" | "+" | "~" | "!" | "*" | "++" | "--" | "..."
+            ),
+            _ => false,
+        }
+    }
+
+    /// An expression that may be an assignment expression (`name := value`)
+    fn parse_named(&mut self) -> PResult<Expr> {
+        if let (Tok::Name(name), Tok::Op(":=")) = (self.peek().clone(), self.peek_nth(1).clone())
+            && !KEYWORDS.contains(&name.as_str())
+        {
+            self.pos += 1;
+            self.expect_op(":=")?;
+            let value = self.parse_test()?;
+            return Ok(Expr::NamedExpr {
+                target: name,
+                value: Box::new(value),
+            });
+        }
+        self.parse_test()
+    }
+
+    fn parse_test(&mut self) -> PResult<Expr> {
+        if self.at_kw("lambda") {
+            return self.parse_lambda();
+        }
+        if self.arrow_follows() {
+            return self.parse_arrow();
+        }
+        let body = self.parse_coalesce()?;
+        if self.eat_op("?") {
+            // C-style `test ? body : orelse`, right-associative
+            let test = body;
+            let body = self.parse_test()?;
+            self.expect_op(":")?;
+            let orelse = self.parse_test()?;
+            return Ok(Expr::IfExp {
+                test: Box::new(test),
+                body: Box::new(body),
+                orelse: Box::new(orelse),
+            });
+        }
+        if self.at_kw("if") {
+            self.pos += 1;
+            let test = self.parse_or_test()?;
+            if !self.eat_kw("else") {
+                return Err(self.unexpected("`else` in conditional expression"));
+            }
+            let orelse = self.parse_test()?;
+            return Ok(Expr::IfExp {
+                test: Box::new(test),
+                body: Box::new(body),
+                orelse: Box::new(orelse),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Expression without an unparenthesized conditional, as used in comprehension filters
+    fn parse_test_no_cond(&mut self) -> PResult<Expr> {
+        if self.at_kw("lambda") {
+            return self.parse_lambda();
+        }
+        if self.arrow_follows() {
+            return self.parse_arrow();
+        }
+        self.parse_coalesce()
+    }
+
+    /// `a ?? b`, right-associative and looser than `|>`
+    fn parse_coalesce(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        let left = self.parse_pipeline()?;
+        if !self.eat_op("??") {
+            return Ok(left);
+        }
+        let right = self.parse_coalesce()?;
+        Ok(Expr::Coalesce {
+            left: Box::new(left),
+            right: Box::new(right),
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    fn parse_lambda(&mut self) -> PResult<Expr> {
+        self.pos += 1;
+        let params = self.parse_params(":", false)?;
+        self.expect_op(":")?;
+        let body = self.within(FUNCTION, Self::parse_test)?;
+        Ok(Expr::Lambda {
+            params,
+            body: Box::new(body),
+        })
+    }
+
+    /// `x => body`, `(params) => body` or `(params) => { statements }`. A
+    /// body starting with `{` is always a block, so a dict or set literal
+    /// body needs parentheses.
+    fn parse_arrow(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        let params = if self.eat_op("(") {
+            let params = self.parse_params(")", false)?;
+            self.expect_op(")")?;
+            params
+        } else {
+            vec![Param {
+                kind: ParamKind::Normal,
+                name: self.expect_name()?,
+                annotation: None,
+                default: None,
+            }]
+        };
+        self.expect_op("=>")?;
+        if !self.at_op("{") {
+            let body = self.within(FUNCTION, Self::parse_test)?;
+            return Ok(Expr::Lambda {
+                params,
+                body: Box::new(body),
+            });
+        }
+        // An arrow in a guard may hold a `match` of its own
+        let header_arrow = self.header_arrow.take();
+        let body = self.within(FUNCTION, Self::parse_block);
+        self.header_arrow = header_arrow;
+        Ok(Expr::Function {
+            params,
+            body: body?,
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    /// `value |> stage`, left-associative and looser than `||`. A lambda or
+    /// arrow function stage needs parentheses.
+    fn parse_pipeline(&mut self) -> PResult<Expr> {
+        let start = self.span();
+        let mut value = self.parse_or_test()?;
+        while self.eat_op("|>") {
+            let stage = self.parse_or_test()?;
+            value = Expr::Pipe {
+                value: Box::new(value),
+                stage: Box::new(stage),
+                span: start.to(self.prev_span()),
+            };
+        }
+        Ok(value)
+    }
+
+    fn parse_or_test(&mut self) -> PResult<Expr> {
+        let mut left = self.parse_and_test()?;
+        while self.eat_kw("or") || self.eat_op("||") {
+            let right = self.parse_and_test()?;
+            left = Expr::BoolOp {
+                left: Box::new(left),
+                op: BoolOp::Or,
+                right: Box::new(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_and_test(&mut self) -> PResult<Expr> {
+        let mut left = self.parse_not_test()?;
+        while self.eat_kw("and") || self.eat_op("&&") {
+            let right = self.parse_not_test()?;
+            left = Expr::BoolOp {
+                left: Box::new(left),
+                op: BoolOp::And,
+                right: Box::new(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_not_test(&mut self) -> PResult<Expr> {
+        if self.eat_kw("not") {
+            let operand = self.parse_not_test()?;
+            return Ok(Expr::UnaryOp {
+                op: UnaryOp::Not,
+                operand: Box::new(operand),
+            });
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> PResult<Expr> {
+        let left = self.parse_range()?;
+        let mut ops = Vec::new();
+        while let Some(op) = self.comparison_op() {
+            let right = self.parse_range()?;
+            ops.push((op, right));
+        }
+        if ops.is_empty() {
+            Ok(left)
+        } else {
+            Ok(Expr::Compare {
+                left: Box::new(left),
+                ops,
+            })
+        }
+    }
+
+    /// `a..b` or `a..=b`, optionally followed by `step s`
+    fn parse_range(&mut self) -> PResult<Expr> {
+        let start = self.parse_or_expr()?;
+        let inclusive = if self.eat_op("..=") {
+            true
+        } else if self.eat_op("..") {
+            false
+        } else {
+            return Ok(start);
+        };
+        let stop = self.parse_or_expr()?;
+        let mut step = None;
+        if self.eat_kw("step") {
+            let at = self.span();
+            let s = self.parse_or_expr()?;
+            if inclusive && s.as_int().is_none() {
+                self.errors.push(
+                    Diagnostic::error(
+                        codes::INVALID_RANGE,
+                        "the step of an inclusive range must be a whole number",
+                    )
+                    .with_primary(at, "this step's sign is only known when it runs")
+                    .with_note(
+                        "`a..=b` ends just past `b`, which is `b + 1` or `b - 1` depending on \
+                         the sign of the step",
+                    ),
+                );
+            }
+            step = Some(Box::new(s));
+        }
+        Ok(Expr::Range {
+            start: Box::new(start),
+            stop: Box::new(stop),
+            inclusive,
+            step,
+        })
+    }
+
+    fn comparison_op(&mut self) -> Option<CmpOp> {
+        let op = match self.peek().clone() {
+            Tok::Op("==") => CmpOp::Eq,
+            Tok::Op("!=") => CmpOp::NotEq,
+            Tok::Op("<") => CmpOp::Lt,
+            Tok::Op("<=") => CmpOp::LtE,
+            Tok::Op(">") => CmpOp::Gt,
+            Tok::Op(">=") => CmpOp::GtE,
+            Tok::Name(n) if n == "in" => CmpOp::In,
+            Tok::Name(n) if n == "not" && matches!(self.peek_nth(1), Tok::Name(m) if m == "in") => {
+                self.pos += 1;
+                CmpOp::NotIn
+            }
+            Tok::Name(n) if n == "is" => {
+                if matches!(self.peek_nth(1), Tok::Name(m) if m == "not") {
+                    self.pos += 1;
+                    CmpOp::IsNot
+                } else {
+                    CmpOp::Is
+                }
+            }
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    /// Left-associative binary operator levels, loosest first
+    fn parse_binary(&mut self, level: usize) -> PResult<Expr> {
+        const LEVELS: &[&[(&str, BinOp)]] = &[
+            &[("|", BinOp::BitOr)],
+            &[("^", BinOp::BitXor)],
+            &[("&", BinOp::BitAnd)],
+            &[("<<", BinOp::LShift), (">>", BinOp::RShift)],
+            &[("+", BinOp::Add), ("-", BinOp::Sub)],
+            &[
+                ("*", BinOp::Mul),
+                ("/", BinOp::Div),
+                ("~/", BinOp::FloorDiv),
+                ("%", BinOp::Mod),
+                ("@", BinOp::MatMul),
+            ],
+        ];
+        if level == LEVELS.len() {
+            return self.parse_factor();
+        }
+        let mut left = self.parse_binary(level + 1)?;
+        'outer: loop {
+            for (tok, op) in LEVELS[level] {
+                if self.eat_op(tok) {
+                    let right = self.parse_binary(level + 1)?;
+                    left = Expr::BinOp {
+                        left: Box::new(left),
+                        op: *op,
+                        right: Box::new(right),
+                    };
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn parse_or_expr(&mut self) -> PResult<Expr> {
+        self.parse_binary(0)
+    }
+
+    fn parse_factor(&mut self) -> PResult<Expr> {
+        let op = match self.peek() {
+            Tok::Op("-") => Some(UnaryOp::Neg),
+            Tok::Op("+") => Some(UnaryOp::Pos),
+            Tok::Op("~") => Some(UnaryOp::Invert),
+            // C-style `!` binds as tightly as the other unary operators, unlike `not`
+            Tok::Op("!") => Some(UnaryOp::Not),
+            _ => None,
+        };
+        if let Some(op) = op {
+            self.pos += 1;
+            let operand = self.parse_factor()?;
+            return Ok(Expr::UnaryOp {
+                op,
+                operand: Box::new(operand),
+            });
+        }
+        let incdec = match self.peek() {
+            Tok::Op("++") => Some(IncDecOp::Inc),
+            Tok::Op("--") => Some(IncDecOp::Dec),
+            _ => None,
+        };
+        if let Some(op) = incdec {
+            let start = self.span();
+            self.pos += 1;
+            let at = self.span();
+            let target = self.parse_factor()?;
+            self.check_single_target(&target, at);
+            return Ok(Expr::IncDec {
+                op,
+                prefix: true,
+                target: Box::new(target),
+                span: start.to(self.prev_span()),
+            });
+        }
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> PResult<Expr> {
+        let base = if self.eat_kw("await") {
+            Expr::Await(Box::new(self.parse_postfix()?))
+        } else {
+            self.parse_postfix()?
+        };
+        if self.eat_op("**") {
+            let exp = self.parse_factor()?;
+            return Ok(Expr::BinOp {
+                left: Box::new(base),
+                op: BinOp::Pow,
+                right: Box::new(exp),
+            });
+        }
+        Ok(base)
+    }
+
+    /// Atom followed by calls, subscripts, attributes (each of the last two
+    /// optionally behind `?`) and postfix `++`/`--`
+    fn parse_postfix(&mut self) -> PResult<Expr> {
+        let at = self.span();
+        let mut expr = self.parse_atom()?;
+        loop {
+            if self.at_op("?.") || self.at_op("?[") {
+                expr = Expr::Optional {
+                    value: Box::new(expr),
+                    span: at.to(self.prev_span()),
+                };
+            }
+            if self.eat_op("(") {
+                let args = self.parse_call_args()?;
+                self.expect_op(")")?;
+                expr = Expr::Call {
+                    func: Box::new(expr),
+                    args,
+                };
+            } else if self.eat_op("[") || self.eat_op("?[") {
+                let index = self.parse_subscript()?;
+                self.expect_op("]")?;
+                expr = Expr::Subscript {
+                    value: Box::new(expr),
+                    index: Box::new(index),
+                };
+            } else if self.eat_op(".") || self.eat_op("?.") {
+                let attr = match self.peek().clone() {
+                    Tok::Name(n) => {
+                        self.pos += 1;
+                        n
+                    }
+                    _ => {
+                        return Err(self.error_here(
+                            codes::UNEXPECTED_TOKEN,
+                            "expected attribute name after `.`",
+                        ));
+                    }
+                };
+                expr = Expr::Attribute {
+                    value: Box::new(expr),
+                    attr,
+                };
+            } else if self.at_op("++") || self.at_op("--") {
+                self.check_single_target(&expr, at);
+                let op = if self.eat_op("++") {
+                    IncDecOp::Inc
+                } else {
+                    self.pos += 1;
+                    IncDecOp::Dec
+                };
+                return Ok(Expr::IncDec {
+                    op,
+                    prefix: false,
+                    target: Box::new(expr),
+                    span: at.to(self.prev_span()),
+                });
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_call_args(&mut self) -> PResult<Vec<Arg>> {
+        let mut args = Vec::new();
+        while !self.at_op(")") {
+            let arg = if self.eat_op("**") {
+                Arg::DoubleStar(self.parse_test()?)
+            } else if self.eat_op("*") {
+                Arg::Star(self.parse_test()?)
+            } else if let (Tok::Name(name), Tok::Op("=")) =
+                (self.peek().clone(), self.peek_nth(1).clone())
+            {
+                self.pos += 1;
+                self.expect_op("=")?;
+                Arg::Keyword(name, self.parse_test()?)
+            } else {
+                let value = self.parse_named()?;
+                if self.at_kw("for") || self.at_kw("async") {
+                    let generators = self.parse_comp_for()?;
+                    Arg::Positional(Expr::Comprehension {
+                        kind: ComprehensionKind::Generator,
+                        elt: Box::new(value),
+                        value: None,
+                        generators,
+                    })
+                } else {
+                    Arg::Positional(value)
+                }
+            };
+            args.push(arg);
+            if !self.eat_op(",") {
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    /// Contents of `[...]` after a value: an index, a slice or a tuple of them
+    fn parse_subscript(&mut self) -> PResult<Expr> {
+        let first = self.parse_slice_item()?;
+        if !self.at_op(",") {
+            return Ok(first);
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if self.at_op("]") {
+                break;
+            }
+            items.push(self.parse_slice_item()?);
+        }
+        Ok(Expr::Tuple(items))
+    }
+
+    fn parse_slice_item(&mut self) -> PResult<Expr> {
+        let lower = if self.at_op(":") {
+            None
+        } else {
+            let value = self.parse_named()?;
+            if !self.at_op(":") {
+                return Ok(value);
+            }
+            Some(Box::new(value))
+        };
+        self.expect_op(":")?;
+        let bound = |p: &mut Self| -> PResult<Option<Box<Expr>>> {
+            if p.at_op(":") || p.at_op("]") || p.at_op(",") {
+                Ok(None)
+            } else {
+                Ok(Some(Box::new(p.parse_test()?)))
+            }
+        };
+        let upper = bound(self)?;
+        let step = if self.eat_op(":") { bound(self)? } else { None };
+        Ok(Expr::Slice { lower, upper, step })
+    }
+
+    fn parse_comp_for(&mut self) -> PResult<Vec<Generator>> {
+        let mut generators = Vec::new();
+        while self.at_kw("for") || self.at_kw("async") {
+            let is_async = self.eat_kw("async");
+            if !self.eat_kw("for") {
+                return Err(self.unexpected("`for`"));
+            }
+            let target = self.parse_target_list()?;
+            if !self.eat_kw("in") {
+                return Err(self.unexpected("`in`"));
+            }
+            let iter = self.parse_coalesce()?;
+            let mut ifs = Vec::new();
+            while self.eat_kw("if") {
+                ifs.push(self.parse_test_no_cond()?);
+            }
+            generators.push(Generator {
+                is_async,
+                target,
+                iter,
+                ifs,
+            });
+        }
+        Ok(generators)
+    }
+
+    fn parse_yield(&mut self) -> PResult<Expr> {
+        if !self.scope.in_function {
+            let err = self.error_here(codes::MISPLACED_STATEMENT, "`yield` outside a function");
+            self.errors.push(*err);
+        }
+        self.pos += 1;
+        if self.eat_kw("from") {
+            return Ok(Expr::YieldFrom(Box::new(self.parse_test()?)));
+        }
+        if self.starts_expr() {
+            Ok(Expr::Yield(Some(Box::new(self.parse_exprlist(true)?))))
+        } else {
+            Ok(Expr::Yield(None))
+        }
+    }
+
+    fn parse_atom(&mut self) -> PResult<Expr> {
+        match self.peek().clone() {
+            Tok::Name(n) if n == "yield" => self.parse_yield(),
+            Tok::Name(n) if KEYWORDS.contains(&n.as_str()) => Err(self.unexpected("an expression")),
+            Tok::Name(n) => {
+                self.pos += 1;
+                // C-style literals are spelled the Python way, but only where
+                // the name is a value of its own, not an attribute or argument name
+                Ok(Expr::Name(python_literal(&n).map_or(n, str::to_string)))
+            }
+            Tok::Number(n) => {
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            }
+            Tok::Str(s) => {
+                self.pos += 1;
+                let mut parts = vec![s];
+                while let Tok::Str(s) = self.peek().clone() {
+                    self.pos += 1;
+                    parts.push(s);
+                }
+                Ok(Expr::Str(parts))
+            }
+            Tok::Op("...") => {
+                self.pos += 1;
+                Ok(Expr::Ellipsis)
+            }
+            Tok::Op("(") => {
+                self.pos += 1;
+                self.parse_paren()
+            }
+            Tok::Op("[") => {
+                self.pos += 1;
+                self.parse_list()
+            }
+            Tok::Op("{") => {
+                self.pos += 1;
+                self.parse_braces()
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    /// After `(`: parenthesized expression, tuple, generator or yield
+    fn parse_paren(&mut self) -> PResult<Expr> {
+        if self.eat_op(")") {
+            return Ok(Expr::Tuple(Vec::new()));
+        }
+        if self.at_kw("yield") {
+            let value = self.parse_yield()?;
+            self.expect_op(")")?;
+            return Ok(Expr::Paren(Box::new(value)));
+        }
+        let first = self.parse_star_or_named()?;
+        if self.at_kw("for") || self.at_kw("async") {
+            let generators = self.parse_comp_for()?;
+            self.expect_op(")")?;
+            return Ok(Expr::Comprehension {
+                kind: ComprehensionKind::Generator,
+                elt: Box::new(first),
+                value: None,
+                generators,
+            });
+        }
+        let inner = if self.at_op(",") {
+            let mut items = vec![first];
+            while self.eat_op(",") {
+                if self.at_op(")") {
+                    break;
+                }
+                items.push(self.parse_star_or_named()?);
+            }
+            Expr::Tuple(items)
+        } else {
+            first
+        };
+        self.expect_op(")")?;
+        Ok(Expr::Paren(Box::new(inner)))
+    }
+
+    fn parse_star_or_named(&mut self) -> PResult<Expr> {
+        if self.eat_op("*") {
+            return Ok(Expr::Starred(Box::new(self.parse_or_expr()?)));
+        }
+        self.parse_named()
+    }
+
+    fn parse_list(&mut self) -> PResult<Expr> {
+        if self.eat_op("]") {
+            return Ok(Expr::List(Vec::new()));
+        }
+        let first = self.parse_star_or_named()?;
+        if self.at_kw("for") || self.at_kw("async") {
+            let generators = self.parse_comp_for()?;
+            self.expect_op("]")?;
+            return Ok(Expr::Comprehension {
+                kind: ComprehensionKind::List,
+                elt: Box::new(first),
+                value: None,
+                generators,
+            });
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if self.at_op("]") {
+                break;
+            }
+            items.push(self.parse_star_or_named()?);
+        }
+        self.expect_op("]")?;
+        Ok(Expr::List(items))
+    }
+
+    /// After `{` in expression position: dict, set or one of their comprehensions
+    fn parse_braces(&mut self) -> PResult<Expr> {
+        if self.eat_op("}") {
+            return Ok(Expr::Dict(Vec::new()));
+        }
+        let first = if self.eat_op("**") {
+            DictItem::Unpack(self.parse_or_expr()?)
+        } else {
+            let key = self.parse_star_or_named()?;
+            if self.eat_op(":") {
+                DictItem::Pair(key, self.parse_test()?)
+            } else {
+                return self.parse_set(key);
+            }
+        };
+        if let DictItem::Pair(key, value) = &first
+            && (self.at_kw("for") || self.at_kw("async"))
+        {
+            let generators = self.parse_comp_for()?;
+            self.expect_op("}")?;
+            return Ok(Expr::Comprehension {
+                kind: ComprehensionKind::Dict,
+                elt: Box::new(key.clone()),
+                value: Some(Box::new(value.clone())),
+                generators,
+            });
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if self.at_op("}") {
+                break;
+            }
+            if self.eat_op("**") {
+                items.push(DictItem::Unpack(self.parse_or_expr()?));
+            } else {
+                let key = self.parse_test()?;
+                self.expect_op(":")?;
+                items.push(DictItem::Pair(key, self.parse_test()?));
+            }
+        }
+        self.expect_op("}")?;
+        Ok(Expr::Dict(items))
+    }
+
+    fn parse_set(&mut self, first: Expr) -> PResult<Expr> {
+        if self.at_kw("for") || self.at_kw("async") {
+            let generators = self.parse_comp_for()?;
+            self.expect_op("}")?;
+            return Ok(Expr::Comprehension {
+                kind: ComprehensionKind::Set,
+                elt: Box::new(first),
+                value: None,
+                generators,
+            });
+        }
+        let mut items = vec![first];
+        while self.eat_op(",") {
+            if self.at_op("}") {
+                break;
+            }
+            items.push(self.parse_star_or_named()?);
+        }
+        self.expect_op("}")?;
+        Ok(Expr::Set(items))
+    }
+}
+
+/// Whether `body` has a `break` that would leave an enclosing switch, looking
+/// into `if` blocks but not into loops, functions or nested switches
+fn contains_break(body: &[Stmt]) -> bool {
+    body.iter().any(|stmt| match stmt {
+        Stmt::Break => true,
+        Stmt::If(s) => {
+            contains_break(&s.body)
+                || s.elifs.iter().any(|e| contains_break(&e.body))
+                || s.orelse.as_deref().is_some_and(contains_break)
+        }
+        Stmt::Match(m) => m.cases.iter().any(|c| contains_break(&c.body)),
+        Stmt::With(w) => contains_break(&w.body),
+        Stmt::Try(t) => {
+            contains_break(&t.body)
+                || t.handlers.iter().any(|h| contains_break(&h.body))
+                || t.orelse.as_deref().is_some_and(contains_break)
+                || t.finalbody.as_deref().is_some_and(contains_break)
+        }
+        _ => false,
+    })
+}
+
+/// The Python spelling of `None`, `True` and `False` and of their C-style
+/// spellings `null`, `true` and `false`
+fn python_literal(name: &str) -> Option<&'static str> {
+    match name {
+        "None" | "null" => Some("None"),
+        "True" | "true" => Some("True"),
+        "False" | "false" => Some("False"),
+        _ => None,
+    }
+}
+
+fn describe(tok: &Tok) -> String {
+    match tok {
+        Tok::Name(n) => format!("`{}`", n),
+        Tok::Number(n) => format!("`{}`", n),
+        Tok::Str(s) => format!("string {}", s),
+        Tok::Op(o) => format!("`{}`", o),
+        Tok::Comment(_) => "a comment".to_string(),
+        Tok::Eof => "end of file".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(n: &str) -> Expr {
+        Expr::Name(n.to_string())
+    }
+
+    fn num(n: &str) -> Expr {
+        Expr::Number(n.to_string())
+    }
+
+    #[test]
+    fn test_parse_function_with_if_else() {
+        let module = parse(
+            "def factorial(n: int) {\n    if (n == 0) {\n        return 1;\n    } else {\n        return n * factorial(n - 1);\n    }\n}\n",
+        )
+        .unwrap();
+        let Stmt::FunctionDef(def) = &module.body[0] else {
+            panic!("expected a function, got {:?}", module.body);
+        };
+        assert_eq!(def.name, "factorial");
+        assert_eq!(def.params[0].name, "n");
+        assert_eq!(def.params[0].annotation, Some(name("int")));
+        let Stmt::If(if_stmt) = &def.body[0] else {
+            panic!("expected an if statement");
+        };
+        assert_eq!(
+            if_stmt.test,
+            Expr::Paren(Box::new(Expr::Compare {
+                left: Box::new(name("n")),
+                ops: vec![(CmpOp::Eq, num("0"))],
+            }))
+        );
+        assert_eq!(if_stmt.body, vec![Stmt::Return(Some(num("1")))]);
+        assert!(if_stmt.orelse.is_some());
+    }
+
+    #[test]
+    fn test_parse_dict_literal_and_comments() {
+        let module =
+            parse("// config\nd = {\"a\": [1, 2], **rest};\nprint(d[\"a\"][1:]);").unwrap();
+        assert_eq!(module.body[0], Stmt::Comment("// config".to_string()));
+        let Stmt::Assign(assign) = &module.body[1] else {
+            panic!("expected an assignment");
+        };
+        assert!(matches!(&assign.value, Expr::Dict(items) if items.len() == 2));
+        assert!(matches!(
+            module.body[2],
+            Stmt::Expr {
+                terminated: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_increment_and_tail_expression() {
+        let module = parse("def f(x) { x++; x * 2 }").unwrap();
+        let Stmt::FunctionDef(def) = &module.body[0] else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            def.body[0],
+            Stmt::Expr {
+                value: Expr::IncDec {
+                    op: IncDecOp::Inc,
+                    prefix: false,
+                    target: Box::new(name("x")),
+                    span: Span::new(11, 14, 1, 12),
+                },
+                terminated: true,
+            }
+        );
+        assert!(matches!(
+            def.body[1],
+            Stmt::Expr {
+                terminated: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_for_header_forms() {
+        for src in [
+            "for (i in range(10)) { }",
+            "for i in range(10) { }",
+            "for (k, v) in d.items() { }",
+            "for (k, v in d.items()) { }",
+        ] {
+            let module = parse(src).unwrap();
+            assert!(matches!(module.body[0], Stmt::For(_)), "{}", src);
+        }
+        for src in ["for (k: v in d) { }", "for k: v in d { }"] {
+            let module = parse(src).unwrap();
+            assert!(
+                matches!(&module.body[0], Stmt::For(f) if f.target == name("k") && f.value == Some(name("v"))),
+                "{}",
+                src
+            );
+        }
+        let errors = parse("for (k: v) in d { }").unwrap_err();
+        assert_eq!(errors[0].message, "expected `in`, found `)`");
+    }
+
+    #[test]
+    fn test_parse_c_style_for() {
+        let module = parse("for (let i = 0; i < n; i++) { }\nfor (;;) { break; }").unwrap();
+        let Stmt::CFor(c) = &module.body[0] else {
+            panic!("expected a C-style for, got {:?}", module.body);
+        };
+        assert_eq!(
+            c.init,
+            vec![Stmt::Assign(Assign {
+                targets: vec![name("i")],
+                value: num("0"),
+            })]
+        );
+        assert!(matches!(c.test, Some(Expr::Compare { .. })));
+        assert!(matches!(
+            c.step.as_slice(),
+            [Stmt::Expr {
+                value: Expr::IncDec { .. },
+                ..
+            }]
+        ));
+        assert_eq!(
+            module.body[1],
+            Stmt::CFor(CFor {
+                init: vec![],
+                test: None,
+                step: vec![],
+                body: vec![Stmt::Break],
+            })
+        );
+
+        let module = parse("for (i = 0, j = 5; i < j; i++, j--) { }").unwrap();
+        let Stmt::CFor(c) = &module.body[0] else {
+            panic!("expected a C-style for");
+        };
+        assert_eq!(
+            c.init,
+            vec![
+                Stmt::Assign(Assign {
+                    targets: vec![name("i")],
+                    value: num("0"),
+                }),
+                Stmt::Assign(Assign {
+                    targets: vec![name("j")],
+                    value: num("5"),
+                }),
+            ]
+        );
+        assert_eq!(c.step.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_c_style_logic() {
+        let module = parse("x = !a == b && c || d;").unwrap();
+        let not_a = Expr::UnaryOp {
+            op: UnaryOp::Not,
+            operand: Box::new(name("a")),
+        };
+        let and = Expr::BoolOp {
+            left: Box::new(Expr::Compare {
+                left: Box::new(not_a),
+                ops: vec![(CmpOp::Eq, name("b"))],
+            }),
+            op: BoolOp::And,
+            right: Box::new(name("c")),
+        };
+        assert_eq!(
+            module.body[0],
+            Stmt::Assign(Assign {
+                targets: vec![name("x")],
+                value: Expr::BoolOp {
+                    left: Box::new(and),
+                    op: BoolOp::Or,
+                    right: Box::new(name("d")),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_ternary_is_right_associative() {
+        let module = parse("x = a ? b : c ? d : e;").unwrap();
+        let if_exp = |test: &str, body: Expr, orelse: Expr| Expr::IfExp {
+            test: Box::new(name(test)),
+            body: Box::new(body),
+            orelse: Box::new(orelse),
+        };
+        assert_eq!(
+            module.body[0],
+            Stmt::Assign(Assign {
+                targets: vec![name("x")],
+                value: if_exp("a", name("b"), if_exp("c", name("d"), name("e"))),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_class() {
+        let src = "@register\n@cache(1)\nclass A(B, metaclass=M) { class C { } def f(self) { } }";
+        let module = parse(src).unwrap();
+        let Stmt::ClassDef(class) = &module.body[0] else {
+            panic!("expected a class, got {:?}", module.body);
+        };
+        assert_eq!(class.name, "A");
+        assert_eq!(class.decorators.len(), 2);
+        assert_eq!(
+            class.args,
+            vec![
+                Arg::Positional(name("B")),
+                Arg::Keyword("metaclass".to_string(), name("M")),
+            ]
+        );
+        assert!(matches!(
+            class.body.as_slice(),
+            [Stmt::ClassDef(_), Stmt::FunctionDef(_)]
+        ));
+        let errors = parse("@register\nx = 1;").unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "expected `def` or `class` after a decorator, found `x`"
+        );
+    }
+
+    #[test]
+    fn test_parse_braces_in_expressions() {
+        // The value of the one assignment or expression statement in `src`
+        let value = |src: &str| match parse(src).unwrap().body.remove(0) {
+            Stmt::Assign(assign) => assign.value,
+            Stmt::Expr { value, .. } => value,
+            other => panic!("expected an expression in {src:?}, got {other:?}"),
+        };
+        let Expr::Dict(items) = value("d = {\"a\": {\"b\": [1]}, \"c\": {}};") else {
+            panic!("expected a dict");
+        };
+        assert!(matches!(&items[0], DictItem::Pair(_, Expr::Dict(inner)) if inner.len() == 1));
+        assert_eq!(
+            items[1],
+            DictItem::Pair(Expr::Str(vec!["\"c\"".into()]), Expr::Dict(vec![]))
+        );
+        assert_eq!(value("e = {};"), Expr::Dict(vec![]));
+        assert_eq!(value("s = {1, 2};"), Expr::Set(vec![num("1"), num("2")]));
+        assert_eq!(value("s = {1};"), Expr::Set(vec![num("1")]));
+        assert!(matches!(
+            value("f({\"a\": 1}, [{}], ({1}));"),
+            Expr::Call { args, .. } if matches!(
+                args.as_slice(),
+                [Arg::Positional(Expr::Dict(_)), Arg::Positional(Expr::List(_)), Arg::Positional(Expr::Paren(_))]
+            )
+        ));
+        assert!(matches!(
+            value("x = {k: v for (k, v) in d};"),
+            Expr::Comprehension {
+                kind: ComprehensionKind::Dict,
+                ..
+            }
+        ));
+        assert!(matches!(
+            value("x = {v for v in d};"),
+            Expr::Comprehension {
+                kind: ComprehensionKind::Set,
+                ..
+            }
+        ));
+        assert!(matches!(value("x = {**a, \"b\": {}};"), Expr::Dict(items) if items.len() == 2));
+        assert!(matches!(value("x = c ? {} : {1};"), Expr::IfExp { .. }));
+        assert!(
+            matches!(value("f = lambda: {};"), Expr::Lambda { body, .. } if *body == Expr::Dict(vec![]))
+        );
+        // A statement may start with a literal
+        assert!(matches!(value("{1, 2}.union(s);"), Expr::Call { .. }));
+        assert!(
+            matches!(value("x = {\n    \"a\": 1,\n    \"b\": {\n        \"c\": 2,\n    },\n};"), Expr::Dict(items) if items.len() == 2)
+        );
+    }
+
+    #[test]
+    fn test_parse_braces_after_headers() {
+        // The one statement in `src`
+        let only = |src: &str| {
+            let mut body = parse(src).unwrap().body;
+            assert_eq!(body.len(), 1, "{src:?}");
+            body.remove(0)
+        };
+        // A literal in a header without parentheses, and in the block after it
+        let Stmt::If(if_stmt) = only("if x == {} { y = {1}; }") else {
+            panic!("expected an if statement");
+        };
+        assert!(
+            matches!(&if_stmt.test, Expr::Compare { ops, .. } if ops[0].1 == Expr::Dict(vec![]))
+        );
+        assert!(
+            matches!(if_stmt.body.as_slice(), [Stmt::Assign(a)] if a.value == Expr::Set(vec![num("1")]))
+        );
+        assert!(
+            matches!(only("for k in {\"a\": 1} { print(k); }"), Stmt::For(f) if matches!(f.iter, Expr::Dict(_)))
+        );
+        assert!(matches!(
+            only("while d != {\"a\": {}} { break; }"),
+            Stmt::While(_)
+        ));
+        assert!(matches!(only("if (x in {1, 2}) { }"), Stmt::If(i) if i.body.is_empty()));
+        // The last statement of a block may hold a slice or a literal
+        let Stmt::FunctionDef(def) = only("def f(xs) { return {\"a\": xs[1:2]}; }") else {
+            panic!("expected a function");
+        };
+        assert!(matches!(
+            def.body.as_slice(),
+            [Stmt::Return(Some(Expr::Dict(_)))]
+        ));
+        let Stmt::FunctionDef(def) = only("def f(xs) { xs[::2] }") else {
+            panic!("expected a function");
+        };
+        assert!(matches!(
+            def.body.as_slice(),
+            [Stmt::Expr {
+                value: Expr::Subscript { .. },
+                terminated: false
+            }]
+        ));
+        let Stmt::FunctionDef(def) = only("def f()\n{\n    {}\n}") else {
+            panic!("expected a function");
+        };
+        assert!(matches!(
+            def.body.as_slice(),
+            [Stmt::Expr {
+                value: Expr::Dict(_),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_parse_expression_body() {
+        let module = parse("def sq(x) -> int => x * x;\nclass A { def f(self) => 1 }").unwrap();
+        let Stmt::FunctionDef(def) = &module.body[0] else {
+            panic!("expected a function, got {:?}", module.body);
+        };
+        assert_eq!(def.returns, Some(name("int")));
+        assert!(matches!(
+            def.body.as_slice(),
+            [Stmt::Return(Some(Expr::BinOp { .. }))]
+        ));
+        assert!(matches!(&module.body[1], Stmt::ClassDef(c) if c.body.len() == 1));
+        let errors = parse("def f() => x = 1;").unwrap_err();
+        assert_eq!(errors[0].message, "expected `;`, found `=`");
+    }
+
+    #[test]
+    fn test_parse_multiline_decorators() {
+        let src = "@app.route(\n    \"/\", // root\n    methods=[\"GET\"],\n)\n@login_required\ndef index(request) {\n    return 1;\n}";
+        let module = parse(src).unwrap();
+        let [Stmt::Comment(comment), Stmt::FunctionDef(def)] = module.body.as_slice() else {
+            panic!("expected a comment and a def, got {:?}", module.body);
+        };
+        assert_eq!(comment, "// root");
+        assert_eq!(def.decorators.len(), 2);
+        assert!(matches!(&def.decorators[0], Expr::Call { args, .. } if args.len() == 2));
+        assert!(matches!(def.body.as_slice(), [Stmt::Return(Some(_))]));
+    }
+
+    #[test]
+    fn test_parse_with_and_async() {
+        let items = |src: &str| -> Vec<(Expr, Option<Expr>)> {
+            match &parse(src).unwrap().body[0] {
+                Stmt::With(w) => w
+                    .items
+                    .iter()
+                    .map(|i| (i.context.clone(), i.target.clone()))
+                    .collect(),
+                other => panic!("expected a with, got {:?}", other),
+            }
+        };
+        let a_as_f = vec![(name("a"), Some(name("f")))];
+        assert_eq!(items("with (a as f) { }"), a_as_f);
+        assert_eq!(items("with a as f { }"), a_as_f);
+        assert_eq!(
+            items("with (a) as f, b { }"),
+            vec![
+                (Expr::Paren(Box::new(name("a"))), Some(name("f"))),
+                (name("b"), None),
+            ]
+        );
+        assert_eq!(
+            items("with (a, b,) { }"),
+            vec![(name("a"), None), (name("b"), None)]
+        );
+
+        let module =
+            parse("@route async def f() { async with a { } async for x in await g() { } }")
+                .unwrap();
+        let Stmt::FunctionDef(def) = &module.body[0] else {
+            panic!("expected a def, got {:?}", module.body);
+        };
+        assert!(def.is_async && def.decorators.len() == 1);
+        assert!(matches!(&def.body[0], Stmt::With(w) if w.is_async));
+        assert!(matches!(
+            &def.body[1],
+            Stmt::For(For {
+                is_async: true,
+                iter: Expr::Await(_),
+                ..
+            })
+        ));
+        let errors = parse("async x = 1;").unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "expected `def`, `for` or `with` after `async`, found `x`"
+        );
+    }
+
+    #[test]
+    fn test_parse_try() {
+        let src = "try { } except (E as e) { } except (A, B) as e { } except C { } except { } else { } finally { }";
+        let module = parse(src).unwrap();
+        let Stmt::Try(t) = &module.body[0] else {
+            panic!("expected a try, got {:?}", module.body);
+        };
+        let handlers: Vec<(Option<&Expr>, Option<&str>)> = t
+            .handlers
+            .iter()
+            .map(|h| (h.typ.as_ref(), h.name.as_deref()))
+            .collect();
+        let pair = Expr::Paren(Box::new(Expr::Tuple(vec![name("A"), name("B")])));
+        assert_eq!(
+            handlers,
+            vec![
+                (Some(&name("E")), Some("e")),
+                (Some(&pair), Some("e")),
+                (Some(&name("C")), None),
+                (None, None),
+            ]
+        );
+        assert!(t.orelse.is_some() && t.finalbody.is_some());
+        assert_eq!(
+            parse("raise E from err;").unwrap().body[0],
+            Stmt::Raise {
+                exc: Some(name("E")),
+                cause: Some(name("err")),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_try_errors() {
+        for (src, code, message) in [
+            (
+                "try { }",
+                codes::UNEXPECTED_TOKEN,
+                "expected `except` or `finally`, found end of file",
+            ),
+            (
+                "try { } finally { } else { }",
+                codes::DANGLING_ELSE,
+                "`else` without a preceding `if` block",
+            ),
+            (
+                "try { } else { } finally { }",
+                codes::INVALID_TRY,
+                "a `try` needs an `except` block before `else`",
+            ),
+            (
+                "try { } except { } except E { }",
+                codes::INVALID_TRY,
+                "a bare `except` must be the last handler",
+            ),
+            (
+                "except E { }",
+                codes::DANGLING_ELSE,
+                "`except` without a preceding `try` block",
+            ),
+        ] {
+            let errors = parse(src).unwrap_err();
+            assert_eq!(
+                (errors[0].code, errors[0].message.as_str()),
+                (code, message)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_if_chains() {
+        let chain = |test: &str| If {
+            test: name("a"),
+            body: Vec::new(),
+            elifs: vec![Elif {
+                test: name(test),
+                body: Vec::new(),
+            }],
+            orelse: Some(vec![Stmt::Pass]),
+        };
+        for src in [
+            "if a { } elif b { } else { pass; }",
+            "if a {\n}\nelif b {\n}\nelse {\n    pass;\n}",
+            "if a { } else if b { }\nelse { pass; }",
+        ] {
+            let module = parse(src).unwrap();
+            assert_eq!(module.body[0], Stmt::If(chain("b")), "{}", src);
+        }
+        // Two `if` statements in a row stay separate
+        let module = parse("if a { } if b { }").unwrap();
+        assert_eq!(module.body.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_dangling_else() {
+        for (src, message) in [
+            (
+                "while a { } else { }",
+                "`else` without a preceding `if` block",
+            ),
+            (
+                "if a { } else { } else { }",
+                "`else` without a preceding `if` block",
+            ),
+            (
+                "if a { }; else if b { }",
+                "`else if` without a preceding `if` block",
+            ),
+            ("x = 1; elif b { }", "`elif` without a preceding `if` block"),
+        ] {
+            let errors = parse(src).unwrap_err();
+            assert_eq!(errors.len(), 1, "{}", src);
+            assert_eq!(errors[0].code, codes::DANGLING_ELSE);
+            assert_eq!(errors[0].message, message);
+        }
+    }
+
+    #[test]
+    fn test_parse_do_while() {
+        let module = parse("do { x += 1; } while (x < 3);\ndo = 1;").unwrap();
+        assert!(matches!(
+            &module.body[0],
+            Stmt::DoWhile(While { test: Expr::Paren(_), body }) if body.len() == 1
+        ));
+        assert!(matches!(module.body[1], Stmt::Assign(_)));
+        let errors = parse("do { x = 1; } (x);").unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "expected `while` after the body of `do`, found `(`"
+        );
+    }
+
+    #[test]
+    fn test_parse_switch() {
+        let src = "switch (x) { default: case 1: case 2: f(); break; case 3: raise; }\nswitch = 1;";
+        let module = parse(src).unwrap();
+        let call = Stmt::Expr {
+            value: Expr::Call {
+                func: Box::new(name("f")),
+                args: Vec::new(),
+            },
+            terminated: true,
+        };
+        assert_eq!(
+            module.body[0],
+            Stmt::Switch(Switch {
+                subject: Expr::Paren(Box::new(name("x"))),
+                cases: vec![
+                    Case {
+                        values: vec![num("1"), num("2")],
+                        body: vec![call.clone()],
+                    },
+                    Case {
+                        values: vec![num("3")],
+                        body: vec![Stmt::Raise {
+                            exc: None,
+                            cause: None,
+                        }],
+                    },
+                ],
+                default: Some(vec![call]),
+            })
+        );
+        assert!(matches!(module.body[1], Stmt::Assign(_)));
+    }
+
+    #[test]
+    fn test_parse_switch_errors() {
+        let errors = parse("switch (x) {\n  case 1: f();\n  case 2: g();\n}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::CASE_FALLTHROUGH);
+        assert_eq!(errors[0].span(), Some(Span::new(15, 19, 2, 3)));
+        let suggestion = errors[0].suggestion.as_ref().unwrap();
+        assert_eq!(suggestion.replacement, "break;");
+        assert_eq!(suggestion.span, Span::new(27, 27, 2, 15));
+        let errors =
+            parse("switch (x) { case 1: if (y) { break; } f(); break; default: default: }")
+                .unwrap_err();
+        let found: Vec<&str> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "`break` can only be the last statement of a case",
+                "a switch can only have one `default`",
+            ]
+        );
+        // A `break` inside a loop in a case belongs to that loop
+        assert!(parse("switch (x) { case 1: while (y) { break; } break; }").is_ok());
+        // Braces around a case's statements only group them
+        let module = parse("switch (x) { case 1: { f(); break; } default: { g(); } }").unwrap();
+        let Stmt::Switch(sw) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        assert_eq!(sw.cases[0].body.len(), 1);
+        assert_eq!(sw.default.as_ref().map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn test_parse_match() {
+        let src = "match (p) {\n  case Point(0, y=[y, *_]) | Point(x=0, y) if y => { }\n  // fallback\n  case {\"k\": -1, **rest} as m => { }\n}\nmatch = 1;";
+        let module = parse(src).unwrap();
+        let Stmt::Match(m) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        assert_eq!(m.subject, Expr::Paren(Box::new(name("p"))));
+        assert_eq!(
+            m.cases[0].pattern,
+            Pattern::Or(vec![
+                Pattern::Class {
+                    cls: name("Point"),
+                    args: vec![Pattern::Value(num("0"))],
+                    kwargs: vec![(
+                        "y".to_string(),
+                        Pattern::Sequence(vec![
+                            Pattern::Capture("y".to_string()),
+                            Pattern::Star(None)
+                        ])
+                    )],
+                },
+                Pattern::Class {
+                    cls: name("Point"),
+                    args: Vec::new(),
+                    kwargs: vec![
+                        ("x".to_string(), Pattern::Value(num("0"))),
+                        ("y".to_string(), Pattern::Capture("y".to_string())),
+                    ],
+                },
+            ])
+        );
+        assert_eq!(m.cases[0].guard, Some(name("y")));
+        assert_eq!(
+            m.cases[1],
+            MatchCase {
+                pattern: Pattern::As {
+                    pattern: Box::new(Pattern::Mapping {
+                        items: vec![(
+                            Expr::Str(vec!["\"k\"".to_string()]),
+                            Pattern::Value(Expr::UnaryOp {
+                                op: UnaryOp::Neg,
+                                operand: Box::new(num("1")),
+                            })
+                        )],
+                        rest: Some("rest".to_string()),
+                    }),
+                    name: "m".to_string(),
+                },
+                guard: None,
+                body: vec![Stmt::Comment("// fallback".to_string())],
+            }
+        );
+        assert!(matches!(module.body[1], Stmt::Assign(_)));
+        // A top-level comma makes a sequence; parentheses alone only group
+        let module = parse("match (x) { case a, (b), (c,) => { } }").unwrap();
+        let Stmt::Match(m) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        assert_eq!(
+            m.cases[0].pattern,
+            Pattern::Sequence(vec![
+                Pattern::Capture("a".to_string()),
+                Pattern::Capture("b".to_string()),
+                Pattern::Sequence(vec![Pattern::Capture("c".to_string())]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_match_errors() {
+        let errors = parse("match (x) {\n  case y => { }\n  case 1 => { }\n}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::INVALID_MATCH);
+        assert_eq!(errors[0].span(), Some(Span::new(30, 34, 3, 3)));
+        let errors = parse(
+            "match (x) { case [*a, *b] => { } case 1 | y => { } }\nmatch (y) { }\nmatch (z) { case {k: 1} => { } }",
+        )
+        .unwrap_err();
+        let found: Vec<&str> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "a sequence pattern can only have one `*` item",
+                "the alternatives of `|` must bind the same names",
+                "a match needs at least one case",
+                "mapping pattern keys must be literals or dotted names",
+            ]
+        );
+        // A guard makes a catch-all case refutable
+        assert!(parse("match (x) { case y if y => { } case _ => { } }").is_ok());
+    }
+
+    #[test]
+    fn test_parse_arrow_functions() {
+        let param = |n: &str| Param {
+            kind: ParamKind::Normal,
+            name: n.to_string(),
+            annotation: None,
+            default: None,
+        };
+        let module = parse("f = x => (y) => { return x; };\n(a, b) = t;").unwrap();
+        let Stmt::Assign(a) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        assert_eq!(
+            a.value,
+            Expr::Lambda {
+                params: vec![param("x")],
+                body: Box::new(Expr::Function {
+                    params: vec![param("y")],
+                    body: vec![Stmt::Return(Some(name("x")))],
+                    span: Span::new(9, 29, 1, 10),
+                }),
+            }
+        );
+        assert!(matches!(module.body[1], Stmt::Assign(_)));
+        // The `=>` after a guard ends the guard, even after parentheses
+        let module =
+            parse("match (x) { case y if (y) => { } case z if f((w) => w) => { } }").unwrap();
+        let Stmt::Match(m) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        assert_eq!(m.cases[0].guard, Some(Expr::Paren(Box::new(name("y")))));
+        assert!(matches!(
+            &m.cases[1].guard,
+            Some(Expr::Call { args, .. }) if matches!(args[0], Arg::Positional(Expr::Lambda { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let module = parse(
+            "for (i in 0..n + 1 step 2) { }
+ok = x in 1..=3;
+ys = xs[a..b];",
+        )
+        .unwrap();
+        let Stmt::For(f) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        assert_eq!(
+            f.iter,
+            Expr::Range {
+                start: Box::new(num("0")),
+                stop: Box::new(Expr::BinOp {
+                    left: Box::new(name("n")),
+                    op: BinOp::Add,
+                    right: Box::new(num("1")),
+                }),
+                inclusive: false,
+                step: Some(Box::new(num("2"))),
+            }
+        );
+        let Stmt::Assign(a) = &module.body[1] else {
+            panic!("{:?}", module.body[1]);
+        };
+        let Expr::Compare { ops, .. } = &a.value else {
+            panic!("{:?}", a.value);
+        };
+        assert!(matches!(
+            ops[0],
+            (
+                CmpOp::In,
+                Expr::Range {
+                    inclusive: true,
+                    ..
+                }
+            )
+        ));
+        let Stmt::Assign(a) = &module.body[2] else {
+            panic!("{:?}", module.body[2]);
+        };
+        assert!(
+            matches!(&a.value, Expr::Subscript { index, .. } if matches!(**index, Expr::Range { .. }))
+        );
+
+        let errors = parse("for (i in 0..=n step k) { }").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::INVALID_RANGE);
+        assert_eq!(errors[0].span(), Some(Span::new(21, 22, 1, 22)));
+        assert!(parse("for (i in 10..=0 step -1) { }").is_ok());
+    }
+
+    #[test]
+    fn test_parse_optional_chaining() {
+        let module = parse("a?.b[0]?.c(k) ?? d || e ?? f;").unwrap();
+        let Stmt::Expr { value, .. } = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        let optional = |e: Expr, end: usize| {
+            Box::new(Expr::Optional {
+                value: Box::new(e),
+                span: Span::new(0, end, 1, 1),
+            })
+        };
+        let chain = Expr::Call {
+            func: Box::new(Expr::Attribute {
+                value: optional(
+                    Expr::Subscript {
+                        value: Box::new(Expr::Attribute {
+                            value: optional(name("a"), 1),
+                            attr: "b".to_string(),
+                        }),
+                        index: Box::new(num("0")),
+                    },
+                    7,
+                ),
+                attr: "c".to_string(),
+            }),
+            args: vec![Arg::Positional(name("k"))],
+        };
+        assert_eq!(
+            *value,
+            Expr::Coalesce {
+                left: Box::new(chain),
+                right: Box::new(Expr::Coalesce {
+                    left: Box::new(Expr::BoolOp {
+                        left: Box::new(name("d")),
+                        op: BoolOp::Or,
+                        right: Box::new(name("e")),
+                    }),
+                    right: Box::new(name("f")),
+                    span: Span::new(17, 28, 1, 18),
+                }),
+                span: Span::new(0, 28, 1, 1),
+            }
+        );
+        assert!(parse("x = c ?[1] : [2];\ny = d?[1];").is_ok());
+
+        let errors = parse("a?.b = 1;\n(x, a?[0]) = t;\na?.n++;\ndel a?.b;").unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors.iter().all(|e| e.code == codes::INVALID_OPTIONAL));
+        assert_eq!(errors[0].span(), Some(Span::new(0, 1, 1, 1)));
+    }
+
+    #[test]
+    fn test_parse_pipelines() {
+        let module = parse("x = a || b |> f(_) |> g ?? c;").unwrap();
+        let Stmt::Assign(a) = &module.body[0] else {
+            panic!("{:?}", module.body[0]);
+        };
+        let call = |f: &str, args: Vec<Arg>| Expr::Call {
+            func: Box::new(name(f)),
+            args,
+        };
+        let pipe = Expr::Pipe {
+            value: Box::new(Expr::Pipe {
+                value: Box::new(Expr::BoolOp {
+                    left: Box::new(name("a")),
+                    op: BoolOp::Or,
+                    right: Box::new(name("b")),
+                }),
+                stage: Box::new(call("f", vec![Arg::Positional(name("_"))])),
+                span: Span::new(4, 18, 1, 5),
+            }),
+            stage: Box::new(name("g")),
+            span: Span::new(4, 23, 1, 5),
+        };
+        assert_eq!(
+            a.value,
+            Expr::Coalesce {
+                left: Box::new(pipe),
+                right: Box::new(name("c")),
+                span: Span::new(4, 28, 1, 5),
+            }
+        );
+        assert!(parse("y = xs |> x => x;").is_err());
+    }
+
+    #[test]
+    fn test_parse_reports_errors() {
+        let errors = parse("x = ;\ndef f() {\n  y = 1;\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "expected an expression, found `;`");
+        assert_eq!(errors[1].message, "unclosed block: expected `}`");
+        assert_eq!(errors[0].span(), Some(Span::new(4, 5, 1, 5)));
+        assert_eq!(errors[1].span(), Some(Span::new(14, 15, 2, 9)));
+        // Offsets count both bytes of a `\r\n`
+        let errors = parse("x = 1;\r\ny = ;").unwrap_err();
+        assert_eq!(errors[0].span(), Some(Span::new(12, 13, 2, 5)));
+        // End of file is just past the last token, even one spanning lines
+        let errors = parse("x = (1 + \n").unwrap_err();
+        assert_eq!(errors[0].message, "expected an expression, found end of file");
+        assert_eq!(errors[0].span(), Some(Span::new(8, 8, 1, 9)));
+        let errors = parse("s = '''a\nb''' +").unwrap_err();
+        assert_eq!(errors[0].span(), Some(Span::new(15, 15, 2, 7)));
+        let errors = parse("}").unwrap_err();
+        assert_eq!(errors[0].message, "unmatched `}` with no open block");
+    }
+
+    #[test]
+    fn test_parse_invalid_targets() {
+        let codes_of = |src: &str| -> Vec<&'static str> {
+            parse(src)
+                .err()
+                .unwrap_or_default()
+                .iter()
+                .map(|d| d.code)
+                .collect()
+        };
+        for src in [
+            "f() = 1;",
+            "x + 1 = 2;",
+            "1++;",
+            "--f();",
+            "a, b += 1;",
+            "null = 1;",
+        ] {
+            assert_eq!(codes_of(src), vec![codes::INVALID_TARGET], "{}", src);
+        }
+        for src in [
+            "a, *b = c;",
+            "[x.y, z[0]] = w;",
+            "(x) += 1;",
+            "a.b++;",
+            "del a[0], b;",
+        ] {
+            assert_eq!(codes_of(src), Vec::<&str>::new(), "{}", src);
+        }
+        assert_eq!(
+            codes_of("for (i = 0, f() = 1; ; ) { }"),
+            vec![codes::INVALID_TARGET]
+        );
+    }
+
+    #[test]
+    fn test_parse_misplaced_statements() {
+        let errors = parse("return 1;\nbreak;\nx = yield;\ncontinue;").unwrap_err();
+        let found: Vec<(&str, &str, usize)> = errors
+            .iter()
+            .map(|d| (d.code, d.message.as_str(), d.span().unwrap().line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (codes::MISPLACED_STATEMENT, "`return` outside a function", 1),
+                (
+                    codes::MISPLACED_STATEMENT,
+                    "`break` outside a loop or `switch`",
+                    2
+                ),
+                (codes::MISPLACED_STATEMENT, "`yield` outside a function", 3),
+                (codes::MISPLACED_STATEMENT, "`continue` outside a loop", 4),
+            ]
+        );
+        assert!(
+            parse(
+                "def f() { while (x) { if (y) { continue; } break; } yield 1; return; }\n\
+                 switch (x) { case 1: break; }\n\
+                 for (y in ys) { switch (y) { case 1: continue; } }\n\
+                 f = () => { return 1; };"
+            )
+            .is_ok()
+        );
+        // A class or function body starts afresh
+        assert_eq!(
+            parse("while (x) { def f() { break; } class C { return; } }")
+                .unwrap_err()
+                .len(),
+            2
+        );
+        assert!(parse("switch (x) { case 1: continue; }").is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Source locations for tokens and diagnostics
+
+/// A region of NWPython source.
+///
+/// `start` and `end` are byte offsets into the source; `line` and `col` are
+/// 1-based and describe where the region starts (`col` counts characters).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, col: usize) -> Self {
+        Span {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        if other.start < self.start {
+            return other.to(self);
+        }
+        Span {
+            end: self.end.max(other.end),
+            ..self
+        }
+    }
+}
+
+/// The lines of `source`, each with the `\n`, `\r\n` or `\r` that ends it
+/// (empty for a last line without one)
+pub(crate) fn lines_with_breaks(source: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = source;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(['\n', '\r']).unwrap_or(rest.len());
+        let break_len = if rest[end..].starts_with("\r\n") {
+            2
+        } else {
+            usize::from(end < rest.len())
+        };
+        let (line, after) = rest.split_at(end);
+        let (line_break, after) = after.split_at(break_len);
+        rest = after;
+        Some((line, line_break))
+    })
+}
@@ -1,5 +1,7 @@
 //! Tokenizer for curly-brace/semicolon Python-like language
 
+use crate::span::{Span, lines_with_breaks};
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
     LBrace,
@@ -9,34 +11,75 @@ pub enum Token {
     Comment(String),
 }
 
-/// Tokenize source code into tokens: '{', '}', ';', text chunks, or comments
+/// A token together with where it appears in the source
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// Tokenize source code into tokens: '{', '}', ';', text chunks, or comments.
+///
+/// Braces inside `(...)` or `[...]` are always dict or set literals, so they
+/// stay part of the surrounding text; only braces outside any bracket become
+/// `LBrace`/`RBrace`, and the parser tells blocks from literals among those.
 pub fn tokenize(source: &str) -> Vec<Token> {
-    let src = source.replace("\r\n", "\n").replace("\r", "\n");
+    tokenize_spanned(source)
+        .into_iter()
+        .map(|t| t.token)
+        .collect()
+}
+
+/// Like `tokenize`, but keeps the source span of every token.
+///
+/// A `/*` comment that is never closed is still returned as a `Comment`
+/// token (without the closing `*/`) so later stages can report it.
+pub fn tokenize_spanned(source: &str) -> Vec<SpannedToken> {
     let mut tokens = Vec::new();
     let mut buf = String::new();
+    // Span where the text currently in `buf` starts
+    let mut buf_start = Span::default();
     let mut in_multiline_comment = false;
     let mut multiline_comment = String::new();
+    let mut multiline_start = Span::default();
+    // `(` and `[` still open, including braces opened inside them
+    let mut nesting = 0usize;
+    // The quote that closes the string being read; only a triple-quoted
+    // string carries on past the end of its line
+    let mut closing_quote: Option<&str> = None;
 
-    fn flush_buf(buf: &mut String, tokens: &mut Vec<Token>) {
+    fn flush_buf(buf: &mut String, start: Span, end: usize, tokens: &mut Vec<SpannedToken>) {
         if !buf.is_empty() {
-            tokens.push(Token::Text(buf.clone()));
+            tokens.push(SpannedToken {
+                token: Token::Text(buf.clone()),
+                span: Span { end, ..start },
+            });
             buf.clear();
         }
     }
 
-    let lines = src.lines();
-    for line in lines {
+    let mut line_start = 0;
+    for (line_idx, (line, line_break)) in lines_with_breaks(source).enumerate() {
+        let line_no = line_idx + 1;
         let mut comment_start = None;
-        let chars: Vec<char> = line.chars().collect();
+        let chars: Vec<(usize, char)> = line.char_indices().collect();
+        // Byte offset in the source of the char at index `i`
+        let offset = |i: usize| line_start + chars.get(i).map_or(line.len(), |c| c.0);
+        let span_at = |i: usize| Span::new(offset(i), offset(i + 1), line_no, i + 1);
         let mut i = 0;
-        let mut in_single = false;
-        let mut in_double = false;
         while i < chars.len() {
-            let ch = chars[i];
+            let ch = chars[i].1;
+            let next = chars.get(i + 1).map(|c| c.1);
             if in_multiline_comment {
-                if ch == '*' && i + 1 < chars.len() && chars[i + 1] == '/' {
+                if ch == '*' && next == Some('/') {
                     multiline_comment.push_str("*/");
-                    tokens.push(Token::Comment(multiline_comment.trim().to_string()));
+                    tokens.push(SpannedToken {
+                        token: Token::Comment(multiline_comment.trim().to_string()),
+                        span: Span {
+                            end: offset(i + 2),
+                            ..multiline_start
+                        },
+                    });
                     multiline_comment.clear();
                     in_multiline_comment = false;
                     i += 2;
@@ -47,62 +90,122 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                     continue;
                 }
             }
-            if ch == '\'' && !in_double {
-                in_single = !in_single;
-            } else if ch == '"' && !in_single {
-                in_double = !in_double;
+            if let Some(quote) = closing_quote {
+                // An escaped character, such as `\"`, never closes the string
+                let len = if ch == '\\' {
+                    2
+                } else if line[chars[i].0..].starts_with(quote) {
+                    closing_quote = None;
+                    quote.len()
+                } else {
+                    1
+                };
+                buf.extend(chars[i..chars.len().min(i + len)].iter().map(|c| c.1));
+                i += len;
+                continue;
             }
-            if !in_single && !in_double {
-                if ch == '/' && i + 1 < chars.len() && chars[i + 1] == '/' {
-                    comment_start = Some(i);
-                    break;
-                }
-                if ch == '#' {
-                    comment_start = Some(i);
-                    break;
-                }
-                if ch == '/' && i + 1 < chars.len() && chars[i + 1] == '*' {
-                    in_multiline_comment = true;
-                    multiline_comment.push_str("/*");
-                    i += 2;
-                    continue;
-                }
-                if ch == '{' {
-                    flush_buf(&mut buf, &mut tokens);
-                    tokens.push(Token::LBrace);
-                    i += 1;
-                    continue;
+            if ch == '"' || ch == '\'' {
+                let quote = ["\"\"\"", "'''", "\"", "'"]
+                    .into_iter()
+                    .find(|q| q.starts_with(ch) && line[chars[i].0..].starts_with(q))
+                    .expect("a quote opens a string");
+                if buf.is_empty() {
+                    buf_start = span_at(i);
                 }
-                if ch == '}' {
-                    flush_buf(&mut buf, &mut tokens);
-                    tokens.push(Token::RBrace);
-                    i += 1;
-                    continue;
-                }
-                if ch == ';' {
-                    flush_buf(&mut buf, &mut tokens);
-                    tokens.push(Token::Semicolon);
-                    i += 1;
-                    continue;
+                buf.push_str(quote);
+                closing_quote = Some(quote);
+                i += quote.len();
+                continue;
+            }
+            // `~/` is floor division, so its `/` never starts a comment
+            if ch == '~' && next == Some('/') {
+                if buf.is_empty() {
+                    buf_start = span_at(i);
                 }
+                buf.push_str("~/");
+                i += 2;
+                continue;
+            }
+            if ch == '/' && next == Some('/') {
+                comment_start = Some(i);
+                break;
+            }
+            if ch == '#' {
+                comment_start = Some(i);
+                break;
+            }
+            if ch == '/' && next == Some('*') {
+                flush_buf(&mut buf, buf_start, offset(i), &mut tokens);
+                in_multiline_comment = true;
+                multiline_comment.push_str("/*");
+                multiline_start = span_at(i);
+                i += 2;
+                continue;
+            }
+            let in_brackets = nesting > 0;
+            match ch {
+                '(' | '[' => nesting += 1,
+                '{' if in_brackets => nesting += 1,
+                ')' | ']' | '}' if in_brackets => nesting -= 1,
+                _ => {}
+            }
+            let punct = match ch {
+                '{' | '}' if in_brackets => None,
+                '{' => Some(Token::LBrace),
+                '}' => Some(Token::RBrace),
+                ';' => Some(Token::Semicolon),
+                _ => None,
+            };
+            if let Some(token) = punct {
+                flush_buf(&mut buf, buf_start, offset(i), &mut tokens);
+                tokens.push(SpannedToken {
+                    token,
+                    span: span_at(i),
+                });
+                i += 1;
+                continue;
+            }
+            if buf.is_empty() {
+                buf_start = span_at(i);
             }
             buf.push(ch);
             i += 1;
         }
-        flush_buf(&mut buf, &mut tokens);
+        if matches!(closing_quote, Some("\"" | "'")) {
+            // Left for the lexer to report as unterminated
+            closing_quote = None;
+        }
+        if closing_quote.is_some() {
+            buf.push_str(line_break);
+        } else {
+            flush_buf(&mut buf, buf_start, offset(i), &mut tokens);
+        }
         if let Some(idx) = comment_start {
-            let comment = &line[idx..];
-            tokens.push(Token::Comment(comment.trim().to_string()));
+            let comment = line[chars[idx].0..].trim_end();
+            tokens.push(SpannedToken {
+                token: Token::Comment(comment.to_string()),
+                span: Span::new(offset(idx), offset(idx) + comment.len(), line_no, idx + 1),
+            });
         }
         // If still in multiline comment, add newline
         if in_multiline_comment {
             multiline_comment.push('\n');
         }
+        line_start += line.len() + line_break.len();
+    }
+    flush_buf(&mut buf, buf_start, line_start, &mut tokens);
+    if in_multiline_comment {
+        tokens.push(SpannedToken {
+            token: Token::Comment(multiline_comment.trim().to_string()),
+            span: Span {
+                end: source.len(),
+                ..multiline_start
+            },
+        });
     }
-    flush_buf(&mut buf, &mut tokens);
     tokens
         .into_iter()
-        .filter(|t| match t {
+        .filter(|t| match &t.token {
             Token::Text(s) => !s.trim().is_empty(),
             Token::Comment(s) => !s.trim().is_empty(),
             _ => true,
@@ -128,4 +231,101 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_tokenize_spans() {
+        let src = "x = 1; // note\r\ny {";
+        let tokens = tokenize_spanned(src);
+        let spans: Vec<(usize, usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.span.start, t.span.end, t.span.line, t.span.col))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 5, 1, 1),
+                (5, 6, 1, 6),
+                (7, 14, 1, 8),
+                (16, 18, 2, 1),
+                (18, 19, 2, 3)
+            ]
+        );
+        assert_eq!(tokens[2].token, Token::Comment("// note".to_string()));
+    }
+
+    #[test]
+    fn test_tokenize_floor_div() {
+        assert_eq!(
+            tokenize("q = a ~/ b; q ~/= 2; // done"),
+            vec![
+                Token::Text("q = a ~/ b".to_string()),
+                Token::Semicolon,
+                Token::Text(" q ~/= 2".to_string()),
+                Token::Semicolon,
+                Token::Comment("// done".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_braces_in_brackets() {
+        assert_eq!(
+            tokenize("f({\"a\": {1}}, [{}]) { x = {}; }"),
+            vec![
+                Token::Text("f({\"a\": {1}}, [{}]) ".to_string()),
+                Token::LBrace,
+                Token::Text(" x = ".to_string()),
+                Token::LBrace,
+                Token::RBrace,
+                Token::Semicolon,
+                Token::RBrace,
+            ]
+        );
+        // `;` still splits inside parentheses, as in a C-style `for` header
+        assert_eq!(
+            tokenize("for (i = 0; i < n; i++) {}"),
+            vec![
+                Token::Text("for (i = 0".to_string()),
+                Token::Semicolon,
+                Token::Text(" i < n".to_string()),
+                Token::Semicolon,
+                Token::Text(" i++) ".to_string()),
+                Token::LBrace,
+                Token::RBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_escaped_quotes() {
+        // `;` and `#` after an escaped quote are still inside the string
+        assert_eq!(
+            tokenize(r#"print("say \"hi; there\""); x = '\'#1\'';"#),
+            vec![
+                Token::Text(r#"print("say \"hi; there\"")"#.to_string()),
+                Token::Semicolon,
+                Token::Text(r#" x = '\'#1\''"#.to_string()),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_triple_quoted_strings() {
+        let tokens = tokenize_spanned("s = \"\"\"a;\n# {b} \\\"\"\" '\n\"\"\"; t = 1;");
+        assert_eq!(
+            tokens.iter().map(|t| t.token.clone()).collect::<Vec<_>>(),
+            vec![
+                Token::Text("s = \"\"\"a;\n# {b} \\\"\"\" '\n\"\"\"".to_string()),
+                Token::Semicolon,
+                Token::Text(" t = 1".to_string()),
+                Token::Semicolon,
+            ]
+        );
+        assert_eq!(tokens[0].span, Span::new(0, 26, 1, 1));
+        assert_eq!(tokens[1].span, Span::new(26, 27, 3, 4));
+        let tokens = tokenize_spanned("s = '''a\r\nb'''; t");
+        assert_eq!(tokens[0].token, Token::Text("s = '''a\r\nb'''".to_string()));
+        assert_eq!(tokens[1].span, Span::new(14, 15, 2, 5));
+    }
 }
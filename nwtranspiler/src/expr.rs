@@ -0,0 +1,867 @@
+//! Printing NWPython expressions as Python source
+//!
+//! Parentheses are only added where Python's precedence rules need them;
+//! parentheses written in the NWPython source are kept as `Expr::Paren`.
+
+use crate::visit::{placeholders, walk, walk_body_exprs};
+use nwparser::ast::*;
+use nwparser::diagnostic::{Diagnostic, codes};
+use nwparser::span::Span;
+
+/// Binding strength of Python expressions, loosest first
+pub(crate) mod prec {
+    pub const NAMED: u8 = 0;
+    pub const TUPLE: u8 = 1;
+    pub const LAMBDA: u8 = 2;
+    pub const IF_EXP: u8 = 3;
+    pub const OR: u8 = 4;
+    pub const AND: u8 = 5;
+    pub const NOT: u8 = 6;
+    pub const COMPARE: u8 = 7;
+    pub const BIT_OR: u8 = 8;
+    pub const BIT_XOR: u8 = 9;
+    pub const BIT_AND: u8 = 10;
+    pub const SHIFT: u8 = 11;
+    pub const ARITH: u8 = 12;
+    pub const TERM: u8 = 13;
+    pub const UNARY: u8 = 14;
+    pub const POWER: u8 = 15;
+    pub const AWAIT: u8 = 16;
+    pub const PRIMARY: u8 = 17;
+    pub const ATOM: u8 = 18;
+}
+
+/// Side effects of `++`/`--` that are moved out of the expression being printed
+#[derive(Debug, Default)]
+pub(crate) struct Effects {
+    /// Statements to run before the statement containing the expression
+    pub before: Vec<String>,
+    /// Statements to run after it
+    pub after: Vec<String>,
+    /// Increments that could not be lowered and arrow functions that could
+    /// not be defined
+    pub errors: Vec<Diagnostic>,
+}
+
+pub(crate) struct ExprPrinter<'a> {
+    pub fx: &'a mut Effects,
+    /// Move `++`/`--` updates into `fx` instead of lowering them to `:=`;
+    /// only set when [`can_hoist`] said that keeps the meaning
+    hoist: bool,
+    /// Parameters of the innermost enclosing lambda, the only names a
+    /// `:=` inside it can update
+    lambda_params: Option<Vec<String>>,
+    /// Iteration variables of enclosing comprehensions, which `:=` cannot rebind
+    comp_targets: Vec<String>,
+    /// Block-bodied arrow functions of the statement being printed and the
+    /// names of the `def`s they were hoisted into
+    arrows: &'a [(Expr, String)],
+    /// Names bound by enclosing lambdas and comprehensions, which those
+    /// `def`s cannot see
+    scoped: Vec<String>,
+    /// The temporary that `??` and `?.` bind the value they test to
+    temp: &'a str,
+    /// Set while printing a comprehension's iterable, where `:=` is not allowed
+    in_comp_iter: bool,
+    /// The values of enclosing pipelines that a `_` in their stage stands
+    /// for, innermost last
+    piped: Vec<Expr>,
+}
+
+impl<'a> ExprPrinter<'a> {
+    pub fn new(fx: &'a mut Effects) -> Self {
+        ExprPrinter {
+            fx,
+            hoist: false,
+            lambda_params: None,
+            comp_targets: Vec::new(),
+            arrows: &[],
+            scoped: Vec::new(),
+            temp: "_v",
+            in_comp_iter: false,
+            piped: Vec::new(),
+        }
+    }
+
+    pub fn hoisting(mut self, hoist: bool) -> Self {
+        self.hoist = hoist;
+        self
+    }
+
+    pub fn arrows(mut self, arrows: &'a [(Expr, String)]) -> Self {
+        self.arrows = arrows;
+        self
+    }
+
+    pub fn temp(mut self, temp: &'a str) -> Self {
+        self.temp = temp;
+        self
+    }
+
+    /// Print `e`, parenthesizing it if it binds looser than `min`
+    pub fn expr(&mut self, e: &Expr, min: u8) -> String {
+        // The value a pipeline stage's `_` stands for is printed in the
+        // pipeline's own context
+        if matches!(e, Expr::Name(n) if n == "_")
+            && let Some(value) = self.piped.pop()
+        {
+            let s = self.expr(&value, min);
+            self.piped.push(value);
+            return s;
+        }
+        let s = self.raw(e);
+        if precedence(e) < min {
+            format!("({})", s)
+        } else {
+            s
+        }
+    }
+
+    fn raw(&mut self, e: &Expr) -> String {
+        match e {
+            Expr::Name(n) | Expr::Number(n) => n.clone(),
+            Expr::Str(parts) => parts.join(" "),
+            Expr::Ellipsis => "...".to_string(),
+            Expr::Paren(inner) => match inner.as_ref() {
+                Expr::Tuple(_) => self.raw(inner),
+                Expr::Comprehension {
+                    kind: ComprehensionKind::Generator,
+                    ..
+                } => self.raw(inner),
+                _ => format!("({})", self.expr(inner, prec::NAMED)),
+            },
+            Expr::Tuple(items) => match items.len() {
+                0 => "()".to_string(),
+                1 => format!("({},)", self.expr(&items[0], prec::LAMBDA)),
+                _ => format!("({})", self.list(items)),
+            },
+            Expr::List(items) => format!("[{}]", self.list(items)),
+            Expr::Set(items) => format!("{{{}}}", self.list(items)),
+            Expr::Dict(items) => {
+                let parts: Vec<String> = items
+                    .iter()
+                    .map(|item| match item {
+                        DictItem::Pair(k, v) => format!(
+                            "{}: {}",
+                            self.expr(k, prec::LAMBDA),
+                            self.expr(v, prec::LAMBDA)
+                        ),
+                        DictItem::Unpack(m) => format!("**{}", self.expr(m, prec::BIT_OR)),
+                    })
+                    .collect();
+                format!("{{{}}}", parts.join(", "))
+            }
+            Expr::Attribute { .. } | Expr::Subscript { .. } | Expr::Call { .. }
+                if e.is_optional_chain() =>
+            {
+                self.optional_chain(e)
+            }
+            // Only ever the start of an optional chain, printed above
+            Expr::Optional { value, .. } => self.raw(value),
+            Expr::Attribute { value, attr } => {
+                format!("{}.{}", self.expr(value, prec::PRIMARY), attr)
+            }
+            Expr::Subscript { value, index } => {
+                let value = self.expr(value, prec::PRIMARY);
+                format!("{}[{}]", value, self.subscript(index))
+            }
+            Expr::Slice { .. } => self.subscript(e),
+            Expr::Range {
+                start,
+                stop,
+                inclusive,
+                step,
+            } => {
+                let stop = range_stop(stop, *inclusive, step.as_deref());
+                let mut args = Vec::new();
+                if step.is_some() || start.as_int() != Some(0) {
+                    args.push(Arg::Positional(start.as_ref().clone()));
+                }
+                args.push(Arg::Positional(stop));
+                args.extend(step.iter().map(|s| Arg::Positional(s.as_ref().clone())));
+                self.raw(&Expr::Call {
+                    func: Box::new(Expr::Name("range".to_string())),
+                    args,
+                })
+            }
+            Expr::Call { func, args } => {
+                let func = self.expr(func, prec::PRIMARY);
+                let args: Vec<String> = if let [
+                    Arg::Positional(
+                        g @ Expr::Comprehension {
+                            kind: ComprehensionKind::Generator,
+                            ..
+                        },
+                    ),
+                ] = args.as_slice()
+                {
+                    // A lone generator argument needs no extra parentheses
+                    vec![self.comprehension_body(g)]
+                } else {
+                    args.iter().map(|a| self.arg(a)).collect()
+                };
+                format!("{}({})", func, args.join(", "))
+            }
+            Expr::UnaryOp { op, operand } => match op {
+                UnaryOp::Not => format!("not {}", self.expr(operand, prec::NOT)),
+                UnaryOp::Neg => format!("-{}", self.expr(operand, prec::UNARY)),
+                UnaryOp::Pos => format!("+{}", self.expr(operand, prec::UNARY)),
+                UnaryOp::Invert => format!("~{}", self.expr(operand, prec::UNARY)),
+            },
+            Expr::BinOp { left, op, right } => {
+                let p = binop_precedence(*op);
+                if *op == BinOp::Pow {
+                    // Right-associative, and binds tighter than a unary operator on its left
+                    format!(
+                        "{} ** {}",
+                        self.expr(left, prec::POWER + 1),
+                        self.expr(right, prec::UNARY)
+                    )
+                } else {
+                    format!(
+                        "{} {} {}",
+                        self.expr(left, p),
+                        op.as_str(),
+                        self.expr(right, p + 1)
+                    )
+                }
+            }
+            Expr::BoolOp { left, op, right } => {
+                let (p, word) = match op {
+                    BoolOp::And => (prec::AND, "and"),
+                    BoolOp::Or => (prec::OR, "or"),
+                };
+                format!(
+                    "{} {} {}",
+                    self.expr(left, p),
+                    word,
+                    self.expr(right, p + 1)
+                )
+            }
+            Expr::Compare { left, ops } => {
+                let mut s = self.expr(left, prec::BIT_OR);
+                for (op, right) in ops {
+                    s.push_str(&format!(
+                        " {} {}",
+                        op.as_str(),
+                        self.expr(right, prec::BIT_OR)
+                    ));
+                }
+                s
+            }
+            Expr::Pipe { value, stage, span } => {
+                let uses = placeholders(stage);
+                if uses.is_empty() {
+                    return self.raw(&Expr::Call {
+                        func: stage.clone(),
+                        args: vec![Arg::Positional(value.as_ref().clone())],
+                    });
+                }
+                self.piped.push(value.as_ref().clone());
+                let s = self.expr(stage, prec::NAMED);
+                self.piped.pop();
+                if uses.len() > 1 || uses[0] {
+                    let problem = if uses.len() > 1 {
+                        format!("uses `_` {} times", uses.len())
+                    } else {
+                        "uses `_` where it may not run exactly once".to_string()
+                    };
+                    let stage = source(stage);
+                    self.fx.errors.push(
+                        Diagnostic::error(
+                            codes::INVALID_PIPELINE,
+                            format!("the pipeline stage `{}` {}", stage, problem),
+                        )
+                        .with_primary(*span, "in this pipeline")
+                        .with_note(
+                            "`_` stands for the piped value, which is evaluated once, so it may \
+                             appear only once and not inside `and`/`or`, `??`, the branches of a \
+                             conditional or the body of a comprehension",
+                        ),
+                    );
+                }
+                s
+            }
+            Expr::Coalesce { left, right, span } => {
+                // `left if left is not None else right`, evaluating `left` once
+                let (test, body) = self.bind_temp(left, *span);
+                self.raw(&Expr::IfExp {
+                    test: Box::new(Expr::Compare {
+                        left: Box::new(test),
+                        ops: vec![(CmpOp::IsNot, Expr::Name("None".to_string()))],
+                    }),
+                    body: Box::new(body),
+                    orelse: right.clone(),
+                })
+            }
+            Expr::IfExp { test, body, orelse } => {
+                let body = self.expr(body, prec::OR);
+                let test = self.expr(test, prec::OR);
+                let orelse = self.expr(orelse, prec::IF_EXP);
+                format!("{} if {} else {}", body, test, orelse)
+            }
+            Expr::Lambda { params, body } => {
+                let names = params.iter().map(|p| p.name.clone()).collect();
+                let depth = self.scoped.len();
+                self.scoped.extend(params.iter().map(|p| p.name.clone()));
+                let params = self.params(params);
+                let outer = self.lambda_params.replace(names);
+                let outer_targets = std::mem::take(&mut self.comp_targets);
+                // A `_` in the body is the lambda's own, not a pipeline's
+                let outer_piped = std::mem::take(&mut self.piped);
+                let body = self.expr(body, prec::LAMBDA);
+                self.lambda_params = outer;
+                self.comp_targets = outer_targets;
+                self.piped = outer_piped;
+                self.scoped.truncate(depth);
+                if params.is_empty() {
+                    format!("lambda: {}", body)
+                } else {
+                    format!("lambda {}: {}", params, body)
+                }
+            }
+            Expr::Function { params, body, span } => self.function(e, params, body, *span),
+            Expr::Comprehension { kind, .. } => {
+                let body = self.comprehension_body(e);
+                match kind {
+                    ComprehensionKind::List => format!("[{}]", body),
+                    ComprehensionKind::Set | ComprehensionKind::Dict => format!("{{{}}}", body),
+                    ComprehensionKind::Generator => format!("({})", body),
+                }
+            }
+            Expr::Starred(inner) => format!("*{}", self.expr(inner, prec::BIT_OR)),
+            Expr::NamedExpr { target, value } => {
+                format!("{} := {}", target, self.expr(value, prec::LAMBDA))
+            }
+            Expr::Yield(None) => "yield".to_string(),
+            Expr::Yield(Some(value)) => format!("yield {}", self.expr(value, prec::TUPLE)),
+            Expr::YieldFrom(value) => format!("yield from {}", self.expr(value, prec::LAMBDA)),
+            Expr::Await(value) => format!("await {}", self.expr(value, prec::PRIMARY)),
+            Expr::IncDec {
+                op,
+                prefix,
+                target,
+                span,
+            } => self.incdec(*op, *prefix, target, *span),
+        }
+    }
+
+    /// `a?.b.c` → `None if a is None else a.b.c`. A later `?` in the chain
+    /// nests another conditional in the `else` branch.
+    fn optional_chain(&mut self, e: &Expr) -> String {
+        fn base(e: &Expr) -> Option<(&Expr, Span)> {
+            match e {
+                Expr::Optional { value, span } => base(value).or(Some((value, *span))),
+                Expr::Attribute { value, .. } | Expr::Subscript { value, .. } => base(value),
+                Expr::Call { func, .. } => base(func),
+                _ => None,
+            }
+        }
+        // `e` with its first `value?` replaced by `bound`
+        fn rest(e: &Expr, bound: &Expr) -> Expr {
+            let inner = |e: &Expr| Box::new(rest(e, bound));
+            match e {
+                Expr::Optional { value, span } if value.is_optional_chain() => Expr::Optional {
+                    value: inner(value),
+                    span: *span,
+                },
+                Expr::Optional { .. } => bound.clone(),
+                Expr::Attribute { value, attr } => Expr::Attribute {
+                    value: inner(value),
+                    attr: attr.clone(),
+                },
+                Expr::Subscript { value, index } => Expr::Subscript {
+                    value: inner(value),
+                    index: index.clone(),
+                },
+                Expr::Call { func, args } => Expr::Call {
+                    func: inner(func),
+                    args: args.clone(),
+                },
+                other => other.clone(),
+            }
+        }
+        let (value, span) = base(e).expect("an optional chain starts with `value?`");
+        let (test, bound) = self.bind_temp(value, span);
+        let none = Expr::Name("None".to_string());
+        self.raw(&Expr::IfExp {
+            test: Box::new(Expr::Compare {
+                left: Box::new(test),
+                ops: vec![(CmpOp::Is, none.clone())],
+            }),
+            body: Box::new(none),
+            orelse: Box::new(rest(e, &bound)),
+        })
+    }
+
+    /// The expression to test `value` with and the one to use it through
+    /// afterwards: `value` itself when it is a name, else `(temp := value)`
+    /// and the temporary, so that it is only evaluated once; `span` is where
+    /// `value` is tested, for the error when no temporary is allowed
+    fn bind_temp(&mut self, value: &Expr, span: Span) -> (Expr, Expr) {
+        if let Expr::Name(_) = value {
+            return (value.clone(), value.clone());
+        }
+        if self.in_comp_iter {
+            let value = source(value);
+            self.fx.errors.push(
+                Diagnostic::error(
+                    codes::INVALID_OPTIONAL,
+                    format!(
+                        "`{}` needs a temporary here, which Python does not allow in a \
+                         comprehension's iterable; assign it to a variable first",
+                        value
+                    ),
+                )
+                .with_primary(span, "tested for `None` in a comprehension's iterable"),
+            );
+        }
+        let test = Expr::NamedExpr {
+            target: self.temp.to_string(),
+            value: Box::new(value.clone()),
+        };
+        (test, Expr::Name(self.temp.to_string()))
+    }
+
+    /// Comma separated items without surrounding brackets
+    pub fn list(&mut self, items: &[Expr]) -> String {
+        items
+            .iter()
+            .map(|i| self.expr(i, prec::LAMBDA))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// An expression list where a bare tuple needs no parentheses, as after `return` or `=`
+    pub fn exprlist(&mut self, e: &Expr) -> String {
+        match e {
+            Expr::Tuple(items) if items.len() > 1 => self.list(items),
+            Expr::Tuple(items) if items.len() == 1 => {
+                format!("{},", self.expr(&items[0], prec::LAMBDA))
+            }
+            _ => self.expr(e, prec::TUPLE),
+        }
+    }
+
+    fn subscript(&mut self, index: &Expr) -> String {
+        match index {
+            Expr::Slice { lower, upper, step } => {
+                let mut s = String::new();
+                if let Some(l) = lower {
+                    s.push_str(&self.expr(l, prec::LAMBDA));
+                }
+                s.push(':');
+                if let Some(u) = upper {
+                    s.push_str(&self.expr(u, prec::LAMBDA));
+                }
+                if let Some(st) = step {
+                    s.push(':');
+                    s.push_str(&self.expr(st, prec::LAMBDA));
+                }
+                s
+            }
+            Expr::Range {
+                start,
+                stop,
+                inclusive,
+                step,
+            } => {
+                // `xs[1..=-1]` runs to the end, which `xs[1:0]` would not
+                let down = step
+                    .as_deref()
+                    .and_then(Expr::as_int)
+                    .is_some_and(|s| s < 0);
+                let to_end = *inclusive && stop.as_int() == Some(if down { 0 } else { -1 });
+                let slice = Expr::Slice {
+                    lower: Some(start.clone()),
+                    upper: (!to_end)
+                        .then(|| Box::new(range_stop(stop, *inclusive, step.as_deref()))),
+                    step: step.clone(),
+                };
+                self.subscript(&slice)
+            }
+            Expr::Tuple(items) if !items.is_empty() => {
+                let parts: Vec<String> = items.iter().map(|i| self.subscript(i)).collect();
+                if parts.len() == 1 {
+                    format!("{},", parts[0])
+                } else {
+                    parts.join(", ")
+                }
+            }
+            other => self.expr(other, prec::LAMBDA),
+        }
+    }
+
+    /// Call arguments without the surrounding parentheses
+    pub fn args(&mut self, args: &[Arg]) -> String {
+        args.iter()
+            .map(|a| self.arg(a))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn arg(&mut self, arg: &Arg) -> String {
+        match arg {
+            Arg::Positional(e) => self.expr(e, prec::LAMBDA),
+            Arg::Keyword(name, e) => format!("{}={}", name, self.expr(e, prec::LAMBDA)),
+            Arg::Star(e) => format!("*{}", self.expr(e, prec::BIT_OR)),
+            Arg::DoubleStar(e) => format!("**{}", self.expr(e, prec::BIT_OR)),
+        }
+    }
+
+    /// Comprehension contents without the surrounding brackets
+    fn comprehension_body(&mut self, e: &Expr) -> String {
+        let Expr::Comprehension {
+            elt,
+            value,
+            generators,
+            ..
+        } = e
+        else {
+            return self.expr(e, prec::LAMBDA);
+        };
+        let depth = self.comp_targets.len();
+        let scoped = self.scoped.len();
+        for g in generators {
+            walk(&g.target, false, &mut |e, _| {
+                if let Expr::Name(n) = e {
+                    self.comp_targets.push(n.clone());
+                    self.scoped.push(n.clone());
+                }
+            });
+        }
+        let mut s = self.expr(elt, prec::LAMBDA);
+        if let Some(v) = value {
+            s.push_str(": ");
+            s.push_str(&self.expr(v, prec::LAMBDA));
+        }
+        for g in generators {
+            let target = self.exprlist(&g.target);
+            let outer = std::mem::replace(&mut self.in_comp_iter, true);
+            let iter = self.expr(&g.iter, prec::OR);
+            self.in_comp_iter = outer;
+            let kw = if g.is_async { "async for" } else { "for" };
+            s.push_str(&format!(" {} {} in {}", kw, target, iter));
+            for cond in &g.ifs {
+                let cond = self.expr(cond, prec::OR);
+                s.push_str(&format!(" if {}", cond));
+            }
+        }
+        self.comp_targets.truncate(depth);
+        self.scoped.truncate(scoped);
+        s
+    }
+
+    /// A block-bodied arrow function, printed as the name of the `def` it
+    /// was hoisted into before the statement
+    fn function(&mut self, e: &Expr, params: &[Param], body: &[Stmt], span: Span) -> String {
+        let mut unseen = Vec::new();
+        let mut check = |e: &Expr, _| {
+            if let Expr::Name(n) = e
+                && self.scoped.contains(n)
+                && !params.iter().any(|p| &p.name == n)
+                && !unseen.contains(n)
+            {
+                unseen.push(n.clone());
+            }
+        };
+        for default in params.iter().filter_map(|p| p.default.as_ref()) {
+            walk(default, false, &mut check);
+        }
+        walk_body_exprs(body, &mut check);
+        if let Some(name) = unseen.first() {
+            self.fx.errors.push(
+                Diagnostic::error(
+                    codes::UNSUPPORTED_ARROW,
+                    format!(
+                        "this arrow function uses `{}`, which only exists inside the enclosing \
+                         lambda or comprehension; a block-bodied arrow function is defined \
+                         before the statement that uses it",
+                        name
+                    ),
+                )
+                .with_primary(span, "hoisted out of the lambda or comprehension"),
+            );
+        }
+        match self.arrows.iter().find(|(arrow, _)| arrow == e) {
+            Some((_, name)) => name.clone(),
+            None => {
+                self.fx.errors.push(
+                    Diagnostic::error(
+                        codes::UNSUPPORTED_ARROW,
+                        "a block-bodied arrow function cannot be used here",
+                    )
+                    .with_primary(span, "no statement to define it before"),
+                );
+                "None".to_string()
+            }
+        }
+    }
+
+    /// Parameter list of a `def` or `lambda`
+    pub fn params(&mut self, params: &[Param]) -> String {
+        params
+            .iter()
+            .map(|p| {
+                let mut s = match p.kind {
+                    ParamKind::Normal => p.name.clone(),
+                    ParamKind::VarArgs => format!("*{}", p.name),
+                    ParamKind::KwArgs => format!("**{}", p.name),
+                    ParamKind::PosOnlyMarker => "/".to_string(),
+                };
+                if let Some(ann) = &p.annotation {
+                    s.push_str(": ");
+                    s.push_str(&self.expr(ann, prec::LAMBDA));
+                }
+                if let Some(default) = &p.default {
+                    let default = self.expr(default, prec::LAMBDA);
+                    if p.annotation.is_some() {
+                        s.push_str(&format!(" = {}", default));
+                    } else {
+                        s.push_str(&format!("={}", default));
+                    }
+                }
+                s
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// A `case` pattern; `|` and `as` patterns inside another one are
+    /// parenthesized
+    pub fn pattern(&mut self, p: &Pattern, nested: bool) -> String {
+        let s = match p {
+            Pattern::Wildcard => "_".to_string(),
+            Pattern::Capture(name) => name.clone(),
+            Pattern::Value(value) => self.expr(value, prec::ARITH),
+            Pattern::Sequence(items) => {
+                let items: Vec<String> = items.iter().map(|p| self.pattern(p, false)).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Pattern::Star(name) => format!("*{}", name.as_deref().unwrap_or("_")),
+            Pattern::Mapping { items, rest } => {
+                let mut parts: Vec<String> = items
+                    .iter()
+                    .map(|(k, v)| {
+                        format!("{}: {}", self.expr(k, prec::ARITH), self.pattern(v, false))
+                    })
+                    .collect();
+                parts.extend(rest.iter().map(|rest| format!("**{}", rest)));
+                format!("{{{}}}", parts.join(", "))
+            }
+            Pattern::Class { cls, args, kwargs } => {
+                let mut parts: Vec<String> = args.iter().map(|p| self.pattern(p, false)).collect();
+                parts.extend(
+                    kwargs
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, self.pattern(v, false))),
+                );
+                format!("{}({})", self.expr(cls, prec::PRIMARY), parts.join(", "))
+            }
+            Pattern::Or(alts) => {
+                let alts: Vec<String> = alts.iter().map(|p| self.pattern(p, true)).collect();
+                alts.join(" | ")
+            }
+            Pattern::As { pattern, name } => format!("{} as {}", self.pattern(pattern, true), name),
+        };
+        if nested && matches!(p, Pattern::Or(_) | Pattern::As { .. }) {
+            format!("({})", s)
+        } else {
+            s
+        }
+    }
+
+    /// `++`/`--` inside an expression.
+    ///
+    /// When hoisting, the update is moved before (prefix) or after (postfix)
+    /// the enclosing statement and the target read in place. Otherwise a
+    /// variable is updated with `:=`: `++x` becomes `(x := x + 1)` and `x++`
+    /// becomes `(x, (x := x + 1))[0]`, which yields the old value.
+    fn incdec(&mut self, op: IncDecOp, prefix: bool, target: &Expr, span: Span) -> String {
+        let mut target = target;
+        while let Expr::Paren(inner) = target {
+            target = inner;
+        }
+        let (sign, aug) = match op {
+            IncDecOp::Inc => ("+", "+="),
+            IncDecOp::Dec => ("-", "-="),
+        };
+        if self.hoist {
+            let target = self.expr(target, prec::PRIMARY);
+            let update = format!("{} {} 1", target, aug);
+            if prefix {
+                self.fx.before.push(update);
+            } else {
+                self.fx.after.push(update);
+            }
+            return target;
+        }
+        let Expr::Name(name) = target else {
+            let target = self.expr(target, prec::PRIMARY);
+            self.fx.errors.push(
+                Diagnostic::error(
+                    codes::UNSUPPORTED_INCREMENT,
+                    format!(
+                        "cannot update `{}` inside this expression; an attribute or subscript \
+                         can only be incremented as the one update of a simple statement",
+                        target
+                    ),
+                )
+                .with_primary(span, "not the one update of a simple statement"),
+            );
+            return target;
+        };
+        if let Some(params) = &self.lambda_params
+            && !params.contains(name)
+        {
+            self.fx.errors.push(
+                Diagnostic::error(
+                    codes::UNSUPPORTED_INCREMENT,
+                    format!(
+                        "cannot update `{}` inside a lambda that does not take it as a parameter",
+                        name
+                    ),
+                )
+                .with_primary(span, "updated inside a lambda"),
+            );
+        } else if self.comp_targets.contains(name) {
+            self.fx.errors.push(
+                Diagnostic::error(
+                    codes::UNSUPPORTED_INCREMENT,
+                    format!(
+                        "cannot update the comprehension variable `{}` inside the comprehension",
+                        name
+                    ),
+                )
+                .with_primary(span, "updated inside the comprehension"),
+            );
+        }
+        let update = format!("({} := {} {} 1)", name, name, sign);
+        if prefix {
+            update
+        } else {
+            format!("({}, {})[0]", name, update)
+        }
+    }
+}
+
+/// `e` as printed on its own, for error messages; printing it there again
+/// would repeat any `++`/`--` updates it moves out
+fn source(e: &Expr) -> String {
+    ExprPrinter::new(&mut Effects::default()).expr(e, prec::NAMED)
+}
+
+/// Whether the `++`/`--` in a statement built from `exprs` can be moved out
+/// of it as a separate `+= 1` / `-= 1` without changing what it computes.
+///
+/// That needs exactly one update, always evaluated, on a side-effect free
+/// target whose variables are not read anywhere else in the statement.
+/// Postfix updates run after the statement, so `allow_postfix` is false for
+/// statements like `return` where nothing runs after.
+pub(crate) fn can_hoist(exprs: &[&Expr], allow_postfix: bool) -> bool {
+    let mut updates = Vec::new();
+    let mut names = Vec::new();
+    for e in exprs {
+        walk(e, false, &mut |e, conditional| match e {
+            Expr::IncDec { prefix, target, .. } => {
+                updates.push((conditional, *prefix, target.as_ref()))
+            }
+            Expr::Name(n) => names.push(n.as_str()),
+            _ => {}
+        });
+    }
+    let [(conditional, prefix, target)] = updates.as_slice() else {
+        return false;
+    };
+    if *conditional || !(*prefix || allow_postfix) || !is_pure(target) {
+        return false;
+    }
+    let mut own = Vec::new();
+    walk(target, false, &mut |e, _| {
+        if let Expr::Name(n) = e {
+            own.push(n.as_str())
+        }
+    });
+    let count = |list: &[&str], name: &str| list.iter().filter(|n| **n == name).count();
+    own.iter().all(|n| count(&names, n) == count(&own, n))
+}
+
+/// Names, literals and attribute/subscript chains over them, which can be
+/// evaluated again without side effects
+fn is_pure(e: &Expr) -> bool {
+    match e {
+        Expr::Name(_) | Expr::Number(_) | Expr::Str(_) => true,
+        Expr::Paren(inner) => is_pure(inner),
+        Expr::Attribute { value, .. } => is_pure(value),
+        Expr::Subscript { value, index } => is_pure(value) && is_pure(index),
+        _ => false,
+    }
+}
+
+/// The exclusive stop of a range, one past `stop` in the direction of
+/// `step` when the range is inclusive
+fn range_stop(stop: &Expr, inclusive: bool, step: Option<&Expr>) -> Expr {
+    if !inclusive {
+        return stop.clone();
+    }
+    let down = step.and_then(Expr::as_int).is_some_and(|s| s < 0);
+    offset(stop, if down { -1 } else { 1 })
+}
+
+/// `stop + delta`, folded when `stop` is an integer literal
+pub(crate) fn offset(stop: &Expr, delta: i64) -> Expr {
+    if let Some(n) = stop.as_int() {
+        return Expr::Number((n + delta).to_string());
+    }
+    let op = if delta > 0 { BinOp::Add } else { BinOp::Sub };
+    Expr::BinOp {
+        left: Box::new(stop.clone()),
+        op,
+        right: Box::new(Expr::Number(delta.abs().to_string())),
+    }
+}
+
+fn binop_precedence(op: BinOp) -> u8 {
+    match op {
+        BinOp::BitOr => prec::BIT_OR,
+        BinOp::BitXor => prec::BIT_XOR,
+        BinOp::BitAnd => prec::BIT_AND,
+        BinOp::LShift | BinOp::RShift => prec::SHIFT,
+        BinOp::Add | BinOp::Sub => prec::ARITH,
+        BinOp::Mul | BinOp::Div | BinOp::FloorDiv | BinOp::Mod | BinOp::MatMul => prec::TERM,
+        BinOp::Pow => prec::POWER,
+    }
+}
+
+/// How tightly the printed form of `e` binds
+pub(crate) fn precedence(e: &Expr) -> u8 {
+    match e {
+        Expr::NamedExpr { .. } | Expr::Yield(_) | Expr::YieldFrom(_) => prec::NAMED,
+        Expr::Lambda { .. } => prec::LAMBDA,
+        Expr::IfExp { .. } | Expr::Coalesce { .. } => prec::IF_EXP,
+        Expr::Pipe { value, stage, .. } => match placeholders(stage).len() {
+            0 => prec::PRIMARY,
+            _ if matches!(stage.as_ref(), Expr::Name(n) if n == "_") => precedence(value),
+            _ => precedence(stage),
+        },
+        _ if e.is_optional_chain() => prec::IF_EXP,
+        Expr::BoolOp { op: BoolOp::Or, .. } => prec::OR,
+        Expr::BoolOp {
+            op: BoolOp::And, ..
+        } => prec::AND,
+        Expr::UnaryOp {
+            op: UnaryOp::Not, ..
+        } => prec::NOT,
+        Expr::Compare { .. } => prec::COMPARE,
+        Expr::BinOp { op, .. } => binop_precedence(*op),
+        Expr::UnaryOp { .. } => prec::UNARY,
+        Expr::Starred(_) => prec::BIT_OR,
+        Expr::Attribute { .. }
+        | Expr::Subscript { .. }
+        | Expr::Call { .. }
+        | Expr::Range { .. } => prec::PRIMARY,
+        Expr::IncDec { .. } => prec::PRIMARY,
+        Expr::Await(_) => prec::AWAIT,
+        _ => prec::ATOM,
+    }
+}
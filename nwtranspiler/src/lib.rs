@@ -1,3 +1,7 @@
+mod expr;
+pub mod options;
 pub mod transpiler;
-pub use transpiler::transpile;
+mod visit;
+pub use options::{CommentStyle, Indent, PythonVersion, Strictness, TranspileOptions};
+pub use transpiler::{transpile, transpile_lossy, transpile_with};
 pub mod reverse_transpiler;
@@ -0,0 +1,299 @@
+//! Settings that change what Python the transpiler writes
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Settings for [`transpile_with`](crate::transpile_with)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TranspileOptions {
+    /// What one level of indentation is written as
+    pub indent: Indent,
+    /// How source comments are written
+    pub comments: CommentStyle,
+    /// The Python version the generated code has to run on
+    pub target: PythonVersion,
+    /// Return the value of the last statement of a function body when it is an
+    /// expression without a `;`, as in `def sq(x) { x * x }`
+    pub implicit_return: bool,
+    /// Whether errors fail the whole file
+    pub strictness: Strictness,
+    /// Start the output with a comment saying it was generated
+    pub banner: bool,
+}
+
+/// The option names accepted by [`TranspileOptions::set`]
+pub const OPTION_NAMES: &[&str] = &[
+    "indent",
+    "comments",
+    "target-version",
+    "implicit-return",
+    "strictness",
+    "banner",
+];
+
+impl TranspileOptions {
+    /// Set the option called `name` (one of [`OPTION_NAMES`]) from its text
+    /// form, as written in a config file or on the command line
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "indent" => self.indent = value.parse()?,
+            "comments" => self.comments = value.parse()?,
+            "target-version" => self.target = value.parse()?,
+            "implicit-return" => self.implicit_return = parse_bool(name, value)?,
+            "strictness" => self.strictness = value.parse()?,
+            "banner" => self.banner = parse_bool(name, value)?,
+            _ => return Err(format!("unknown option `{}`", name)),
+        }
+        Ok(())
+    }
+
+    /// Read options from a config file made of `name = value` lines, e.g.
+    ///
+    /// ```toml
+    /// # nwpython.toml
+    /// indent = "tab"
+    /// target-version = "3.9"
+    /// implicit-return = true
+    /// ```
+    ///
+    /// Values may be quoted; `#` starts a comment. Options that are not
+    /// mentioned keep their defaults.
+    pub fn from_config(text: &str) -> Result<Self, String> {
+        let mut options = TranspileOptions::default();
+        for (idx, line) in text.lines().enumerate() {
+            // Drop a `#` comment, unless the `#` is inside a quoted value
+            let mut quoted = false;
+            let end = line
+                .char_indices()
+                .find(|&(_, c)| {
+                    quoted ^= c == '"';
+                    c == '#' && !quoted
+                })
+                .map_or(line.len(), |(pos, _)| pos);
+            let line = line[..end].trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected `name = value`", idx + 1))?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            options
+                .set(name.trim(), value)
+                .map_err(|e| format!("line {}: {}", idx + 1, e))?;
+        }
+        Ok(options)
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!(
+            "invalid value `{}` for `{}`, expected `true` or `false`",
+            value, name
+        )),
+    }
+}
+
+/// One level of indentation in the generated code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    Tab,
+}
+
+impl Indent {
+    /// The text of one level
+    pub fn unit(self) -> String {
+        match self {
+            Indent::Spaces(n) => " ".repeat(n),
+            Indent::Tab => "\t".to_string(),
+        }
+    }
+}
+
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(4)
+    }
+}
+
+impl FromStr for Indent {
+    type Err = String;
+
+    /// Parse a number of spaces such as `2`, or `tab`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "tab" => Ok(Indent::Tab),
+            n => match n.parse() {
+                Ok(n) if n > 0 => Ok(Indent::Spaces(n)),
+                _ => Err(format!(
+                    "invalid indent `{}`, expected a number of spaces or `tab`",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// How comments from the source appear in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommentStyle {
+    /// `//` and `#` comments become `#` comments and `/* */` comments become
+    /// `"""..."""` string literals
+    #[default]
+    Docstring,
+    /// Every comment becomes `#` comments, one per line
+    Hash,
+    /// Comments are left out
+    Strip,
+}
+
+impl FromStr for CommentStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "docstring" => Ok(CommentStyle::Docstring),
+            "hash" => Ok(CommentStyle::Hash),
+            "strip" => Ok(CommentStyle::Strip),
+            _ => Err(format!(
+                "invalid comment style `{}`, expected `docstring`, `hash` or `strip`",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to a file with errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// Any error fails the whole file
+    #[default]
+    Strict,
+    /// Whatever parses is converted, and each error becomes a `# ERROR:`
+    /// comment at the top of the output
+    Lossy,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "strict" => Ok(Strictness::Strict),
+            "lossy" => Ok(Strictness::Lossy),
+            _ => Err(format!(
+                "invalid strictness `{}`, expected `strict` or `lossy`",
+                s
+            )),
+        }
+    }
+}
+
+/// The Python version the generated code has to run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PythonVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl PythonVersion {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        PythonVersion { major, minor }
+    }
+
+    /// Whether `match` statements are available
+    pub fn has_match(self) -> bool {
+        self >= PythonVersion::new(3, 10)
+    }
+}
+
+impl Default for PythonVersion {
+    fn default() -> Self {
+        PythonVersion::new(3, 10)
+    }
+}
+
+impl FromStr for PythonVersion {
+    type Err = String;
+
+    /// Parse a version such as `3.9`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid Python version `{}`, expected e.g. `3.10`", s);
+        let (major, minor) = s.trim().split_once('.').ok_or_else(invalid)?;
+        let major = major.parse().map_err(|_| invalid())?;
+        let minor = minor.parse().map_err(|_| invalid())?;
+        if major != 3 {
+            return Err(format!(
+                "unsupported Python version `{}`, only 3.x is supported",
+                s
+            ));
+        }
+        Ok(PythonVersion::new(major, minor))
+    }
+}
+
+impl fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_python_version() {
+        assert_eq!("3.9".parse(), Ok(PythonVersion::new(3, 9)));
+        assert!("3.10".parse::<PythonVersion>().unwrap().has_match());
+        assert!(!"3.9".parse::<PythonVersion>().unwrap().has_match());
+        assert!("3".parse::<PythonVersion>().is_err());
+        assert!("2.7".parse::<PythonVersion>().is_err());
+        assert_eq!(PythonVersion::default().to_string(), "3.10");
+    }
+
+    #[test]
+    fn test_options_from_config() {
+        let config = "# nwpython.toml\nindent = \"tab\"\ncomments = \"hash\" # no docstrings\n\ntarget-version = \"3.9\"\nimplicit-return = true\nbanner = true\n";
+        let options = TranspileOptions::from_config(config).unwrap();
+        assert_eq!(
+            options,
+            TranspileOptions {
+                indent: Indent::Tab,
+                comments: CommentStyle::Hash,
+                target: PythonVersion::new(3, 9),
+                implicit_return: true,
+                strictness: Strictness::Strict,
+                banner: true,
+            }
+        );
+        assert_eq!(
+            TranspileOptions::from_config("indent = 2").unwrap().indent,
+            Indent::Spaces(2)
+        );
+        assert_eq!(
+            TranspileOptions::from_config("indent = 0"),
+            Err("line 1: invalid indent `0`, expected a number of spaces or `tab`".to_string())
+        );
+        assert_eq!(
+            TranspileOptions::from_config("\nstyle = \"k&r\""),
+            Err("line 2: unknown option `style`".to_string())
+        );
+        assert_eq!(
+            TranspileOptions::from_config("banner"),
+            Err("line 1: expected `name = value`".to_string())
+        );
+        assert_eq!(
+            TranspileOptions::from_config("banner = yes"),
+            Err("line 1: invalid value `yes` for `banner`, expected `true` or `false`".to_string())
+        );
+    }
+}
@@ -3,7 +3,19 @@
 
 use regex::Regex;
 
+/// Options for `reverse_transpile_with`
+#[derive(Debug, Clone, Default)]
+pub struct ReverseOptions {
+    /// Write `&&`, `||`, `!`, `true`, `false` and `null` instead of
+    /// `and`, `or`, `not`, `True`, `False` and `None`
+    pub c_style: bool,
+}
+
 pub fn reverse_transpile(py_code: &str) -> String {
+    reverse_transpile_with(py_code, &ReverseOptions::default())
+}
+
+pub fn reverse_transpile_with(py_code: &str, opts: &ReverseOptions) -> String {
     let mut result = String::new();
     let lines: Vec<&str> = py_code.lines().collect();
     let mut indent_levels: Vec<usize> = vec![0];
@@ -74,44 +86,80 @@ pub fn reverse_transpile(py_code: &str) -> String {
         }
         
         // Handle single-line comments (keep '#')
-        if trimmed.starts_with('#') {
-            result.push_str(&format!("# {}\n", &trimmed[1..].trim()));
+        if let Some(comment) = trimmed.strip_prefix('#') {
+            result.push_str(&format!("# {}\n", comment.trim()));
             i += 1;
             continue;
         }
         
-        // Handle inline comments
-    let (code_part, comment_part) = if let Some(hash_pos) = trimmed.find('#') {
-            // Make sure # is not inside a string
-            let before_hash = &trimmed[..hash_pos];
-            if !is_inside_string(before_hash) {
-                let code = before_hash.trim();
-        let comment = trimmed[hash_pos+1..].trim();
-        (code, if comment.is_empty() { None } else { Some(comment) })
-            } else {
-                (trimmed, None)
-            }
+        // A statement continues over the following lines while brackets are
+        // open, as in a call or decorator with one argument per line
+        let mut last = i;
+        let mut depth = bracket_depth(trimmed);
+        while depth > 0 && last + 1 < lines.len() {
+            last += 1;
+            depth += bracket_depth(lines[last]);
+        }
+        let statement = if last == i {
+            trimmed.to_string()
         } else {
-            (trimmed, None)
+            format!("{}\n{}", trimmed, lines[i + 1..=last].join("\n").trim_end())
         };
-        
+        let last_line = statement.rfind('\n').map_or(0, |pos| pos + 1);
+
+        // Handle inline comments on the statement's last line
+        let (code_part, comment_part) =
+            if let Some(hash_pos) = statement[last_line..].find('#').map(|pos| pos + last_line) {
+                // Make sure # is not inside a string
+                let before_hash = &statement[last_line..hash_pos];
+                if !is_inside_string(before_hash) {
+                    let code = statement[..hash_pos].trim();
+                    let comment = statement[hash_pos + 1..].trim();
+                    (
+                        code,
+                        if comment.is_empty() {
+                            None
+                        } else {
+                            Some(comment)
+                        },
+                    )
+                } else {
+                    (statement.as_str(), None)
+                }
+            } else {
+                (statement.as_str(), None)
+            };
+
         if code_part.is_empty() {
-            i += 1;
+            i = last + 1;
             continue;
         }
-        
-    let converted_code = convert_python_to_nw(code_part);
-        
+
+        let mut converted_code = convert_conditionals(&convert_ranges(&convert_lambdas(
+            &convert_python_to_nw(code_part),
+        )));
+        if opts.c_style {
+            converted_code = c_style_logic(&converted_code);
+        }
+
         // Check if this is a block header (ends with :)
-    if code_part.ends_with(':') {
+        if code_part.ends_with(':') {
+            // `elif`/`else`/`except`/`finally` continue the chain on the line of the closing brace
+            let continues_chain = ["elif", "else", "except", "finally"].iter().any(|kw| {
+                code_part.strip_prefix(kw).is_some_and(|rest| rest.starts_with([' ', ':', '(']))
+            });
+            if continues_chain && result.ends_with("}\n") {
+                result.pop();
+                result.push(' ');
+            }
             result.push_str(&converted_code);
             if let Some(comment) = comment_part {
                 result.push_str(&format!(" # {}", comment));
             }
             result.push_str(" {\n");
             // Calculate next expected indent
-            if i + 1 < lines.len() {
-                let next_line = lines[i + 1];
+            if last + 1 < lines.len() {
+                let next_line = lines[last + 1];
                 let next_indent = next_line.len() - next_line.trim_start().len();
                 if next_indent > current_indent {
                     indent_levels.push(next_indent);
@@ -121,6 +169,13 @@ pub fn reverse_transpile(py_code: &str) -> String {
             } else {
                 indent_levels.push(current_indent + 4);
             }
+        } else if code_part.starts_with('@') {
+            // Decorators belong to the `def` or `class` below and take no `;`
+            result.push_str(&converted_code);
+            if let Some(comment) = comment_part {
+                result.push_str(&format!(" # {}", comment));
+            }
+            result.push('\n');
         } else {
             result.push_str(&converted_code);
             result.push(';');
@@ -130,7 +185,7 @@ pub fn reverse_transpile(py_code: &str) -> String {
             result.push('\n');
         }
         
-        i += 1;
+        i = last + 1;
     }
     
     // Close remaining blocks
@@ -142,6 +197,31 @@ pub fn reverse_transpile(py_code: &str) -> String {
     result
 }
 
+/// How many more brackets `line` opens than it closes, ignoring strings and
+/// a trailing comment
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for ch in line.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (ch, quote) {
+            ('\\', Some(_)) => escaped = true,
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('\'' | '"', None) => quote = Some(ch),
+            ('#', None) => break,
+            ('(' | '[' | '{', None) => depth += 1,
+            (')' | ']' | '}', None) => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
 fn is_inside_string(code: &str) -> bool {
     let mut in_single = false;
     let mut in_double = false;
@@ -166,6 +246,11 @@ fn is_inside_string(code: &str) -> bool {
 
 fn convert_python_to_nw(code: &str) -> String {
     let mut result = code.to_string();
+
+    // `async def`, `async for` and `async with` convert like their plain forms
+    if let Some(rest) = code.strip_prefix("async ") {
+        return format!("async {}", convert_python_to_nw(rest.trim_start()));
+    }
     
     // Normalize Python def: keep 'def', strip param type annotations but keep return type if present
     let def_regex = Regex::new(r"^def\s+(\w+)\s*\((.*?)\)\s*(->\s*[^:]+)?:?$").unwrap();
@@ -182,11 +267,12 @@ fn convert_python_to_nw(code: &str) -> String {
         return result;
     }
     
-    // Convert class
-    let class_regex = Regex::new(r"^class\s+(\w+).*:?$").unwrap();
+    // Convert class, keeping its bases and keyword arguments such as `metaclass=`
+    let class_regex = Regex::new(r"^class\s+(\w+)\s*(\(.*\))?\s*:?$").unwrap();
     if let Some(caps) = class_regex.captures(&result) {
         let name = &caps[1];
-        result = format!("class {}", name);
+        let args = caps.get(2).map_or("", |m| m.as_str());
+        result = format!("class {}{}", name, args);
         return result;
     }
     
@@ -196,13 +282,39 @@ fn convert_python_to_nw(code: &str) -> String {
     }
     
     // Keep 'elif' as-is (closer to NWPython example)
+
+    // `for k, v in d.items()` is NWPython's key/value loop `for (k: v in d)`
+    let items_regex = Regex::new(r"^for\s+(\w+)\s*,\s*(\w+)\s+in\s+(.+)\.items\(\)$").unwrap();
+    if let Some(caps) = items_regex.captures(&result)
+        && is_primary(&caps[3])
+    {
+        result = format!("for ({}: {} in {})", &caps[1], &caps[2], &caps[3]);
+        return convert_operators(&result);
+    }
+    
+    // `case P if g:` labels its block with `=>`; only a header can be a
+    // `case` or `match`, which are ordinary names elsewhere
+    if code.ends_with(':') && let Some(pattern) = result.strip_prefix("case ") {
+        result = format!("case {} =>", pattern.trim());
+        return convert_operators(&result);
+    }
     
-    // Wrap conditions in parentheses for if/elif/while/for
-    for kw in ["if", "elif", "while", "for"].iter() {
+    // Wrap conditions in parentheses for if/elif/while/for, exception types
+    // for except, context managers for with and the subject of match
+    let is_header = code.ends_with(':');
+    for kw in ["if", "elif", "while", "for", "except", "with", "match"].iter() {
+        if *kw == "match" && !is_header {
+            break;
+        }
         let prefix = format!("{} ", kw);
         if result.starts_with(&prefix) {
             let cond = result[prefix.len()..].trim();
-            result = format!("{} ({})", kw, cond);
+            // `except (A, B):` and `with (a, b):` are already parenthesized
+            let chars: Vec<char> = cond.chars().collect();
+            let grouped = cond.starts_with('(') && group_end(&chars, 0) + 1 == chars.len();
+            if !(matches!(*kw, "except" | "with") && grouped) {
+                result = format!("{} ({})", kw, cond);
+            }
             break;
         }
     }
@@ -251,7 +363,683 @@ fn convert_operators(code: &str) -> String {
     let return_inc_regex = Regex::new(r"return\s+(\w+)\s*\+=\s*1").unwrap();
     result = return_inc_regex.replace_all(&result, "return ++$1").to_string();
     
+    // `//` would start a comment in NWPython; floor division is spelled `~/`
+    result = replace_outside_strings(&result, "//", "~/");
+    
     // Keep print() as-is for NWPython
     
     result
 }
+
+/// Replace `from` with `to` everywhere except inside string literals
+fn replace_outside_strings(code: &str, from: &str, to: &str) -> String {
+    let mut result = String::with_capacity(code.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut rest = code;
+    while let Some(ch) = rest.chars().next() {
+        if quote.is_none() && rest.starts_with(from) {
+            result.push_str(to);
+            rest = &rest[from.len()..];
+            continue;
+        }
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' && quote.is_some() {
+            escaped = true;
+        } else if ch == '\'' || ch == '"' {
+            match quote {
+                None => quote = Some(ch),
+                Some(q) if q == ch => quote = None,
+                Some(_) => {}
+            }
+        }
+        result.push(ch);
+        rest = &rest[ch.len_utf8()..];
+    }
+    result
+}
+
+/// Part of a line being searched for conditional expressions
+enum Piece {
+    Word(String),
+    Text(String),
+}
+
+/// Turn Python conditional expressions `a if c else b` into `c ? a : b`,
+/// including nested and parenthesized ones
+fn convert_conditionals(code: &str) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut result = String::new();
+    // The expression being collected, up to the next `,`, `;`, `:` or `=`
+    let mut slot: Vec<Piece> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' || ch == '\'' {
+            let end = string_literal_end(&chars, i);
+            slot.push(Piece::Text(chars[i..end].iter().collect()));
+            i = end;
+            continue;
+        }
+        if matches!(ch, '(' | '[' | '{') {
+            let end = group_end(&chars, i);
+            let inner: String = chars[i + 1..end].iter().collect();
+            let close: String = chars.get(end).map(|c| c.to_string()).unwrap_or_default();
+            slot.push(Piece::Text(format!("{}{}{}", ch, convert_conditionals(&inner), close)));
+            i = end + 1;
+            continue;
+        }
+        if ch.is_alphanumeric() || ch == '_' {
+            let word = word_at(&chars, i);
+            i += word.chars().count();
+            // `for` starts a loop header or a comprehension clause
+            if word == "for" {
+                result.push_str(&conditional_slot(&slot));
+                slot.clear();
+            }
+            slot.push(Piece::Word(word));
+            continue;
+        }
+        let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+        let next = chars.get(i + 1).copied();
+        // An arrow function's body is an expression of its own
+        if ch == '=' && next == Some('>') {
+            result.push_str(&conditional_slot(&slot));
+            slot.clear();
+            result.push_str("=>");
+            i += 2;
+            continue;
+        }
+        let separator = match ch {
+            ',' | ';' => true,
+            ':' => next != Some('='),
+            '=' => next != Some('=') && !matches!(prev, Some('=' | '!' | '<' | '>' | ':')),
+            _ => false,
+        };
+        if separator {
+            result.push_str(&conditional_slot(&slot));
+            slot.clear();
+            result.push(ch);
+        } else {
+            slot.push(Piece::Text(ch.to_string()));
+        }
+        i += 1;
+    }
+    result.push_str(&conditional_slot(&slot));
+    result
+}
+
+/// Index of the bracket closing the one at `open`, or the end of the line
+fn group_end(chars: &[char], open: usize) -> usize {
+    let mut depth = 0usize;
+    let mut i = open;
+    while i < chars.len() {
+        match chars[i] {
+            '"' | '\'' => {
+                i = string_literal_end(chars, i);
+                continue;
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// Rewrite one expression slot, leaving a leading statement keyword (or a
+/// `for ... in` header) in front of the rewritten expression
+fn conditional_slot(pieces: &[Piece]) -> String {
+    let concat = |pieces: &[Piece]| -> String {
+        pieces
+            .iter()
+            .map(|p| match p {
+                Piece::Word(s) | Piece::Text(s) => s.as_str(),
+            })
+            .collect()
+    };
+    let is_word = |p: &Piece, w: &str| matches!(p, Piece::Word(s) if s == w);
+    let first_word = pieces
+        .iter()
+        .position(|p| !matches!(p, Piece::Text(t) if t.trim().is_empty()));
+    let start = match first_word {
+        Some(k) if is_word(&pieces[k], "for") => pieces
+            .iter()
+            .position(|p| is_word(p, "in"))
+            .map_or(pieces.len(), |k| k + 1),
+        Some(k)
+            if [
+                "if", "elif", "while", "return", "yield", "assert", "raise", "del", "await",
+            ]
+            .iter()
+            .any(|kw| is_word(&pieces[k], kw)) =>
+        {
+            k + 1
+        }
+        _ => 0,
+    };
+    let Some(if_pos) = pieces[start..].iter().position(|p| is_word(p, "if")) else {
+        return concat(pieces);
+    };
+    let if_pos = start + if_pos;
+    let Some(else_pos) = pieces[if_pos..].iter().position(|p| is_word(p, "else")) else {
+        return concat(pieces);
+    };
+    let else_pos = if_pos + else_pos;
+    let body = concat(&pieces[start..if_pos]);
+    let test = concat(&pieces[if_pos + 1..else_pos]);
+    let orelse = conditional_slot(&pieces[else_pos + 1..]);
+    let leading = &body[..body.len() - body.trim_start().len()];
+    let trailing = &orelse[orelse.trim_end().len()..];
+    format!(
+        "{}{}{} ? {} : {}{}",
+        concat(&pieces[..start]),
+        leading,
+        test.trim(),
+        body.trim(),
+        orelse.trim(),
+        trailing
+    )
+}
+
+/// Rewrite `and`, `or`, `not`, `True`, `False` and `None` outside string
+/// literals to their C-style spellings. `not in` and `is not` are kept, and
+/// the operand of `!` is parenthesized unless it is a single primary
+/// expression, since `!` binds tighter than `not`.
+fn c_style_logic(code: &str) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut result = String::new();
+    let mut prev_word = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' || ch == '\'' {
+            let end = string_literal_end(&chars, i);
+            result.extend(&chars[i..end]);
+            prev_word.clear();
+            i = end;
+            continue;
+        }
+        if !(ch.is_alphabetic() || ch == '_') {
+            if !ch.is_whitespace() {
+                prev_word.clear();
+            }
+            result.push(ch);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+            i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        let after_dot = start > 0 && chars[start - 1] == '.';
+        match word.as_str() {
+            _ if after_dot || (start > 0 && chars[start - 1].is_ascii_digit()) => {
+                result.push_str(&word)
+            }
+            "and" => result.push_str("&&"),
+            "or" => result.push_str("||"),
+            "True" => result.push_str("true"),
+            "False" => result.push_str("false"),
+            "None" => result.push_str("null"),
+            "not" if prev_word != "is" && word_at(&chars, skip_spaces(&chars, i)) != "in" => {
+                let from = skip_spaces(&chars, i);
+                let end = not_operand_end(&chars, from);
+                let operand: String = chars[from..end].iter().collect();
+                let trimmed = operand.trim_end();
+                let converted = c_style_logic(trimmed);
+                if is_primary(trimmed) {
+                    result.push('!');
+                    result.push_str(&converted);
+                } else {
+                    result.push_str(&format!("!({})", converted));
+                }
+                result.push_str(&operand[trimmed.len()..]);
+                i = end;
+            }
+            _ => result.push_str(&word),
+        }
+        prev_word = word;
+    }
+    result
+}
+
+/// `lambda x, y: body` → `(x, y) => body`
+fn convert_lambdas(code: &str) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' || ch == '\'' {
+            let end = string_literal_end(&chars, i);
+            result.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        if !(ch.is_alphanumeric() || ch == '_') {
+            result.push(ch);
+            i += 1;
+            continue;
+        }
+        let word = word_at(&chars, i);
+        let start = i;
+        i += word.chars().count();
+        if word != "lambda" || (start > 0 && chars[start - 1] == '.') {
+            result.push_str(&word);
+            continue;
+        }
+        // The parameters end at the first `:` outside brackets and strings
+        let mut end = i;
+        let mut depth = 0;
+        while end < chars.len() {
+            match chars[end] {
+                '"' | '\'' => {
+                    end = string_literal_end(&chars, end);
+                    continue;
+                }
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                ':' if depth == 0 && chars.get(end + 1) != Some(&'=') => break,
+                _ => {}
+            }
+            end += 1;
+        }
+        if end == chars.len() {
+            result.push_str(&word);
+            continue;
+        }
+        let params: String = chars[i..end].iter().collect();
+        result.push_str(&format!("({}) =>", params.trim()));
+        i = end + 1;
+    }
+    result
+}
+
+/// `range(n)` → `0..n`, `range(a, b + 1)` → `a..=b` and `range(a, b, s)` →
+/// `a..b step s`, for calls whose arguments are plain enough to be range
+/// bounds; anything else stays a `range()` call
+fn convert_ranges(code: &str) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut result = String::new();
+    let mut prev_word = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '"' || ch == '\'' {
+            let end = string_literal_end(&chars, i);
+            result.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        if !(ch.is_alphanumeric() || ch == '_') {
+            result.push(ch);
+            i += 1;
+            continue;
+        }
+        let word = word_at(&chars, i);
+        let start = i;
+        i += word.chars().count();
+        // A unary or binary operator in front binds tighter than `..`
+        let before = chars[..start].iter().rev().find(|c| !c.is_whitespace());
+        let operand = matches!(
+            before,
+            Some('.' | '*' | '+' | '-' | '/' | '%' | '@' | '~' | '&' | '|' | '^')
+        );
+        let opens_call = word == "range" && prev_word != "def" && chars.get(i) == Some(&'(');
+        let end = if opens_call {
+            group_end(&chars, i)
+        } else {
+            chars.len()
+        };
+        let is_call = end < chars.len();
+        // `range(n)[0]` and `range(n).index(k)` need the call
+        let used_as_value = matches!(chars.get(end + 1), Some('.' | '[' | '('));
+        let inner: String = if is_call {
+            chars[i + 1..end].iter().collect()
+        } else {
+            String::new()
+        };
+        match range_expr(&inner).filter(|_| is_call && !operand && !used_as_value) {
+            Some(range) => {
+                result.push_str(&range);
+                i = end + 1;
+            }
+            None => result.push_str(&word),
+        }
+        prev_word = word;
+    }
+    result
+}
+
+/// The range expression for the arguments of a `range()` call
+fn range_expr(args: &str) -> Option<String> {
+    let args = split_top_level(args);
+    let simple = |arg: &String| !arg.is_empty() && is_range_bound(arg);
+    if !args.iter().all(simple) {
+        return None;
+    }
+    let args: Vec<String> = args.iter().map(|a| convert_ranges(a)).collect();
+    let inclusive = |stop: &str| {
+        let bound = stop.strip_suffix("+ 1")?.trim_end();
+        let chars: Vec<char> = bound.chars().collect();
+        let mut depth = 0usize;
+        for &c in &chars {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                '<' | '>' | '&' | '|' | '^' if depth == 0 => return None,
+                _ => {}
+            }
+        }
+        Some(bound.to_string())
+    };
+    match args.as_slice() {
+        [stop] => Some(format!("0..{}", stop)),
+        [start, stop] => Some(match inclusive(stop) {
+            Some(stop) => format!("{}..={}", start, stop),
+            None => format!("{}..{}", start, stop),
+        }),
+        [start, stop, step] => {
+            let positive = step.parse::<u64>().is_ok();
+            Some(match inclusive(stop).filter(|_| positive) {
+                Some(stop) => format!("{}..={} step {}", start, stop, step),
+                None => format!("{}..{} step {}", start, stop, step),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// `code` split at its top-level commas, each part trimmed
+fn split_top_level(code: &str) -> Vec<String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"' | '\'' => {
+                let end = string_literal_end(&chars, i);
+                current.extend(&chars[i..end]);
+                i = end;
+                continue;
+            }
+            '(' | '[' | '{' => {
+                let end = (group_end(&chars, i) + 1).min(chars.len());
+                current.extend(&chars[i..end]);
+                i = end;
+                continue;
+            }
+            ',' => parts.push(std::mem::take(&mut current).trim().to_string()),
+            c => current.push(c),
+        }
+        i += 1;
+    }
+    parts.push(current.trim().to_string());
+    parts
+}
+
+/// Whether `arg` can be an operand of `..`: no keyword argument, unpacking,
+/// comparison or boolean, conditional or lambda expression at its top level
+fn is_range_bound(arg: &str) -> bool {
+    if arg.starts_with('*') {
+        return false;
+    }
+    let chars: Vec<char> = arg.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"' | '\'' => {
+                i = string_literal_end(&chars, i);
+                continue;
+            }
+            '(' | '[' | '{' => {
+                i = group_end(&chars, i) + 1;
+                continue;
+            }
+            '<' | '>' | '=' | '!' => return false,
+            c if c.is_alphabetic() || c == '_' => {
+                let word = word_at(&chars, i);
+                let keyword = [
+                    "if", "else", "and", "or", "not", "in", "is", "lambda", "for", "await", "yield",
+                ];
+                let keyword = keyword.contains(&word.as_str());
+                if keyword && (i == 0 || !chars[i - 1].is_ascii_digit()) {
+                    return false;
+                }
+                i += word.chars().count();
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    true
+}
+
+fn skip_spaces(chars: &[char], mut i: usize) -> usize {
+    while i < chars.len() && chars[i].is_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// The identifier starting at `i`, or an empty string
+fn word_at(chars: &[char], i: usize) -> String {
+    chars[i.min(chars.len())..]
+        .iter()
+        .take_while(|c| c.is_alphanumeric() || **c == '_')
+        .collect()
+}
+
+/// End of the string literal whose opening quote is at `i`
+fn string_literal_end(chars: &[char], i: usize) -> usize {
+    let quote = chars[i];
+    let triple = chars.get(i + 1) == Some(&quote) && chars.get(i + 2) == Some(&quote);
+    let mut k = if triple { i + 3 } else { i + 1 };
+    while k < chars.len() {
+        if chars[k] == '\\' {
+            k += 2;
+            continue;
+        }
+        if chars[k] == quote
+            && (!triple || (chars.get(k + 1) == Some(&quote) && chars.get(k + 2) == Some(&quote)))
+        {
+            return if triple { k + 3 } else { k + 1 };
+        }
+        k += 1;
+    }
+    chars.len()
+}
+
+/// Where the operand of a `not` starting at `i` ends: at the next `and`,
+/// `or`, `if`, `else`, `for`, `,`, `:` or closing bracket outside brackets
+fn not_operand_end(chars: &[char], mut i: usize) -> usize {
+    let mut depth = 0usize;
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            '"' | '\'' => {
+                i = string_literal_end(chars, i);
+                continue;
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => return i,
+            ')' | ']' | '}' => depth -= 1,
+            ',' | ':' | ';' | '?' if depth == 0 => return i,
+            c if depth == 0 && (c.is_alphabetic() || c == '_') => {
+                let word = word_at(chars, i);
+                let starts_word = i == 0 || !(chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+                if starts_word && ["and", "or", "if", "else", "for"].contains(&word.as_str()) {
+                    return i;
+                }
+                i += word.chars().count();
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    i
+}
+
+/// A name, literal, call, attribute or subscript chain, or a bracketed group,
+/// which needs no parentheses after `!`
+fn is_primary(operand: &str) -> bool {
+    let chars: Vec<char> = operand.chars().collect();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"' | '\'' => {
+                i = string_literal_end(&chars, i);
+                continue;
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            c if depth == 0 && !(c.is_alphanumeric() || c == '_' || c == '.') => return false,
+            _ => {}
+        }
+        i += 1;
+    }
+    !operand.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_floor_division_is_not_a_comment() {
+        assert_eq!(
+            reverse_transpile("q = a // b\nq //= 2\nurl = \"http://x\" # a // b\n"),
+            "q = a ~/ b;\nq ~/= 2;\nurl = \"http://x\"; # a // b\n"
+        );
+    }
+
+    #[test]
+    fn test_conditional_expressions() {
+        let py = "x = n if n > 0 else -n\nreturn a if c else b if d else e\nprint((p if q else r) if s else t, {k: 1 if v else 0 for k, v in d})\nif (y if z else w):\n    pass\n";
+        assert_eq!(
+            reverse_transpile(py),
+            "x = n > 0 ? n : -n;\nreturn c ? a : d ? b : e;\nprint(s ? (q ? p : r) : t, {k: v ? 1 : 0 for k, v in d});\nif ((z ? y : w)) {\npass;\n}\n"
+        );
+        assert_eq!(
+            reverse_transpile_with("y = 1 if not x else 2\n", &ReverseOptions { c_style: true }),
+            "y = !x ? 1 : 2;\n"
+        );
+    }
+
+    /// Reverse transpile `py` and transpile the result back
+    fn round_trip(py: &str) -> String {
+        let nw = reverse_transpile(py);
+        crate::transpile(&nwparser::tokenize_spanned(&nw))
+            .unwrap_or_else(|e| panic!("{}\n{:?}", nw, e))
+    }
+
+    #[test]
+    fn test_classes_round_trip() {
+        let py = "@dataclass\nclass Point(Base, metaclass=Meta):\n    class Inner:\n        pass\n    def __init__(self, x, y=0):\n        self.x = x\n        self.y = y\n    @staticmethod\n    def origin():\n        return Point(0, 0)\n";
+        assert_eq!(round_trip(py), py);
+        assert!(reverse_transpile(py).starts_with("@dataclass\nclass Point(Base, metaclass=Meta) {\n"));
+    }
+
+    #[test]
+    fn test_multiline_decorator_round_trip() {
+        let py = "@app.route(\n    \"/\",  # root\n    methods=[\"GET\"],\n)\ndef index(\n    request,\n):\n    return render(request,\n                  \"index.html\")\n";
+        let nw = reverse_transpile(py);
+        assert!(nw.starts_with("@app.route(\n    \"/\",  # root\n    methods=[\"GET\"],\n)\ndef index(\n    request,\n) {\n"), "{}", nw);
+        assert_eq!(round_trip(py), "# root\n@app.route(\"/\", methods=[\"GET\"])\ndef index(request):\n    return render(request, \"index.html\")\n");
+    }
+
+    #[test]
+    fn test_multiline_string_round_trip() {
+        let py = "print(\"\"\"a; b {\n  \\\"c\\\" # d\n\"\"\")\n";
+        assert_eq!(round_trip(py), py);
+    }
+
+    #[test]
+    fn test_items_loop_round_trip() {
+        let py = "for k, v in self.data[\"x\"].items():\n    print(k, v)\nfor v in d.values():\n    pass\nfor k, v in a + b.items():\n    pass\n";
+        let nw = reverse_transpile(py);
+        assert!(nw.starts_with("for (k: v in self.data[\"x\"]) {\n"), "{}", nw);
+        assert!(nw.contains("for (v in d.values()) {\n"), "{}", nw);
+        assert!(nw.contains("for (k, v in a + b.items()) {\n"), "{}", nw);
+        assert_eq!(round_trip(py), py);
+    }
+
+    #[test]
+    fn test_match_round_trip() {
+        let py = "match command.split():\n    case [\"go\", direction] if direction in exits:\n        move(direction)\n    case Point(x=0, y=y) | Point(x=y, y=0):\n        print(y)\n    case {\"action\": str() as action, **rest}:\n        run(action, rest)\n    case _:\n        pass\nmatch = case = 1\n";
+        let nw = reverse_transpile(py);
+        assert!(nw.starts_with("match (command.split()) {\ncase [\"go\", direction] if direction in exits => {\n"), "{}", nw);
+        assert!(nw.ends_with("}\nmatch = case = 1;\n"), "{}", nw);
+        assert_eq!(round_trip(py), py);
+    }
+
+    #[test]
+    fn test_lambdas_become_arrow_functions() {
+        let py = "add = lambda x, y=1: x + y\nif any(map(lambda s: s.strip() if s else \"lambda: no\", xs)):\n    handlers = {\"a\": lambda: None, \"b\": lambda *a, **k: d[1:]}\n";
+        let nw = reverse_transpile(py);
+        assert!(nw.starts_with("add = (x, y=1) => x + y;\nif (any(map((s) => s ? s.strip() : \"lambda: no\", xs))) {\n"), "{}", nw);
+        assert!(nw.contains("{\"a\": () => None, \"b\": (*a, **k) => d[1:]};"), "{}", nw);
+        assert_eq!(round_trip(py), py.replace("if any(map(", "if (any(map(").replace(" xs)):", " xs))):"));
+    }
+
+    #[test]
+    fn test_range_calls_become_ranges() {
+        let py = "for i in range(n):\n    xs = list(range(1, n + 1, 2))\nfor i in range(len(xs) - 1, -1, -1):\n    ys = range(a, b + 1)\n";
+        let nw = reverse_transpile(py);
+        assert!(nw.starts_with("for (i in 0..n) {\nxs = list(1..=n step 2);\n}\nfor (i in len(xs) - 1..-1 step -1) {\nys = a..=b;\n"), "{}", nw);
+        assert_eq!(round_trip(py), py);
+        // Calls that are not plain bounds, or are used as a value, stay calls
+        let py =
+            "r = range(10)[0]\nxs = [*range(3)]\nys = range(x if c else 3)\nzs = range(*args)\n";
+        assert_eq!(reverse_transpile(py).matches("range(").count(), 4);
+    }
+
+    #[test]
+    fn test_with_and_async_round_trip() {
+        let py = "async def main(urls):\n    with open(a) as f, open(b) as g:\n        data = f.read()\n    async with (lock, session() as s):\n        pass\n    async for x in s.get(urls):\n        print(await x)\n";
+        assert_eq!(round_trip(py), py.replace("(lock, session() as s)", "lock, session() as s"));
+        assert!(reverse_transpile(py).contains("with (open(a) as f, open(b) as g) {\n"));
+    }
+
+    #[test]
+    fn test_exceptions_round_trip() {
+        let py = "try:\n    n = int(s)\nexcept ValueError as e:\n    raise RuntimeError(\"bad\") from e\nexcept (TypeError, KeyError):\n    n = None\nexcept:\n    raise\nelse:\n    print(n)\nfinally:\n    print(\"done\")\n";
+        assert_eq!(round_trip(py), py);
+        assert!(reverse_transpile(py).contains("} except (ValueError as e) {\n"));
+    }
+
+    #[test]
+    fn test_else_joins_the_closing_brace() {
+        let py = "if x:\n    a()\nelif y:\n    b()\nelse:\n    c()\nelse_branch = 1\n";
+        assert_eq!(
+            reverse_transpile(py),
+            "if (x) {\na();\n} elif (y) {\nb();\n} else {\nc();\n}\nelse_branch = 1;\n"
+        );
+    }
+
+    #[test]
+    fn test_c_style_logic() {
+        let py = "if a and not b or c is not None:\n    x = not y == 2\n    s = \"True and None\" if k not in d else False\n";
+        assert_eq!(
+            reverse_transpile_with(py, &ReverseOptions { c_style: true }),
+            "if (a && !b || c is not null) {\nx = !(y == 2);\ns = k not in d ? \"True and None\" : false;\n}\n"
+        );
+        assert_eq!(
+            reverse_transpile("x = not y\n"),
+            "x = not y;\n"
+        );
+    }
+}
@@ -1,314 +1,1693 @@
 //! Transpiler: convert tokens to Python code
-use nwparser::tokenizer::Token;
-
-pub fn transpile(tokens: &[Token]) -> String {
-    let mut out_lines = Vec::new();
-    let mut indent = 0;
-    let mut stmt_buf = String::new();
-    let block_headers = ["if ", "elif ", "else", "def ", "while ", "for "];
-    let mut block_stack: Vec<&str> = Vec::new(); // Track what kind of blocks we're in
-
-    fn header_needs_colon(s: &str, block_headers: &[&str]) -> bool {
-        let s = s.trim();
-        block_headers.iter().any(|h| s.starts_with(h))
-    }
-
-    fn is_function_header(s: &str) -> bool {
-        s.trim().starts_with("def ")
-    }
-
-    let re_print_post_inc =
-        regex::Regex::new(r"print\s*\(\s*([a-zA-Z_][a-zA-Z0-9_]*)\s*\+\+\s*\)").unwrap();
-    let re_print_post_dec =
-        regex::Regex::new(r"print\s*\(\s*([a-zA-Z_][a-zA-Z0-9_]*)\s*--\s*\)").unwrap();
-    let re_print_pre_inc =
-        regex::Regex::new(r"print\s*\(\s*\+\+\s*([a-zA-Z_][a-zA-Z0-9_]*)\s*\)").unwrap();
-    let re_print_pre_dec =
-        regex::Regex::new(r"print\s*\(\s*--\s*([a-zA-Z_][a-zA-Z0-9_]*)\s*\)").unwrap();
-    let re_ret_pre_inc = regex::Regex::new(r"return\s*\+\+\s*([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
-    let re_ret_pre_dec = regex::Regex::new(r"return\s*--\s*([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
-    let re_pre_inc = regex::Regex::new(r"\+\+\s*([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
-    let re_pre_dec = regex::Regex::new(r"--\s*([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
-    let re_inc = regex::Regex::new(r"\b([a-zA-Z_][a-zA-Z0-9_]*)\s*\+\+").unwrap();
-    let re_dec = regex::Regex::new(r"\b([a-zA-Z_][a-zA-Z0-9_]*)\s*--").unwrap();
-    let mut i = 0;
-    while i < tokens.len() {
-        match &tokens[i] {
-            Token::Comment(c) => {
-                let c = c.trim_start_matches(['/', '#', ' ']);
-                if c.starts_with("*") {
-                    // Multi-line comment: /* ... */
-                    let content = c
-                        .trim_start_matches('*')
-                        .trim_start_matches('/')
-                        .trim_end_matches("*/")
-                        .trim();
-                    out_lines.push(format!("{}\"\"\"{}\"\"\"", "    ".repeat(indent), content));
-                } else {
-                    // Single-line comment
-                    out_lines.push(format!("{}# {}", "    ".repeat(indent), c));
-                }
+use crate::expr::{Effects, ExprPrinter, can_hoist, offset, prec};
+use crate::options::{CommentStyle, PythonVersion, Strictness, TranspileOptions};
+use crate::visit::{stmt_blocks, stmt_exprs, walk, walk_body_exprs, walk_stmts};
+use nwparser::ast::*;
+use nwparser::diagnostic::{Diagnostic, codes};
+use nwparser::tokenizer::SpannedToken;
+use std::collections::HashSet;
+
+/// Transpile tokenized NWPython to Python source.
+///
+/// Any error (unbalanced braces, unterminated strings or comments, syntax
+/// errors) makes this fail with the full list of diagnostics.
+pub fn transpile(tokens: &[SpannedToken]) -> Result<String, Vec<Diagnostic>> {
+    transpile_with(tokens, &TranspileOptions::default())
+}
+
+/// The first line of the output when [`TranspileOptions::banner`] is set
+pub const BANNER: &str = "# Generated from NWPython source by nwtranspiler; do not edit";
+
+/// Like [`transpile`], with settings such as the indentation and the target
+/// Python version. With [`Strictness::Lossy`] this never fails.
+pub fn transpile_with(
+    tokens: &[SpannedToken],
+    options: &TranspileOptions,
+) -> Result<String, Vec<Diagnostic>> {
+    let (module, mut errors) = match options.strictness {
+        Strictness::Strict => (nwparser::parse_tokens(tokens)?, Vec::new()),
+        Strictness::Lossy => nwparser::parse_tokens_recovering(tokens),
+    };
+    let mut emitter = Emitter::new(&module, options);
+    emitter.block(&module.body);
+    errors.append(&mut emitter.errors);
+    if options.strictness == Strictness::Strict && !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut out = Vec::new();
+    if options.banner {
+        out.push(BANNER.to_string());
+    }
+    out.extend(errors.iter().map(|d| format!("# ERROR: {}", d)));
+    out.push(emitter.finish());
+    Ok(out.join("\n"))
+}
+
+/// Transpile whatever parses, writing a `# ERROR: ...` comment at the top of
+/// the output for every diagnostic instead of failing
+pub fn transpile_lossy(tokens: &[SpannedToken]) -> String {
+    let options = TranspileOptions {
+        strictness: Strictness::Lossy,
+        ..TranspileOptions::default()
+    };
+    transpile_with(tokens, &options).expect("lossy transpiling never fails")
+}
+
+#[derive(Default)]
+struct Emitter {
+    out_lines: Vec<String>,
+    indent: usize,
+    errors: Vec<Diagnostic>,
+    /// For each enclosing loop, the C-style `for` steps or `do`/`while` test
+    /// that a `continue` has to run first
+    loop_steps: Vec<Vec<Stmt>>,
+    /// One level of indentation
+    indent_unit: String,
+    comments: CommentStyle,
+    target: PythonVersion,
+    implicit_return: bool,
+    /// Every name in the program, so generated temporaries never shadow one
+    names: HashSet<String>,
+    /// Block-bodied arrow functions already defined by the statements being
+    /// emitted, and the names of their `def`s
+    arrows: Vec<(Expr, String)>,
+    /// The temporary `??` and `?.` bind values to
+    temp: String,
+    /// The body of the function (or the module) being emitted, which is
+    /// everywhere a loop variable could be read once the loop is over
+    scope: Vec<Stmt>,
+}
+
+impl Emitter {
+    fn new(module: &Module, options: &TranspileOptions) -> Self {
+        let mut emitter = Emitter {
+            indent_unit: options.indent.unit(),
+            comments: options.comments,
+            target: options.target,
+            implicit_return: options.implicit_return,
+            names: module_names(&module.body),
+            scope: module.body.clone(),
+            ..Emitter::default()
+        };
+        emitter.temp = emitter.fresh_name("_v");
+        emitter
+    }
+
+    fn finish(self) -> String {
+        self.out_lines.join("\n") + "\n"
+    }
+
+    fn line(&mut self, s: impl AsRef<str>) {
+        self.out_lines.push(format!(
+            "{}{}",
+            self.indent_unit.repeat(self.indent),
+            s.as_ref()
+        ));
+    }
+
+    fn decorators(&mut self, decorators: &[Expr]) {
+        for d in decorators {
+            self.simple(&[], false, |p| format!("@{}", p.expr(d, prec::NAMED)));
+        }
+    }
+
+    /// A name for a temporary variable that is not used anywhere else
+    fn fresh_name(&mut self, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut n = 1;
+        while self.names.contains(&name) {
+            n += 1;
+            name = format!("{}{}", base, n);
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    /// Emit an indented block, or `pass` if it has no statements
+    fn body(&mut self, body: &[Stmt]) {
+        self.indent += 1;
+        if body.iter().all(|s| matches!(s, Stmt::Comment(_))) {
+            self.block(body);
+            self.line("pass");
+        } else {
+            self.block(body);
+        }
+        self.indent -= 1;
+    }
+
+    fn block(&mut self, body: &[Stmt]) {
+        for stmt in body {
+            self.stmt(stmt);
+        }
+    }
+
+    /// With `implicit_return`, `body` with its last statement turned into a
+    /// `return` when it is an expression without a `;`. Assignments, control
+    /// flow, updates such as `i++` and `yield` are never returned.
+    fn implicit_return(&self, body: &[Stmt]) -> Option<Vec<Stmt>> {
+        if !self.implicit_return {
+            return None;
+        }
+        let last = body.iter().rposition(|s| !matches!(s, Stmt::Comment(_)))?;
+        match &body[last] {
+            Stmt::Expr {
+                value,
+                terminated: false,
+            } if !matches!(
+                value,
+                Expr::IncDec { .. } | Expr::Yield(_) | Expr::YieldFrom(_)
+            ) =>
+            {
+                let mut body = body.to_vec();
+                body[last] = Stmt::Return(Some(value.clone()));
+                Some(body)
             }
-            Token::Text(s) => {
-                let mut line = s.to_string();
-
-                let mut handled = false;
-                if re_print_post_inc.is_match(&line) {
-                    let var = re_print_post_inc
-                        .captures(&line)
-                        .unwrap()
-                        .get(1)
-                        .unwrap()
-                        .as_str();
-                    out_lines.push(format!("{}print({})", "    ".repeat(indent), var));
-                    out_lines.push(format!("{}{} += 1", "    ".repeat(indent), var));
-                    handled = true;
-                }
-                if re_print_post_dec.is_match(&line) {
-                    let var = re_print_post_dec
-                        .captures(&line)
-                        .unwrap()
-                        .get(1)
-                        .unwrap()
-                        .as_str();
-                    out_lines.push(format!("{}print({})", "    ".repeat(indent), var));
-                    out_lines.push(format!("{}{} -= 1", "    ".repeat(indent), var));
-                    handled = true;
-                }
-                if re_print_pre_inc.is_match(&line) {
-                    let var = re_print_pre_inc
-                        .captures(&line)
-                        .unwrap()
-                        .get(1)
-                        .unwrap()
-                        .as_str();
-                    out_lines.push(format!("{}{} += 1", "    ".repeat(indent), var));
-                    out_lines.push(format!("{}print({})", "    ".repeat(indent), var));
-                    handled = true;
-                }
-                if re_print_pre_dec.is_match(&line) {
-                    let var = re_print_pre_dec
-                        .captures(&line)
-                        .unwrap()
-                        .get(1)
-                        .unwrap()
-                        .as_str();
-                    out_lines.push(format!("{}{} -= 1", "    ".repeat(indent), var));
-                    out_lines.push(format!("{}print({})", "    ".repeat(indent), var));
-                    handled = true;
-                }
-                if handled {
-                    // Don't process further
-                } else {
-                    // Handle return x++ and return x-- (post-increment/decrement)
-
-                    let mut handled_ret = false;
-                    if re_ret_pre_inc.is_match(&line) {
-                        let var = re_ret_pre_inc
-                            .captures(&line)
-                            .unwrap()
-                            .get(1)
-                            .unwrap()
-                            .as_str();
-                        stmt_buf.push_str("");
-                        out_lines.push(format!("{}{} += 1", "    ".repeat(indent), var));
-                        out_lines.push(format!("{}return {}", "    ".repeat(indent), var));
-                        handled_ret = true;
-                    }
-                    if re_ret_pre_dec.is_match(&line) {
-                        let var = re_ret_pre_dec
-                            .captures(&line)
-                            .unwrap()
-                            .get(1)
-                            .unwrap()
-                            .as_str();
-                        stmt_buf.push_str("");
-                        out_lines.push(format!("{}{} -= 1", "    ".repeat(indent), var));
-                        out_lines.push(format!("{}return {}", "    ".repeat(indent), var));
-                        handled_ret = true;
-                    }
-                    if handled_ret {
-                        // Don't process further
-                    } else {
-                        // Handle ++x and --x (standalone)
+            _ => None,
+        }
+    }
 
-                        line = re_pre_inc.replace_all(&line, "$1 += 1").to_string();
-                        line = re_pre_dec.replace_all(&line, "$1 -= 1").to_string();
-                        // Handle x++ and x-- (standalone)
+    /// Emit the body of a loop; `step` is emitted at its end and before
+    /// every `continue` that belongs to this loop
+    fn loop_body(&mut self, body: &[Stmt], step: &[Stmt]) {
+        self.loop_steps.push(step.to_vec());
+        if step.is_empty() {
+            self.body(body);
+        } else {
+            self.indent += 1;
+            self.block(body);
+            for s in step {
+                self.stmt(s);
+            }
+            self.indent -= 1;
+        }
+        self.loop_steps.pop();
+    }
 
-                        line = re_inc.replace_all(&line, "$1 += 1").to_string();
-                        line = re_dec.replace_all(&line, "$1 -= 1").to_string();
+    /// Print a simple statement made of `exprs`. A lone `++`/`--` update is
+    /// placed around it when that keeps its meaning, otherwise lowered to `:=`.
+    fn simple(
+        &mut self,
+        exprs: &[&Expr],
+        allow_postfix: bool,
+        print: impl FnOnce(&mut ExprPrinter) -> String,
+    ) {
+        let mut fx = Effects::default();
+        let hoist = can_hoist(exprs, allow_postfix);
+        let mut printer = ExprPrinter::new(&mut fx)
+            .hoisting(hoist)
+            .arrows(&self.arrows)
+            .temp(&self.temp);
+        let line = print(&mut printer);
+        self.flush_effects(fx, Some(line));
+    }
 
-                        // Fix dictionary iteration: .values -> .items() for key,value pairs
-                        if line.contains("for ")
-                            && line.contains(" in ")
-                            && line.contains(".values")
-                        {
-                            line = line.replace(".values", ".items()");
-                        }
+    fn flush_effects(&mut self, fx: Effects, line: Option<String>) {
+        self.errors.extend(fx.errors);
+        for s in fx.before {
+            self.line(s);
+        }
+        if let Some(line) = line {
+            self.line(line);
+        }
+        for s in fx.after {
+            self.line(s);
+        }
+    }
 
-                        stmt_buf.push_str(&line);
-                    }
-                }
+    /// Print a block header such as `if x:`. Headers evaluated once may have
+    /// a prefix update placed before them; loop conditions and `elif` tests
+    /// always use `:=`.
+    fn header(
+        &mut self,
+        exprs: &[&Expr],
+        evaluated_once: bool,
+        print: impl FnOnce(&mut ExprPrinter) -> String,
+    ) -> String {
+        let mut fx = Effects::default();
+        let hoist = evaluated_once && can_hoist(exprs, false);
+        let mut printer = ExprPrinter::new(&mut fx)
+            .hoisting(hoist)
+            .arrows(&self.arrows)
+            .temp(&self.temp);
+        let header = print(&mut printer);
+        self.flush_effects(fx, None);
+        header
+    }
+
+    fn comment(&mut self, comment: &str) {
+        let c = comment.trim_start_matches(['/', '#', ' ']);
+        if self.comments == CommentStyle::Strip {
+            return;
+        }
+        if !c.starts_with('*') {
+            // Single-line comment
+            self.line(format!("# {}", c));
+            return;
+        }
+        // Multi-line comment: /* ... */
+        let content = c
+            .trim_start_matches('*')
+            .trim_start_matches('/')
+            .trim_end_matches("*/")
+            .trim();
+        if self.comments == CommentStyle::Docstring {
+            self.line(format!("\"\"\"{}\"\"\"", content));
+            return;
+        }
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() {
+                self.line("#");
+            } else {
+                self.line(format!("# {}", line));
             }
-            Token::LBrace => {
-                let header = stmt_buf.trim().to_string();
-
-                // Check if this is a dictionary literal by analyzing the context
-                let is_dictionary = if header.is_empty() {
-                    // Empty header could be a standalone dictionary
-                    true
-                } else if header.contains('=') {
-                    // Contains assignment, likely: var = {
-                    let after_equals = header.split('=').last().unwrap_or("").trim();
-                    after_equals.is_empty() || !header_needs_colon(&header, &block_headers)
-                } else {
-                    // Check if this is a control structure that needs a colon
-                    !header_needs_colon(&header, &block_headers)
-                };
+        }
+    }
 
-                if is_dictionary {
-                    // This is a dictionary literal, keep the brace as part of the statement
-                    stmt_buf.push('{');
-                } else {
-                    // This is a code block
-                    stmt_buf.clear();
-                    let mut line = header.clone();
-                    if !line.ends_with(':') {
-                        line.push(':');
+    fn stmt(&mut self, stmt: &Stmt) {
+        let defined = self.arrows.len();
+        self.hoist_arrows(stmt);
+        match stmt {
+            Stmt::Comment(c) => self.comment(c),
+            Stmt::FunctionDef(def) => {
+                self.decorators(&def.decorators);
+                let header = self.header(&[], false, |p| {
+                    let params = p.params(&def.params);
+                    let kw = if def.is_async { "async def" } else { "def" };
+                    match &def.returns {
+                        Some(ret) => format!(
+                            "{} {}({}) -> {}:",
+                            kw,
+                            def.name,
+                            params,
+                            p.expr(ret, prec::LAMBDA)
+                        ),
+                        None => format!("{} {}({}):", kw, def.name, params),
                     }
-                    out_lines.push(format!("{}{}", "    ".repeat(indent), line));
-
-                    // Track what kind of block we're entering
-                    if is_function_header(&header) {
-                        block_stack.push("function");
+                });
+                self.line(header);
+                let outer_loops = std::mem::take(&mut self.loop_steps);
+                let body = self.implicit_return(&def.body);
+                let body = body.as_deref().unwrap_or(&def.body);
+                let outer_scope = std::mem::replace(&mut self.scope, body.to_vec());
+                self.body(body);
+                self.scope = outer_scope;
+                self.loop_steps = outer_loops;
+            }
+            Stmt::ClassDef(class) => {
+                self.decorators(&class.decorators);
+                let header = self.header(&[], false, |p| {
+                    if class.args.is_empty() {
+                        format!("class {}:", class.name)
                     } else {
-                        block_stack.push("control");
+                        format!("class {}({}):", class.name, p.args(&class.args))
                     }
-
-                    indent += 1;
+                });
+                self.line(header);
+                let outer_loops = std::mem::take(&mut self.loop_steps);
+                self.body(&class.body);
+                self.loop_steps = outer_loops;
+            }
+            Stmt::If(if_stmt) => {
+                let header = self.header(&[&if_stmt.test], true, |p| {
+                    format!("if {}:", p.expr(&if_stmt.test, prec::TUPLE))
+                });
+                self.line(header);
+                self.body(&if_stmt.body);
+                for elif in &if_stmt.elifs {
+                    let header = self.header(&[], false, |p| {
+                        format!("elif {}:", p.expr(&elif.test, prec::TUPLE))
+                    });
+                    self.line(header);
+                    self.body(&elif.body);
+                }
+                if let Some(orelse) = &if_stmt.orelse {
+                    self.line("else:");
+                    self.body(orelse);
                 }
             }
-            Token::RBrace => {
-                // Check if we're in a dictionary context by looking at the statement buffer
-                let current_stmt = stmt_buf.trim();
-                let in_dictionary = current_stmt.contains('{')
-                    || current_stmt.contains(':')
-                        && !current_stmt.starts_with("if ")
-                        && !current_stmt.starts_with("elif ")
-                        && !current_stmt.starts_with("else")
-                        && !current_stmt.starts_with("def ")
-                        && !current_stmt.starts_with("while ")
-                        && !current_stmt.starts_with("for ");
-
-                if in_dictionary {
-                    // This is closing a dictionary literal
-                    stmt_buf.push('}');
-                } else {
-                    // This is closing a code block
-                    let simple = stmt_buf.trim().to_string();
-                    let in_function_context = block_stack.contains(&"function");
-
-                    if !simple.is_empty() {
-                        let statements: Vec<&str> = simple.split(';').collect();
-                        for (idx, stmt) in statements.iter().enumerate() {
-                            let s = stmt.trim();
-                            if !s.is_empty() {
-                                // Handle 'let' keyword conversion to standard variable assignment
-
-                                let s = if let Some(stripped) = s.strip_prefix("let ") {
-                                    stripped
-                                } else {
-                                    s
-                                };
-                                // Check if this is the last statement and we should auto-return
-                                let should_auto_return = in_function_context
-                                    && idx == statements.len() - 1
-                                    && !s.starts_with("return ")
-                                    && !s.trim().is_empty()
-                                    && !s.starts_with("print(")
-                                    && !s.starts_with("print ");
-
-                                if should_auto_return {
-                                    // Auto-return: add return prefix to the last expression
-                                    out_lines.push(format!(
-                                        "{}return {}",
-                                        "    ".repeat(indent),
-                                        s
-                                    ));
-                                } else {
-                                    out_lines.push(format!("{}{}", "    ".repeat(indent), s));
-                                }
+            Stmt::While(w) => {
+                let header = self.header(&[], false, |p| {
+                    format!("while {}:", p.expr(&w.test, prec::TUPLE))
+                });
+                self.line(header);
+                self.loop_body(&w.body, &[]);
+            }
+            Stmt::For(f) => {
+                let header = self.header(&[&f.iter], true, |p| {
+                    let kw = if f.is_async { "async for" } else { "for" };
+                    match &f.value {
+                        // `for (k: v in d)` iterates over `d.items()`
+                        Some(value) => format!(
+                            "{} {}, {} in {}.items():",
+                            kw,
+                            p.expr(&f.target, prec::LAMBDA),
+                            p.expr(value, prec::LAMBDA),
+                            p.expr(&f.iter, prec::PRIMARY)
+                        ),
+                        None => format!(
+                            "{} {} in {}:",
+                            kw,
+                            p.exprlist(&f.target),
+                            p.exprlist(&f.iter)
+                        ),
+                    }
+                });
+                self.line(header);
+                self.loop_body(&f.body, &[]);
+            }
+            Stmt::CFor(c) => self.c_for(c),
+            Stmt::DoWhile(w) => {
+                // The test runs at the end of every pass, including one cut
+                // short by `continue`
+                let exit = Stmt::If(If {
+                    test: Expr::UnaryOp {
+                        op: UnaryOp::Not,
+                        operand: Box::new(w.test.clone()),
+                    },
+                    body: vec![Stmt::Break],
+                    elifs: Vec::new(),
+                    orelse: None,
+                });
+                self.line("while True:");
+                self.loop_body(&w.body, std::slice::from_ref(&exit));
+            }
+            Stmt::Switch(s) => self.switch(s),
+            Stmt::Match(m) => self.match_stmt(m),
+            Stmt::With(w) => {
+                let contexts: Vec<&Expr> = w.items.iter().map(|i| &i.context).collect();
+                let header = self.header(&contexts, true, |p| {
+                    let items: Vec<String> = w
+                        .items
+                        .iter()
+                        .map(|i| {
+                            let context = p.expr(&i.context, prec::LAMBDA);
+                            match &i.target {
+                                Some(t) => format!("{} as {}", context, p.expr(t, prec::BIT_OR)),
+                                None => context,
                             }
+                        })
+                        .collect();
+                    let kw = if w.is_async { "async with" } else { "with" };
+                    format!("{} {}:", kw, items.join(", "))
+                });
+                self.line(header);
+                self.body(&w.body);
+            }
+            Stmt::Try(t) => {
+                self.line("try:");
+                self.body(&t.body);
+                for handler in &t.handlers {
+                    let header = self.header(&[], false, |p| {
+                        let typ = handler.typ.as_ref().map(|t| p.expr(t, prec::LAMBDA));
+                        match (typ, &handler.name) {
+                            (Some(typ), Some(name)) => format!("except {} as {}:", typ, name),
+                            (Some(typ), None) => format!("except {}:", typ),
+                            (None, _) => "except:".to_string(),
                         }
-                        stmt_buf.clear();
+                    });
+                    self.line(header);
+                    self.body(&handler.body);
+                }
+                if let Some(orelse) = &t.orelse {
+                    self.line("else:");
+                    self.body(orelse);
+                }
+                if let Some(finalbody) = &t.finalbody {
+                    self.line("finally:");
+                    self.body(finalbody);
+                }
+            }
+            Stmt::Return(value) => {
+                let exprs: Vec<&Expr> = value.iter().collect();
+                self.simple(&exprs, false, |p| match value {
+                    Some(v) => format!("return {}", p.exprlist(v)),
+                    None => "return".to_string(),
+                })
+            }
+            Stmt::Assign(a) => {
+                let exprs: Vec<&Expr> = a.targets.iter().chain([&a.value]).collect();
+                self.simple(&exprs, true, |p| {
+                    let mut parts: Vec<String> = a.targets.iter().map(|t| p.exprlist(t)).collect();
+                    parts.push(rhs(p, &a.value));
+                    parts.join(" = ")
+                })
+            }
+            Stmt::AugAssign(a) => self.simple(&[&a.target, &a.value], true, |p| {
+                let target = p.expr(&a.target, prec::PRIMARY);
+                format!("{} {}= {}", target, a.op.as_str(), rhs(p, &a.value))
+            }),
+            Stmt::AnnAssign(a) => {
+                let exprs: Vec<&Expr> = [&a.target].into_iter().chain(&a.value).collect();
+                self.simple(&exprs, true, |p| {
+                    let target = p.expr(&a.target, prec::PRIMARY);
+                    let annotation = p.expr(&a.annotation, prec::LAMBDA);
+                    match &a.value {
+                        Some(v) => format!("{}: {} = {}", target, annotation, rhs(p, v)),
+                        None => format!("{}: {}", target, annotation),
                     }
+                })
+            }
+            Stmt::Expr { value, .. } => {
+                // A statement that is only `x++` / `--x` becomes a plain update
+                if let Expr::IncDec { op, target, .. } = value {
+                    let op = match op {
+                        IncDecOp::Inc => "+=",
+                        IncDecOp::Dec => "-=",
+                    };
+                    self.simple(&[target], true, |p| {
+                        format!("{} {} 1", p.expr(target, prec::PRIMARY), op)
+                    });
+                } else {
+                    self.simple(&[value], true, |p| rhs(p, value));
+                }
+            }
+            Stmt::Import(names) => self.line(format!("import {}", aliases(names))),
+            Stmt::ImportFrom(i) => {
+                let names = if i.names.is_empty() {
+                    "*".to_string()
+                } else {
+                    aliases(&i.names)
+                };
+                self.line(format!("from {} import {}", i.module, names));
+            }
+            Stmt::Global(names) => self.line(format!("global {}", names.join(", "))),
+            Stmt::Nonlocal(names) => self.line(format!("nonlocal {}", names.join(", "))),
+            Stmt::Del(targets) => {
+                let exprs: Vec<&Expr> = targets.iter().collect();
+                self.simple(&exprs, true, |p| format!("del {}", p.list(targets)))
+            }
+            // Assertions may be stripped by `python -O`, so their updates are never moved out
+            Stmt::Assert { test, msg } => self.simple(&[], false, |p| {
+                let test = p.expr(test, prec::LAMBDA);
+                match msg {
+                    Some(m) => format!("assert {}, {}", test, p.expr(m, prec::LAMBDA)),
+                    None => format!("assert {}", test),
+                }
+            }),
+            Stmt::Raise { exc, cause } => self.simple(&[], false, |p| match (exc, cause) {
+                (Some(e), Some(c)) => format!(
+                    "raise {} from {}",
+                    p.expr(e, prec::LAMBDA),
+                    p.expr(c, prec::LAMBDA)
+                ),
+                (Some(e), None) => format!("raise {}", p.expr(e, prec::LAMBDA)),
+                (None, _) => "raise".to_string(),
+            }),
+            Stmt::Pass => self.line("pass"),
+            Stmt::Break => self.line("break"),
+            Stmt::Continue => {
+                for step in self.loop_steps.last().cloned().unwrap_or_default() {
+                    self.stmt(&step);
+                }
+                self.line("continue");
+            }
+        }
+        self.arrows.truncate(defined);
+    }
 
-                    if indent > 0 {
-                        indent -= 1;
-                        block_stack.pop();
-                    } else {
-                        out_lines.push("# ERROR: Too many '}'".to_string());
-                    }
+    /// Define each block-bodied arrow function in `stmt` as a `def` before
+    /// it, under a name no other part of the program uses
+    fn hoist_arrows(&mut self, stmt: &Stmt) {
+        let mut functions = Vec::new();
+        for e in stmt_exprs(stmt) {
+            walk(e, false, &mut |e, _| {
+                if let Expr::Function { .. } = e {
+                    functions.push(e);
                 }
+            });
+        }
+        for function in functions {
+            let Expr::Function { params, body, .. } = function else {
+                continue;
+            };
+            if self.arrows.iter().any(|(arrow, _)| arrow == function) {
+                continue;
             }
-            Token::Semicolon => {
-                let stmt = stmt_buf.trim().to_string();
-                stmt_buf.clear();
-                for s in stmt.split(';') {
-                    let s = s.trim();
-                    if !s.is_empty() {
-                        let s = if let Some(stripped) = s.strip_prefix("let ") {
-                            stripped
-                        } else {
-                            s
-                        };
-                        if header_needs_colon(s, &block_headers) {
-                            let mut line = s.to_string();
-                            if !line.ends_with(':') {
-                                line.push(':');
-                            }
-                            out_lines.push(format!("{}{}", "    ".repeat(indent), line));
+            let name = self.fresh_name("_arrow");
+            let def = FunctionDef {
+                decorators: Vec::new(),
+                is_async: false,
+                name: name.clone(),
+                params: params.clone(),
+                returns: None,
+                body: body.clone(),
+            };
+            self.stmt(&Stmt::FunctionDef(def));
+            self.arrows.push((function.clone(), name));
+        }
+    }
 
-                            // Track what kind of block we're entering
-                            if is_function_header(s) {
-                                block_stack.push("function");
-                            } else {
-                                block_stack.push("control");
-                            }
+    /// A C-style `for` becomes `for i in range(...)` when it is a simple
+    /// counting loop (see [`counter_range`]), and otherwise
+    /// `init; while test: body; step` with the step also run before `continue`
+    fn c_for(&mut self, c: &CFor) {
+        if let Some((var, args)) = counter_range(c, &self.scope) {
+            let header = self.header(&[], false, |p| {
+                let args: Vec<String> = args.iter().map(|a| p.expr(a, prec::LAMBDA)).collect();
+                format!("for {} in range({}):", var, args.join(", "))
+            });
+            self.line(header);
+            self.loop_body(&c.body, &[]);
+            return;
+        }
+        for init in &c.init {
+            self.stmt(init);
+        }
+        let header = match &c.test {
+            Some(test) => self.header(&[], false, |p| {
+                format!("while {}:", p.expr(test, prec::TUPLE))
+            }),
+            None => "while True:".to_string(),
+        };
+        self.line(header);
+        self.loop_body(&c.body, &c.step);
+    }
+}
 
-                            indent += 1;
-                        } else {
-                            out_lines.push(format!("{}{}", "    ".repeat(indent), s));
-                        }
-                    }
+impl Emitter {
+    /// A `switch` becomes a `match` when the target has one and every case
+    /// value is a literal or dotted name, which `match` compares with `==`.
+    /// Otherwise it becomes an `if`/`elif` chain on the subject, first
+    /// stored in a temporary unless it is a plain name.
+    fn switch(&mut self, s: &Switch) {
+        let values = s.cases.iter().flat_map(|c| &c.values);
+        if self.target.has_match() && !s.cases.is_empty() && values.clone().all(is_value_pattern) {
+            let header = self.header(&[&s.subject], true, |p| {
+                format!("match {}:", p.exprlist(&s.subject))
+            });
+            self.line(header);
+            self.indent += 1;
+            for case in &s.cases {
+                let header = self.header(&[], false, |p| {
+                    let patterns: Vec<String> =
+                        case.values.iter().map(|v| p.expr(v, prec::ATOM)).collect();
+                    format!("case {}:", patterns.join(" | "))
+                });
+                self.line(header);
+                self.body(&case.body);
+            }
+            if let Some(default) = &s.default {
+                self.line("case _:");
+                self.body(default);
+            }
+            self.indent -= 1;
+            return;
+        }
+
+        let mut subject = &s.subject;
+        while let Expr::Paren(inner) = subject {
+            subject = inner;
+        }
+        if s.cases.is_empty() {
+            self.simple(&[subject], true, |p| rhs(p, subject));
+            self.block(s.default.as_deref().unwrap_or_default());
+            return;
+        }
+        let subject = match subject {
+            Expr::Name(_) => subject.clone(),
+            _ => {
+                let temp = self.fresh_name("_switch");
+                self.simple(&[subject], true, |p| {
+                    format!("{} = {}", temp, rhs(p, subject))
+                });
+                Expr::Name(temp)
+            }
+        };
+        for (i, case) in s.cases.iter().enumerate() {
+            let test = match case.values.as_slice() {
+                [value] => (CmpOp::Eq, value.clone()),
+                values => (CmpOp::In, Expr::Tuple(values.to_vec())),
+            };
+            let test = Expr::Compare {
+                left: Box::new(subject.clone()),
+                ops: vec![test],
+            };
+            let keyword = if i == 0 { "if" } else { "elif" };
+            let header = self.header(&[], false, |p| {
+                format!("{} {}:", keyword, p.expr(&test, prec::TUPLE))
+            });
+            self.line(header);
+            self.body(&case.body);
+        }
+        if let Some(default) = &s.default {
+            self.line("else:");
+            self.body(default);
+        }
+    }
+}
+
+impl Emitter {
+    /// A `match` is printed as Python's own, so it needs a target that has one
+    fn match_stmt(&mut self, m: &Match) {
+        if !self.target.has_match() {
+            self.errors.push(
+                Diagnostic::error(
+                    codes::UNSUPPORTED_STATEMENT,
+                    format!(
+                        "`match` needs Python 3.10 or newer, but the target is {}",
+                        self.target
+                    ),
+                )
+                .with_primary(m.span, "Python's own `match` is needed here"),
+            );
+        }
+        // The parentheses NWPython needs around the subject are dropped
+        let mut subject = &m.subject;
+        while let Expr::Paren(inner) = subject {
+            subject = inner;
+        }
+        let header = self.header(&[subject], true, |p| {
+            format!("match {}:", p.exprlist(subject))
+        });
+        self.line(header);
+        self.indent += 1;
+        for case in &m.cases {
+            let header = self.header(&[], false, |p| {
+                let pattern = p.pattern(&case.pattern, false);
+                match &case.guard {
+                    Some(guard) => format!("case {} if {}:", pattern, p.expr(guard, prec::NAMED)),
+                    None => format!("case {}:", pattern),
                 }
+            });
+            self.line(header);
+            self.body(&case.body);
+        }
+        self.indent -= 1;
+    }
+}
+
+/// A case value that means the same as a `match` pattern: a number, a
+/// string that is not an f-string, `None`, or a dotted name such as
+/// `Color.RED`. Bare names would capture instead of compare, and `True`
+/// and `False` are compared with `is`, which `1 == True` would not be.
+fn is_value_pattern(e: &Expr) -> bool {
+    fn dotted(e: &Expr) -> bool {
+        match e {
+            Expr::Name(_) => true,
+            Expr::Attribute { value, .. } => dotted(value),
+            _ => false,
+        }
+    }
+    match e {
+        Expr::Number(_) => true,
+        Expr::UnaryOp {
+            op: UnaryOp::Neg,
+            operand,
+        } => matches!(operand.as_ref(), Expr::Number(_)),
+        Expr::Str(parts) => parts.iter().all(|p| {
+            !p.chars()
+                .take_while(|c| c.is_alphabetic())
+                .any(|c| c == 'f' || c == 'F')
+        }),
+        Expr::Name(n) => n == "None",
+        Expr::Attribute { value, .. } => dotted(value),
+        _ => false,
+    }
+}
+
+/// Recognize `for (i = start; i < stop; i += k)` (or counting down with `>`,
+/// `>=` and `-=`) and return the variable and the `range()` arguments.
+///
+/// The loop must not assign `i` or change anything `stop` depends on, and
+/// `i` must not be read anywhere else in `scope`, the enclosing function or
+/// module: a `range` loop leaves it one step short of where the C-style loop
+/// leaves it, or unassigned when it never runs.
+fn counter_range(c: &CFor, scope: &[Stmt]) -> Option<(String, Vec<Expr>)> {
+    let (var, start) = match c.init.as_slice() {
+        [Stmt::Assign(Assign { targets, value })] => match targets.as_slice() {
+            [Expr::Name(var)] => (var, value),
+            _ => return None,
+        },
+        [
+            Stmt::AnnAssign(AnnAssign {
+                target: Expr::Name(var),
+                value: Some(value),
+                ..
+            }),
+        ] => (var, value),
+        _ => return None,
+    };
+    let Some(Expr::Compare { left, ops }) = &c.test else {
+        return None;
+    };
+    let (Expr::Name(tested), [(cmp, stop)]) = (left.as_ref(), ops.as_slice()) else {
+        return None;
+    };
+    let [step] = c.step.as_slice() else {
+        return None;
+    };
+    let (up, step) = counter_step(step, var)?;
+    let inclusive = match (cmp, up) {
+        (CmpOp::Lt, true) | (CmpOp::Gt, false) => false,
+        (CmpOp::LtE, true) | (CmpOp::GtE, false) => true,
+        _ => return None,
+    };
+    if tested != var || !is_stable_bound(stop) {
+        return None;
+    }
+    let (assigned, mutated) = written_names(&c.body);
+    let mut stop_names = Vec::new();
+    walk(stop, false, &mut |e, _| {
+        if let Expr::Name(n) = e {
+            stop_names.push(n.as_str())
+        }
+    });
+    if assigned.contains(&var.as_str())
+        || stop_names
+            .iter()
+            .any(|n| assigned.contains(n) || mutated.contains(n))
+    {
+        return None;
+    }
+    let mut copies = 0;
+    walk_stmts(scope, &mut |s| {
+        copies += matches!(s, Stmt::CFor(other) if other == c) as usize
+    });
+    // With a copy of the loop elsewhere there is no telling which one leaves `i`
+    if copies != 1 || read_outside(scope, c, var) {
+        return None;
+    }
+
+    let stop = if inclusive {
+        offset(stop, if up { 1 } else { -1 })
+    } else {
+        stop.clone()
+    };
+    let mut args = Vec::new();
+    let counts_from_zero = matches!(start, Expr::Number(n) if n == "0");
+    if !(up && step == 1 && counts_from_zero) {
+        args.push(start.clone());
+    }
+    args.push(stop);
+    if !up {
+        args.push(Expr::Number(format!("-{}", step)));
+    } else if step != 1 {
+        args.push(Expr::Number(step.to_string()));
+    }
+    Some((var.clone(), args))
+}
+
+/// Whether `var` is read in `body` outside the loop `c`. Another C-style
+/// `for` that sets `var` anew in its init never sees the value `c` leaves,
+/// unless `c` is nested in it; a `global` or `nonlocal` declaration of `var`
+/// lets code outside `body` read it.
+fn read_outside(body: &[Stmt], c: &CFor, var: &str) -> bool {
+    body.iter().any(|stmt| match stmt {
+        Stmt::CFor(other) if other == c => false,
+        Stmt::CFor(other) if restarts(other, var) && !contains_loop(stmt, c) => false,
+        Stmt::Global(names) | Stmt::Nonlocal(names) => names.iter().any(|n| n == var),
+        _ => {
+            stmt_exprs(stmt).into_iter().any(|e| reads(e, c, var))
+                || stmt_blocks(stmt)
+                    .into_iter()
+                    .any(|b| read_outside(b, c, var))
+        }
+    })
+}
+
+/// Whether `e` reads `var`, counting the bodies of arrow functions, which
+/// may run at any later point
+fn reads(e: &Expr, c: &CFor, var: &str) -> bool {
+    let mut found = false;
+    walk(e, false, &mut |e, _| match e {
+        Expr::Name(n) => found |= n == var,
+        Expr::Function { body, .. } => found |= read_outside(body, c, var),
+        _ => {}
+    });
+    found
+}
+
+/// Whether the init of `c` assigns `var` without reading it first
+fn restarts(c: &CFor, var: &str) -> bool {
+    let mut assigned = false;
+    for init in &c.init {
+        let (target, value) = match init {
+            Stmt::Assign(Assign { targets, value }) => match targets.as_slice() {
+                [target] => (target, value),
+                _ => return false,
+            },
+            Stmt::AnnAssign(AnnAssign {
+                target,
+                value: Some(value),
+                ..
+            }) => (target, value),
+            _ => return false,
+        };
+        if reads(value, c, var) {
+            return assigned;
+        }
+        match target {
+            Expr::Name(n) if n == var => assigned = true,
+            _ if reads(target, c, var) => return assigned,
+            _ => {}
+        }
+    }
+    assigned
+}
+
+/// Whether `c` is `stmt` or nested in it
+fn contains_loop(stmt: &Stmt, c: &CFor) -> bool {
+    let mut found = false;
+    walk_stmts(std::slice::from_ref(stmt), &mut |s| {
+        found |= matches!(s, Stmt::CFor(other) if other == c)
+    });
+    found
+}
+
+/// `i++`, `i += k`, `i = i + k` and their decrementing forms, as
+/// (counts up, positive step size)
+fn counter_step(step: &Stmt, var: &str) -> Option<(bool, u64)> {
+    let is_var = |e: &Expr| matches!(e, Expr::Name(n) if n == var);
+    let literal = |e: &Expr| match e {
+        Expr::Number(n) => n.parse::<u64>().ok().filter(|k| *k > 0),
+        _ => None,
+    };
+    let (op, amount) = match step {
+        Stmt::Expr {
+            value: Expr::IncDec { op, target, .. },
+            ..
+        } if is_var(target) => (*op, 1),
+        Stmt::AugAssign(AugAssign { target, op, value }) if is_var(target) => match op {
+            BinOp::Add => (IncDecOp::Inc, literal(value)?),
+            BinOp::Sub => (IncDecOp::Dec, literal(value)?),
+            _ => return None,
+        },
+        Stmt::Assign(Assign { targets, value }) if targets.len() == 1 && is_var(&targets[0]) => {
+            match value {
+                Expr::BinOp {
+                    left,
+                    op: BinOp::Add,
+                    right,
+                } if is_var(left) => (IncDecOp::Inc, literal(right)?),
+                Expr::BinOp {
+                    left,
+                    op: BinOp::Sub,
+                    right,
+                } if is_var(left) => (IncDecOp::Dec, literal(right)?),
+                _ => return None,
             }
         }
-        i += 1;
+        _ => return None,
+    };
+    Some((op == IncDecOp::Inc, amount))
+}
+
+/// A loop bound that `range` can evaluate once up front: arithmetic on
+/// names and numbers, and `len(name)`
+fn is_stable_bound(e: &Expr) -> bool {
+    match e {
+        Expr::Name(_) | Expr::Number(_) => true,
+        Expr::Paren(inner) => is_stable_bound(inner),
+        Expr::UnaryOp {
+            op: UnaryOp::Neg,
+            operand,
+        } => is_stable_bound(operand),
+        Expr::BinOp {
+            left,
+            op: BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::FloorDiv,
+            right,
+        } => is_stable_bound(left) && is_stable_bound(right),
+        Expr::Call { func, args } => {
+            matches!(func.as_ref(), Expr::Name(f) if f == "len")
+                && matches!(args.as_slice(), [Arg::Positional(Expr::Name(_))])
+        }
+        _ => false,
     }
-    let tail = stmt_buf.trim();
-    if !tail.is_empty() {
-        for s in tail.split(';') {
-            let s = s.trim();
-            if !s.is_empty() {
-                let s = if let Some(stripped) = s.strip_prefix("let ") {
-                    stripped
-                } else {
-                    s
-                };
+}
 
-                if header_needs_colon(s, &block_headers) {
-                    let mut line = s.to_string();
-                    if !line.ends_with(':') {
-                        line.push(':');
-                    }
-                    out_lines.push(format!("{}{}", "    ".repeat(indent), line));
-                } else {
-                    out_lines.push(format!("{}{}", "    ".repeat(indent), s));
+/// Names rebound anywhere in `body`, and names of objects it may modify in
+/// place (passed to a call, a method called on them, or an item deleted)
+fn written_names(body: &[Stmt]) -> (Vec<&str>, Vec<&str>) {
+    let mut assigned = Vec::new();
+    let mut mutated = Vec::new();
+    walk_stmts(body, &mut |stmt| match stmt {
+        Stmt::Assign(a) => a
+            .targets
+            .iter()
+            .for_each(|t| target_names(t, &mut assigned)),
+        Stmt::AugAssign(AugAssign { target, .. }) | Stmt::AnnAssign(AnnAssign { target, .. }) => {
+            target_names(target, &mut assigned)
+        }
+        Stmt::For(f) => {
+            target_names(&f.target, &mut assigned);
+            if let Some(value) = &f.value {
+                target_names(value, &mut assigned);
+            }
+        }
+        Stmt::Del(targets) => {
+            for t in targets {
+                target_names(t, &mut assigned);
+                if let Expr::Subscript { value, .. } = t
+                    && let Expr::Name(n) = value.as_ref()
+                {
+                    mutated.push(n.as_str());
                 }
             }
         }
+        Stmt::FunctionDef(def) => assigned.push(def.name.as_str()),
+        Stmt::ClassDef(class) => assigned.push(class.name.as_str()),
+        Stmt::Try(t) => assigned.extend(t.handlers.iter().filter_map(|h| h.name.as_deref())),
+        Stmt::Match(m) => {
+            for case in &m.cases {
+                assigned.extend(case.pattern.bound_names());
+            }
+        }
+        Stmt::With(w) => {
+            for t in w.items.iter().filter_map(|i| i.target.as_ref()) {
+                target_names(t, &mut assigned);
+            }
+        }
+        Stmt::Import(names) => assigned.extend(names.iter().map(|a| {
+            a.asname
+                .as_deref()
+                .unwrap_or_else(|| a.name.split('.').next().unwrap_or(&a.name))
+        })),
+        Stmt::ImportFrom(i) => assigned.extend(
+            i.names
+                .iter()
+                .map(|a| a.asname.as_deref().unwrap_or(&a.name)),
+        ),
+        _ => {}
+    });
+    walk_body_exprs(body, &mut |e, _| match e {
+        Expr::NamedExpr { target, .. } => assigned.push(target.as_str()),
+        Expr::IncDec { target, .. } => target_names(target, &mut assigned),
+        Expr::Call { func, args } => {
+            if let Expr::Attribute { value, .. } = func.as_ref()
+                && let Expr::Name(n) = value.as_ref()
+            {
+                mutated.push(n.as_str());
+            }
+            for arg in args {
+                if let Arg::Positional(Expr::Name(n)) | Arg::Keyword(_, Expr::Name(n)) = arg {
+                    mutated.push(n.as_str());
+                }
+            }
+        }
+        _ => {}
+    });
+    (assigned, mutated)
+}
+
+/// Every name used or defined in `body`
+fn module_names(body: &[Stmt]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    walk_stmts(body, &mut |stmt| match stmt {
+        Stmt::FunctionDef(def) => {
+            names.insert(def.name.clone());
+            names.extend(def.params.iter().map(|p| p.name.clone()));
+        }
+        Stmt::ClassDef(class) => {
+            names.insert(class.name.clone());
+        }
+        Stmt::Try(t) => names.extend(t.handlers.iter().filter_map(|h| h.name.clone())),
+        Stmt::Match(m) => {
+            for case in &m.cases {
+                names.extend(case.pattern.bound_names().into_iter().map(String::from));
+            }
+        }
+        Stmt::Import(aliases) | Stmt::ImportFrom(ImportFrom { names: aliases, .. }) => {
+            for a in aliases {
+                names.insert(a.asname.clone().unwrap_or_else(|| a.name.clone()));
+            }
+        }
+        Stmt::Global(n) | Stmt::Nonlocal(n) => names.extend(n.iter().cloned()),
+        _ => {}
+    });
+    walk_body_exprs(body, &mut |e, _| match e {
+        Expr::Name(n) | Expr::NamedExpr { target: n, .. } => {
+            names.insert(n.clone());
+        }
+        Expr::Lambda { params, .. } => names.extend(params.iter().map(|p| p.name.clone())),
+        Expr::Function { params, body, .. } => {
+            names.extend(params.iter().map(|p| p.name.clone()));
+            names.extend(module_names(body));
+        }
+        _ => {}
+    });
+    names
+}
+
+/// Names bound by an assignment target
+fn target_names<'e>(target: &'e Expr, out: &mut Vec<&'e str>) {
+    match target {
+        Expr::Name(n) => out.push(n),
+        Expr::Paren(inner) | Expr::Starred(inner) => target_names(inner, out),
+        Expr::Tuple(items) | Expr::List(items) => {
+            items.iter().for_each(|i| target_names(i, out));
+        }
+        _ => {}
+    }
+}
+
+/// Right-hand side of an assignment or an expression statement, where a
+/// bare tuple or `yield` needs no parentheses
+fn rhs(p: &mut ExprPrinter, e: &Expr) -> String {
+    match e {
+        Expr::Yield(_) | Expr::YieldFrom(_) => p.expr(e, prec::NAMED),
+        _ => p.exprlist(e),
+    }
+}
+
+fn aliases(names: &[Alias]) -> String {
+    names
+        .iter()
+        .map(|a| match &a.asname {
+            Some(asname) => format!("{} as {}", a.name, asname),
+            None => a.name.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Indent;
+    use nwparser::tokenize_spanned;
+
+    fn py(src: &str) -> String {
+        transpile(&tokenize_spanned(src)).unwrap()
+    }
+
+    #[test]
+    fn test_readme_example() {
+        let src = "// Find factorial using recursion\ndef factorial(n: int) {\n    if (n == 0) {\n        return 1;\n    }\n    return n * factorial(n - 1);\n}\n/* Multi-line comment\n   for demonstration */\ndef main() {\n    i = 10;\n    print(i++);\n    print(++i);\n}\n";
+        assert_eq!(
+            py(src),
+            "# Find factorial using recursion\ndef factorial(n: int):\n    if (n == 0):\n        return 1\n    return n * factorial(n - 1)\n\"\"\"Multi-line comment\n   for demonstration\"\"\"\ndef main():\n    i = 10\n    print(i)\n    i += 1\n    i += 1\n    print(i)\n"
+        );
+    }
+
+    #[test]
+    fn test_expression_bodies() {
+        let src = "def sq(x) => x * x;\nclass P {\n    def norm(self) -> float => abs(self.x)\n}\nasync def load() => await fetch(), 1;\n";
+        assert_eq!(
+            py(src),
+            "def sq(x):\n    return x * x\nclass P:\n    def norm(self) -> float:\n        return abs(self.x)\nasync def load():\n    return await fetch(), 1\n"
+        );
+        assert_eq!(run("def sq(x) => x * x;\nprint(sq(7));"), "49\n");
+    }
+
+    #[test]
+    fn test_implicit_return() {
+        let implicit = |src: &str| {
+            let options = TranspileOptions {
+                implicit_return: true,
+                ..TranspileOptions::default()
+            };
+            transpile_with(&tokenize_spanned(src), &options).unwrap()
+        };
+        let src = "def f(x) { d = {\"a\": {\"b\": x}}; d[\"a\"] // last\n}";
+        assert_eq!(
+            implicit(src),
+            "def f(x):\n    d = {\"a\": {\"b\": x}}\n    # last\n    return d[\"a\"]\n"
+        );
+        // Off by default
+        assert_eq!(
+            py(src),
+            "def f(x):\n    d = {\"a\": {\"b\": x}}\n    # last\n    d[\"a\"]\n"
+        );
+        // Only a function's own last statement, and only an expression
+        assert_eq!(implicit("def f() { x = 1 }"), "def f():\n    x = 1\n");
+        assert_eq!(
+            implicit("def f() { log(\"x\"); }"),
+            "def f():\n    log(\"x\")\n"
+        );
+        assert_eq!(implicit("def f(n) { n++ }"), "def f(n):\n    n += 1\n");
+        assert_eq!(implicit("def f() { yield 1 }"), "def f():\n    yield 1\n");
+        assert_eq!(
+            implicit("def f(xs) { for x in xs { g(x) } }"),
+            "def f(xs):\n    for x in xs:\n        g(x)\n"
+        );
+        assert_eq!(
+            implicit("def f(x) { if (x) { g() } else { h() } }"),
+            "def f(x):\n    if (x):\n        g()\n    else:\n        h()\n"
+        );
+        assert_eq!(implicit("with (lock) { g() }"), "with lock:\n    g()\n");
+    }
+
+    /// Run the transpiled program with `python3` and return what it printed
+    fn run(src: &str) -> String {
+        run_python(&py(src))
+    }
+
+    fn run_python(code: &str) -> String {
+        let out = std::process::Command::new("python3")
+            .arg("-c")
+            .arg(code)
+            .output()
+            .expect("python3 is needed to run transpiled programs");
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_incdec_lowering() {
+        assert_eq!(py("arr[i++] = v;"), "arr[i] = v\ni += 1\n");
+        assert_eq!(
+            py("while (++i < 10) { f(a, n--, n); }"),
+            "while ((i := i + 1) < 10):\n    f(a, (n, (n := n - 1))[0], n)\n"
+        );
+    }
+
+    #[test]
+    fn test_incdec_runs_like_c() {
+        let src = "arr = [0, 0, 0]; i = 0;\n\
+                   arr[i++] = 5; arr[i++] = 6;\n\
+                   print(arr, i);\n\
+                   def pair(a, b) { return a * 10 + b; }\n\
+                   n = 3; print(pair(n, n--), n);\n\
+                   j = 0; while (++j < 4) { print(j); }\n\
+                   def post(x) { return x++; }\n\
+                   print(post(4));\n\
+                   k = 0; t = k++ + k++; print(k, t);\n\
+                   c = 0; ys = [c++ for _ in range(3)]; print(ys, c);\n\
+                   g = lambda x: x++ + x; print(g(1));\n\
+                   m = 0; s = False and m++; print(m, s);\n";
+        assert_eq!(
+            run(src),
+            "[5, 6, 0] 2\n33 2\n1\n2\n3\n4\n2 1\n[0, 1, 2] 3\n3\n0 False\n"
+        );
+    }
+
+    #[test]
+    fn test_incdec_errors() {
+        let messages = |src: &str| -> Vec<String> {
+            transpile(&tokenize_spanned(src))
+                .unwrap_err()
+                .into_iter()
+                .map(|d| d.message)
+                .collect()
+        };
+        assert_eq!(
+            messages("while (o.n++ < 3) { }"),
+            vec![
+                "cannot update `o.n` inside this expression; an attribute or subscript can only be \
+                 incremented as the one update of a simple statement"
+            ]
+        );
+        assert_eq!(
+            messages("f = lambda: n++;"),
+            vec!["cannot update `n` inside a lambda that does not take it as a parameter"]
+        );
+    }
+
+    #[test]
+    fn test_lowering_errors_point_at_the_source() {
+        let src = "x = 1;\nwhile (o.n++ < 3) { }";
+        let errors = transpile(&tokenize_spanned(src)).unwrap_err();
+        assert_eq!(
+            errors[0].render(src, "main.nwpy"),
+            "error[NW0009]: cannot update `o.n` inside this expression; an attribute or subscript \
+             can only be incremented as the one update of a simple statement\n \
+             --> main.nwpy:2:8\n  \
+             |\n\
+             2 | while (o.n++ < 3) { }\n  \
+             |        ^^^^^ not the one update of a simple statement\n"
+        );
+    }
+
+    #[test]
+    fn test_c_for_counter_becomes_range() {
+        assert_eq!(
+            py("for (let i = 0; i < n; i++) { print(i); }"),
+            "for i in range(n):\n    print(i)\n"
+        );
+        assert_eq!(
+            py("for (i = 10; i >= 0; i -= 2) { }"),
+            "for i in range(10, -1, -2):\n    pass\n"
+        );
+        assert_eq!(
+            py("for (i = 1; i <= len(xs); i = i + 1) { print(xs[i - 1]); }"),
+            "for i in range(1, len(xs) + 1):\n    print(xs[i - 1])\n"
+        );
+    }
+
+    #[test]
+    fn test_c_for_falls_back_to_while() {
+        // `i` is read after the loop, so it must end up at 10 as in C
+        assert_eq!(
+            py(
+                "for (i = 0; i < 10; i++) {\n    if (i % 2 == 0) { continue; }\n    print(i);\n}\nprint(i);"
+            ),
+            "i = 0\nwhile i < 10:\n    if (i % 2 == 0):\n        i += 1\n        continue\n    print(i)\n    i += 1\nprint(i)\n"
+        );
+        assert_eq!(py("for (;;) { break; }"), "while True:\n    break\n");
+    }
+
+    #[test]
+    fn test_c_for_runs_like_c() {
+        let src = "for (i = 0; i < 3; i++) { print(i); }\n\
+                   for (j = 0; j < 6; j++) {\n\
+                       for (k = 0; k < 2; k++) { if (k == 1) { continue; } print(j, k); }\n\
+                       if (j % 2 == 0) { continue; }\n\
+                       if (j == 5) { break; }\n\
+                       print(\"odd\", j);\n\
+                   }\n\
+                   print(j);\n\
+                   xs = [1, 2, 3];\n\
+                   for (n = 0; n < len(xs); n++) { xs.pop(); }\n\
+                   print(xs);\n";
+        assert_eq!(
+            run(src),
+            "0\n1\n2\n0 0\n1 0\nodd 1\n2 0\n3 0\nodd 3\n4 0\n5 0\n5\n[1]\n"
+        );
+    }
+
+    #[test]
+    fn test_c_for_counter_read_elsewhere() {
+        // Read after the `if` holding the loop, or by the next pass of an
+        // outer loop: either way it must be where C leaves it
+        let src = "def f(flag) { if (flag) { for (i = 0; i < 3; i++) { pass; } } print(i); }\n\
+                   f(True);\n\
+                   n = 0;\n\
+                   while (n < 2) { if (n > 0) { print(j); } for (j = 0; j < 3; j++) {} n++; }\n";
+        assert!(!py(src).contains("range("), "{}", py(src));
+        assert_eq!(run(src), "3\n3\n");
+        // A later loop that starts the counter anew does not read it
+        assert_eq!(
+            py("for (k = 0; k < 3; k++) {}\nfor (k = 0; k < 2; k++) { print(k); }"),
+            "for k in range(3):\n    pass\nfor k in range(2):\n    print(k)\n"
+        );
+    }
+
+    #[test]
+    fn test_c_for_comma_lists() {
+        // Each item of a comma-separated init or step is its own statement
+        let src = "for (i = 0, j = 5; i < j; i++, j--) {\n\
+                       if (i == 1) { continue; }\n\
+                       print(i, j);\n\
+                   }\n";
+        assert_eq!(
+            py(src),
+            "i = 0\nj = 5\nwhile i < j:\n    if (i == 1):\n        i += 1\n        j -= 1\n        continue\n    print(i, j)\n    i += 1\n    j -= 1\n"
+        );
+        assert_eq!(run(src), "0 5\n2 3\n");
+    }
+
+    #[test]
+    fn test_c_style_logic() {
+        assert_eq!(
+            py("if (a && !b || c) { x = null; } flag = !done == false;"),
+            "if (a and not b or c):\n    x = None\nflag = (not done) == False\n"
+        );
+        // Only names standing for a value are literals
+        assert_eq!(
+            py(
+                "import true;\nfrom m import null;\ndef false(null, true=1) { }\nx = cfg.null;\nf(true=1, null=true);"
+            ),
+            "import true\nfrom m import null\ndef false(null, true=1):\n    pass\nx = cfg.null\nf(true=1, null=True)\n"
+        );
+        assert!(py("match (x) { case null | true => { } }").contains("case None | True:"));
+    }
+
+    #[test]
+    fn test_ternary() {
+        assert_eq!(py("x = n > 0 ? n : -n;"), "x = n if n > 0 else -n\n");
+        assert_eq!(
+            py("s = a ? b ? 1 : 2 : c ? 3 : 4;"),
+            "s = (1 if b else 2) if a else 3 if c else 4\n"
+        );
+        assert_eq!(
+            py("y = (a || b ? f : g)(x) + (k ? 1 : 0);"),
+            "y = (f if a or b else g)(x) + (1 if k else 0)\n"
+        );
+    }
+
+    #[test]
+    fn test_floor_division() {
+        assert_eq!(
+            py("half = n ~/ 2; // halve\nn ~/= 3;"),
+            "half = n // 2\n# halve\nn //= 3\n"
+        );
+    }
+
+    #[test]
+    fn test_classes() {
+        let src = "class Shape { }\n@total_ordering\nclass Rect(Shape, metaclass=ABCMeta) {\n    sides = 4;\n    def __init__(self, w, h) { self.w = w; self.h = h; }\n    @property\n    def area(self) => self.w * self.h;\n}\n";
+        assert_eq!(
+            py(src),
+            "class Shape:\n    pass\n@total_ordering\nclass Rect(Shape, metaclass=ABCMeta):\n    sides = 4\n    def __init__(self, w, h):\n        self.w = w\n        self.h = h\n    @property\n    def area(self):\n        return self.w * self.h\n"
+        );
+        let src = "class Counter {\n    def __init__(self) { self.n = 0; }\n    def tick(self) { self.n++; return self; }\n}\nprint(Counter().tick().tick().n);";
+        assert_eq!(run(src), "2\n");
+    }
+
+    #[test]
+    fn test_with_and_async() {
+        let src = "with (open(p) as f, lock) { f.read() }\nasync def main() {\n    async with (session() as s) { }\n    async for (x in feed(await s.get())) { print(-await x ** 2); }\n    return [y async for y in ys];\n}\n";
+        assert_eq!(
+            py(src),
+            "with open(p) as f, lock:\n    f.read()\nasync def main():\n    async with session() as s:\n        pass\n    async for x in feed(await s.get()):\n        print(-await x ** 2)\n    return [y async for y in ys]\n"
+        );
+        let src = "import asyncio;\nasync def twice(x) { await asyncio.sleep(0); return x * 2; }\nasync def main() { with (open(\"/dev/null\") as f) { print(await twice(len(f.read()) + 2)); } }\nasyncio.run(main());";
+        assert_eq!(run(src), "4\n");
+    }
+
+    #[test]
+    fn test_try() {
+        let src = "def load(s) {\n    try { n = int(s); }\n    except (ValueError as e) { raise LookupError(s) from e; }\n    except (TypeError, KeyError) { return None; }\n    else { return n; }\n    finally { print(\"done\"); }\n}\n";
+        assert_eq!(
+            py(src),
+            "def load(s):\n    try:\n        n = int(s)\n    except ValueError as e:\n        raise LookupError(s) from e\n    except (TypeError, KeyError):\n        return None\n    else:\n        return n\n    finally:\n        print(\"done\")\n"
+        );
+        let src = format!(
+            "{}print(load(\"4\"));\ntry {{ load(\"x\"); }} except (LookupError as e) {{ print(type(e.__cause__).__name__); }}",
+            src
+        );
+        assert_eq!(run(&src), "done\n4\ndone\nValueError\n");
+    }
+
+    #[test]
+    fn test_do_while() {
+        assert_eq!(
+            py("do { x = f(); } while (x);"),
+            "while True:\n    x = f()\n    if not (x):\n        break\n"
+        );
+        // `continue` still runs the test, and the body runs before the first test
+        let src = "i = 0;\ndo {\n    i++;\n    if (i % 2 == 0) { continue; }\n    print(i);\n} while (i < 6);\ndo { print(\"once\"); } while (false);";
+        assert_eq!(run(src), "1\n3\n5\nonce\n");
+    }
+
+    const SWITCH: &str = "def size(n) {\n    switch (n) {\n        case 0: return \"none\";\n        case 1, 2: return \"few\";\n        default: return \"many\";\n    }\n}\n";
+
+    #[test]
+    fn test_switch_becomes_match() {
+        assert_eq!(
+            py(SWITCH),
+            "def size(n):\n    match (n):\n        case 0:\n            return \"none\"\n        case 1 | 2:\n            return \"few\"\n        case _:\n            return \"many\"\n"
+        );
+        // A bare name in a pattern would capture, so this needs comparisons
+        assert_eq!(
+            py("switch (f()) { case limit: g(); break; case -1: }"),
+            "_switch = f()\nif _switch == limit:\n    g()\nelif _switch == -1:\n    pass\n"
+        );
+    }
+
+    fn py39() -> TranspileOptions {
+        TranspileOptions {
+            target: PythonVersion::new(3, 9),
+            ..TranspileOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_switch_before_python_3_10() {
+        let tokens = tokenize_spanned(SWITCH);
+        assert_eq!(
+            transpile_with(&tokens, &py39()).unwrap(),
+            "def size(n):\n    if n == 0:\n        return \"none\"\n    elif n in (1, 2):\n        return \"few\"\n    else:\n        return \"many\"\n"
+        );
+        // Temporaries never shadow a name from the program
+        let src = "_switch = 1; switch (g()) { case 1: switch (h()) { case 2: x = 3; } }";
+        let tokens = tokenize_spanned(src);
+        assert_eq!(
+            transpile_with(&tokens, &py39()).unwrap(),
+            "_switch = 1\n_switch2 = g()\nif _switch2 == 1:\n    _switch3 = h()\n    if _switch3 == 2:\n        x = 3\n"
+        );
+    }
+
+    #[test]
+    fn test_switch_runs_the_matching_case() {
+        let src = format!(
+            "{}for (i = 0; i < 4; i++) {{ print(size(i)); }}\nswitch (\"b\") {{ case \"a\": print(1); break; default: case \"c\": print(2); }}",
+            SWITCH
+        );
+        assert_eq!(run(&src), "none\nfew\nfew\nmany\n2\n");
+        let old = transpile_with(&tokenize_spanned(&src), &py39()).unwrap();
+        assert!(!old.contains("match"));
+        assert_eq!(run_python(&old), "none\nfew\nfew\nmany\n2\n");
+    }
+
+    #[test]
+    fn test_switch_in_a_loop() {
+        // A `break` inside `with` would leave the loop, not the switch
+        let src = "for (i in [1, 2]) {\n\
+                   switch (i) { case 1: with (open(p)) { break; } print(\"in\"); break; default: print(\"d\"); }\n\
+                   print(\"after\", i);\n\
+                   }";
+        let errors = crate::transpile(&tokenize_spanned(src)).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::INVALID_SWITCH);
+        let src = "import contextlib;\n\
+                   for (i in [1, 2]) {\n\
+                   switch (i) { case 1: with (contextlib.nullcontext()) { print(\"in\"); } break; default: print(\"d\"); }\n\
+                   print(\"after\", i);\n\
+                   }";
+        assert_eq!(run(src), "in\nafter 1\nd\nafter 2\n");
+    }
+
+    const MATCH: &str = "def describe(shape) {\n    match (shape) {\n        case Point(x=0, y=0) => { return \"origin\"; }\n        case Point(x=0, y) | Point(x=y, y=0) => { return f\"axis {y}\"; }\n        case [a, *rest] if a > 0 => { return f\"positive then {len(rest)}\"; }\n        case {\"kind\": \"circle\", \"r\": (int() | float()) as r} => { return f\"circle {r}\"; }\n        case -1 | None => { return \"nothing\"; }\n        case other => { return f\"unknown {other!r}\"; }\n    }\n}\n";
+
+    #[test]
+    fn test_match() {
+        assert_eq!(
+            py(MATCH),
+            "def describe(shape):\n    match shape:\n        case Point(x=0, y=0):\n            return \"origin\"\n        case Point(x=0, y=y) | Point(x=y, y=0):\n            return f\"axis {y}\"\n        case [a, *rest] if a > 0:\n            return f\"positive then {len(rest)}\"\n        case {\"kind\": \"circle\", \"r\": (int() | float()) as r}:\n            return f\"circle {r}\"\n        case -1 | None:\n            return \"nothing\"\n        case other:\n            return f\"unknown {other!r}\"\n"
+        );
+        assert_eq!(
+            py(
+                "match (a, b) { case 1, *_ => { } case (x, y) if (z := x + y) > 2 => { print(z); } }"
+            ),
+            "match a, b:\n    case [1, *_]:\n        pass\n    case [x, y] if (z := x + y) > 2:\n        print(z)\n"
+        );
+        let tokens = tokenize_spanned(MATCH);
+        let errors = transpile_with(&tokens, &py39()).unwrap_err();
+        assert_eq!(errors[0].code, codes::UNSUPPORTED_STATEMENT);
+        assert!(
+            errors[0].message.contains("but the target is 3.9"),
+            "{}",
+            errors[0].message
+        );
+    }
+
+    #[test]
+    fn test_match_runs_the_matching_case() {
+        let src = format!(
+            "from dataclasses import dataclass;\n@dataclass\nclass Point {{ x: int; y: int; }}\n{}for (s in [Point(0, 0), Point(0, 5), Point(3, 0), [2, 7, 8], [-2], {{\"kind\": \"circle\", \"r\": 1.5}}, -1, None, \"x\"]) {{ print(describe(s)); }}\nfor (i in range(3)) {{ match (i) {{ case 1 => {{ break; }} case _ => {{ print(i); }} }} }}",
+            MATCH
+        );
+        assert_eq!(
+            run(&src),
+            "origin\naxis 5\naxis 3\npositive then 2\nunknown [-2]\ncircle 1.5\nnothing\nnothing\nunknown 'x'\n0\n"
+        );
+    }
+
+    #[test]
+    fn test_arrow_functions() {
+        assert_eq!(
+            py("add = (x, y=1) => x + y;\nf(x => (y) => x * y, () => ({\"k\": 1}));"),
+            "add = lambda x, y=1: x + y\nf(lambda x: lambda y: x * y, lambda: ({\"k\": 1}))\n"
+        );
+        // Block bodies become a `def` before the statement, named so they
+        // shadow nothing
+        assert_eq!(
+            py(
+                "_arrow = 0;\nif (ok) { } elif (any(map((x) => { y = x * 2; return y > 3; }, xs))) { }"
+            ),
+            "_arrow = 0\ndef _arrow2(x):\n    y = x * 2\n    return y > 3\nif (ok):\n    pass\nelif (any(map(_arrow2, xs))):\n    pass\n"
+        );
+        let src = "def apply(xs) {\n    return list(map((x) => {\n        if (x < 0) { return -x; }\n        return list(map((y) => { return x * y; }, [1, 2]));\n    }, xs));\n}\nprint(apply([-3, 2]));\nmatch (5) { case n if ((k) => { return k > 1; })(n) => { print(n); } }";
+        assert_eq!(run(src), "[3, [2, 4]]\n5\n");
+        let errors = transpile(&tokenize_spanned(
+            "ys = [(() => { return x; })() for x in xs];",
+        ))
+        .unwrap_err();
+        assert_eq!(errors[0].code, codes::UNSUPPORTED_ARROW);
+        assert!(
+            errors[0].message.contains("uses `x`"),
+            "{}",
+            errors[0].message
+        );
+    }
+
+    #[test]
+    fn test_dict_and_set_literals() {
+        let src = "d = {\"a\": {\"b\": [1, {2}]}, \"c\": {}};\ndef keys(m) { return {k for k in m}; }\ndef pairs(m) => {k: v * 2 for (k, v) in m.items()};\ndef tail(xs) { return xs[1:] }\nif d[\"c\"] == {} { print(sorted(keys(d)), pairs({\"x\": 1}), tail([1, 2, 3])); }\nfor k in {\"z\": 0} { print({**d, k: {0}}[k]); }\ntable = {\n    \"rows\": [\n        {\"id\": 1},\n    ],\n};\nprint(len(table[\"rows\"]), {1, 1, 2});\n";
+        assert_eq!(run(src), "['a', 'c'] {'x': 2} [2, 3]\n{0}\n1 {1, 2}\n");
+    }
+
+    #[test]
+    fn test_key_value_for() {
+        assert_eq!(
+            py(
+                "for (k: v in d) { print(k, v); }\nfor name: (x, y) in points { }\nfor (k: v in a | b) { }"
+            ),
+            "for k, v in d.items():\n    print(k, v)\nfor name, (x, y) in points.items():\n    pass\nfor k, v in (a | b).items():\n    pass\n"
+        );
+        // Method calls in the source are never rewritten
+        assert_eq!(
+            py("for (v in d.values()) { }\nfor k, v in d.values { }"),
+            "for v in d.values():\n    pass\nfor k, v in d.values:\n    pass\n"
+        );
+        assert_eq!(
+            run(
+                "d = {\"a\": 1, \"b\": 2};\nfor (k: v in d) { print(k, v); }\nfor (v in d.values()) { print(v); }"
+            ),
+            "a 1\nb 2\n1\n2\n"
+        );
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(
+            py(
+                "for (i in 0..n) { }\nfor (i in 1..=n step 2) { }\nfor (i in n..=0 step -1) { }\nok = x in a..b + 1;"
+            ),
+            "for i in range(n):\n    pass\nfor i in range(1, n + 1, 2):\n    pass\nfor i in range(n, -1, -1):\n    pass\nok = x in range(a, b + 1)\n"
+        );
+        // A range index is a slice; `..=-1` runs to the end
+        assert_eq!(
+            py(
+                "a = xs[1..3];\nb = xs[i..=j];\nc = xs[1..=-1];\nd = xs[5..=0 step -1];\ne = m[0..2, 1..=2];"
+            ),
+            "a = xs[1:3]\nb = xs[i:j + 1]\nc = xs[1:]\nd = xs[5::-1]\ne = m[0:2, 1:3]\n"
+        );
+        assert_eq!(
+            run(
+                "xs = [0, 1, 2, 3, 4];\nprint(list(2..5), list(1..=7 step 3), list(3..=1 step -1));\nprint(xs[1..3], xs[2..=-1], xs[3..=0 step -1], 4 in 0..=4);"
+            ),
+            "[2, 3, 4] [1, 4, 7] [3, 2, 1]\n[1, 2] [2, 3, 4] [3, 2, 1, 0] True\n"
+        );
+    }
+
+    #[test]
+    fn test_null_coalescing_and_optional_chaining() {
+        assert_eq!(
+            py("a = x ?? 0;\nb = f() ?? g() ?? 3;\nc = (x ?? 1) + 2;"),
+            "a = x if x is not None else 0\nb = _v if (_v := f()) is not None else _v if (_v := g()) is not None else 3\nc = (x if x is not None else 1) + 2\n"
+        );
+        assert_eq!(
+            py("c = obj?.attr?.method();\nd = load()?[\"k\"] ?? {};\ne = obj?.n + 1;"),
+            "c = None if obj is None else None if (_v := obj.attr) is None else _v.method()\nd = _v if (_v := None if (_v := load()) is None else _v[\"k\"]) is not None else {}\ne = (None if obj is None else obj.n) + 1\n"
+        );
+        // The temporary never shadows a name of the program
+        assert!(py("_v = 1;\nx = f() ?? _v;").contains("_v2 if (_v2 := f()) is not None else _v"));
+        let errors =
+            crate::transpile(&tokenize_spanned("ys = [y for y in f() ?? []];")).unwrap_err();
+        assert_eq!(errors[0].code, codes::INVALID_OPTIONAL);
+    }
+
+    #[test]
+    fn test_null_safe_operators_short_circuit() {
+        let src = "calls = [];\n\
+                   def f(v) { calls.append(v); return v; }\n\
+                   d = {\"a\": {\"b\": None}, \"n\": 0};\n\
+                   print(f(None) ?? f(0) ?? f(1), f(False) ?? f(2));\n\
+                   print(f(None)?.x.y(f(3)), f(d)?[\"a\"]?[\"b\"]?.z, f(d)?[\"n\"]?.real);\n\
+                   print(f(\"s\")?.upper() ?? \"none\", calls);\n";
+        assert_eq!(
+            run(src),
+            "0 False\nNone None 0\nS [None, 0, False, None, {'a': {'b': None}, 'n': 0}, {'a': {'b': None}, 'n': 0}, 's']\n"
+        );
+    }
+
+    #[test]
+    fn test_pipelines() {
+        // A stage without `_` is called with the value; otherwise `_` stands for it
+        assert_eq!(
+            py(
+                "r = xs |> map(g, _) |> filter(f, _) |> sorted;\ns = a + b |> _.strip() |> len;\nt = xs |> ((x) => x * 2);"
+            ),
+            "r = sorted(filter(f, map(g, xs)))\ns = len((a + b).strip())\nt = (lambda x: x * 2)(xs)\n"
+        );
+        // `_` in a lambda or in a nested pipeline's stage is not this stage's
+        assert_eq!(
+            py(
+                "u = xs |> [y * 2 for y in _] |> sum;\nv = xs |> f(_, lambda _: 0);\nw = ys |> h(_ |> f(1, _));\nq = (x |> _ + 1) * 2;"
+            ),
+            "u = sum([y * 2 for y in xs])\nv = f(xs, lambda _: 0)\nw = h(f(1, ys))\nq = (x + 1) * 2\n"
+        );
+        assert_eq!(
+            run(
+                "words = [\" b \", \"a\", \" c\"];\nprint(words |> map((w) => w.strip(), _) |> sorted |> \", \".join(_));\nprint(3 |> _ * 2 + 1 |> range |> list);"
+            ),
+            "a, b, c\n[0, 1, 2, 3, 4, 5, 6]\n"
+        );
+        let errors = crate::transpile(&tokenize_spanned(
+            "a = xs |> f(_, _);\nb = xs |> (c ? _ : 0);\nd = xs |> [y for y in ys if _];",
+        ))
+        .unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|e| e.code == codes::INVALID_PIPELINE));
+        assert!(errors[0].message.ends_with("uses `_` 2 times"));
+    }
+
+    #[test]
+    fn test_transpile_options() {
+        let with = |src: &str, options: TranspileOptions| {
+            transpile_with(&tokenize_spanned(src), &options).unwrap()
+        };
+        let src = "/* two\n   lines */\ndef f(x) {\n    // check\n    if (x) { g(); }\n}\n";
+        assert_eq!(
+            with(
+                src,
+                TranspileOptions {
+                    indent: Indent::Tab,
+                    comments: CommentStyle::Hash,
+                    banner: true,
+                    ..TranspileOptions::default()
+                }
+            ),
+            format!(
+                "{}\n# two\n# lines\ndef f(x):\n\t# check\n\tif (x):\n\t\tg()\n",
+                BANNER
+            )
+        );
+        // A block left with only comments still gets a `pass`
+        assert_eq!(
+            with(
+                "/* header */\ndef f() { // todo\n}\n",
+                TranspileOptions {
+                    indent: Indent::Spaces(2),
+                    comments: CommentStyle::Strip,
+                    ..TranspileOptions::default()
+                }
+            ),
+            "def f():\n  pass\n"
+        );
+        let lossy = TranspileOptions {
+            strictness: Strictness::Lossy,
+            ..TranspileOptions::default()
+        };
+        assert_eq!(
+            with("x = ;\ny = 1;", lossy),
+            "# ERROR: 1:5: error[NW0003]: expected an expression, found `;`\ny = 1\n"
+        );
+    }
+
+    #[test]
+    fn test_errors_instead_of_broken_output() {
+        let codes_of = |src: &str| -> Vec<&str> {
+            transpile(&tokenize_spanned(src))
+                .unwrap_err()
+                .iter()
+                .map(|d| d.code)
+                .collect()
+        };
+        assert_eq!(codes_of("x = 1;\n}"), vec![codes::UNMATCHED_BRACE]);
+        assert_eq!(
+            codes_of("def f() {\n  x = 1;\n"),
+            vec![codes::UNCLOSED_BLOCK]
+        );
+        assert_eq!(
+            codes_of("x = 1;\n/* open"),
+            vec![codes::UNTERMINATED_COMMENT]
+        );
+        assert_eq!(codes_of("print(\"hi);"), vec![codes::UNTERMINATED_STRING]);
+    }
+
+    #[test]
+    fn test_lossy_keeps_good_statements() {
+        let out = transpile_lossy(&tokenize_spanned("x = 1;\n}\ny = 2;"));
+        assert_eq!(
+            out,
+            "# ERROR: 2:1: error[NW0005]: unmatched `}` with no open block\nx = 1\ny = 2\n"
+        );
     }
-    out_lines.join("\n") + "\n"
 }
@@ -0,0 +1,303 @@
+//! Walking the syntax tree
+
+use nwparser::ast::*;
+
+/// Call `f` on `e` and every expression inside it. The flag passed to `f` is
+/// set when evaluating the expression depends on a condition (the right side
+/// of `and`/`or`/`??`, the branches of a conditional expression, the rest of
+/// an optional chain, later operands of a chained comparison) or happens in a
+/// nested scope (lambda bodies and comprehensions).
+pub(crate) fn walk<'e>(e: &'e Expr, conditional: bool, f: &mut dyn FnMut(&'e Expr, bool)) {
+    f(e, conditional);
+    let c = conditional;
+    match e {
+        Expr::Name(_) | Expr::Number(_) | Expr::Str(_) | Expr::Ellipsis => {}
+        Expr::Paren(inner)
+        | Expr::Optional { value: inner, .. }
+        | Expr::Starred(inner)
+        | Expr::YieldFrom(inner)
+        | Expr::Await(inner)
+        | Expr::Yield(Some(inner))
+        | Expr::Attribute { value: inner, .. }
+        | Expr::UnaryOp { operand: inner, .. }
+        | Expr::NamedExpr { value: inner, .. }
+        | Expr::IncDec { target: inner, .. } => walk(inner, c, f),
+        Expr::Yield(None) => {}
+        Expr::Tuple(items) | Expr::List(items) | Expr::Set(items) => {
+            for item in items {
+                walk(item, c, f);
+            }
+        }
+        Expr::Dict(items) => {
+            for item in items {
+                match item {
+                    DictItem::Pair(k, v) => {
+                        walk(k, c, f);
+                        walk(v, c, f);
+                    }
+                    DictItem::Unpack(m) => walk(m, c, f),
+                }
+            }
+        }
+        Expr::Subscript { value, index } => {
+            walk(value, c, f);
+            walk(index, c || value.is_optional_chain(), f);
+        }
+        Expr::Range {
+            start, stop, step, ..
+        } => {
+            walk(start, c, f);
+            walk(stop, c, f);
+            if let Some(step) = step {
+                walk(step, c, f);
+            }
+        }
+        Expr::Slice { lower, upper, step } => {
+            for part in [lower, upper, step].into_iter().flatten() {
+                walk(part, c, f);
+            }
+        }
+        Expr::Call { func, args } => {
+            walk(func, c, f);
+            let c = c || func.is_optional_chain();
+            for arg in args {
+                match arg {
+                    Arg::Positional(e) | Arg::Keyword(_, e) | Arg::Star(e) | Arg::DoubleStar(e) => {
+                        walk(e, c, f)
+                    }
+                }
+            }
+        }
+        Expr::Pipe { value, stage, .. } => {
+            walk(value, c, f);
+            walk(stage, c, f);
+        }
+        Expr::BinOp { left, right, .. } => {
+            walk(left, c, f);
+            walk(right, c, f);
+        }
+        Expr::BoolOp { left, right, .. } | Expr::Coalesce { left, right, .. } => {
+            walk(left, c, f);
+            walk(right, true, f);
+        }
+        Expr::Compare { left, ops } => {
+            walk(left, c, f);
+            for (i, (_, right)) in ops.iter().enumerate() {
+                walk(right, c || i > 0, f);
+            }
+        }
+        Expr::IfExp { test, body, orelse } => {
+            walk(test, c, f);
+            walk(body, true, f);
+            walk(orelse, true, f);
+        }
+        Expr::Lambda { params, body } => {
+            for p in params {
+                if let Some(d) = &p.default {
+                    walk(d, c, f);
+                }
+            }
+            walk(body, true, f);
+        }
+        // The body runs later, in a scope of its own
+        Expr::Function { params, .. } => {
+            for p in params {
+                if let Some(d) = &p.default {
+                    walk(d, c, f);
+                }
+            }
+        }
+        Expr::Comprehension {
+            elt,
+            value,
+            generators,
+            ..
+        } => {
+            for (i, g) in generators.iter().enumerate() {
+                walk(&g.iter, c || i > 0, f);
+                walk(&g.target, true, f);
+                for cond in &g.ifs {
+                    walk(cond, true, f);
+                }
+            }
+            walk(elt, true, f);
+            if let Some(v) = value {
+                walk(v, true, f);
+            }
+        }
+    }
+}
+
+/// Expressions that belong to `stmt` itself rather than to its nested blocks
+pub(crate) fn stmt_exprs(stmt: &Stmt) -> Vec<&Expr> {
+    match stmt {
+        Stmt::FunctionDef(def) => {
+            let mut exprs: Vec<&Expr> = def.decorators.iter().collect();
+            for p in &def.params {
+                exprs.extend(&p.annotation);
+                exprs.extend(&p.default);
+            }
+            exprs.extend(&def.returns);
+            exprs
+        }
+        Stmt::ClassDef(class) => {
+            let mut exprs: Vec<&Expr> = class.decorators.iter().collect();
+            for arg in &class.args {
+                match arg {
+                    Arg::Positional(e) | Arg::Keyword(_, e) | Arg::Star(e) | Arg::DoubleStar(e) => {
+                        exprs.push(e)
+                    }
+                }
+            }
+            exprs
+        }
+        Stmt::If(s) => {
+            let mut exprs = vec![&s.test];
+            exprs.extend(s.elifs.iter().map(|e| &e.test));
+            exprs
+        }
+        Stmt::While(w) | Stmt::DoWhile(w) => vec![&w.test],
+        Stmt::For(f) => [&f.target]
+            .into_iter()
+            .chain(&f.value)
+            .chain([&f.iter])
+            .collect(),
+        Stmt::CFor(c) => c.test.iter().collect(),
+        Stmt::Switch(s) => [&s.subject]
+            .into_iter()
+            .chain(s.cases.iter().flat_map(|c| &c.values))
+            .collect(),
+        Stmt::Match(m) => {
+            let mut exprs = vec![&m.subject];
+            for case in &m.cases {
+                pattern_exprs(&case.pattern, &mut exprs);
+                exprs.extend(&case.guard);
+            }
+            exprs
+        }
+        Stmt::Return(value) => value.iter().collect(),
+        Stmt::Assign(a) => a.targets.iter().chain([&a.value]).collect(),
+        Stmt::AugAssign(a) => vec![&a.target, &a.value],
+        Stmt::AnnAssign(a) => [&a.target, &a.annotation]
+            .into_iter()
+            .chain(&a.value)
+            .collect(),
+        Stmt::Expr { value, .. } => vec![value],
+        Stmt::Del(targets) => targets.iter().collect(),
+        Stmt::Assert { test, msg } => [test].into_iter().chain(msg).collect(),
+        Stmt::With(w) => w
+            .items
+            .iter()
+            .flat_map(|i| [&i.context].into_iter().chain(&i.target))
+            .collect(),
+        Stmt::Try(t) => t.handlers.iter().filter_map(|h| h.typ.as_ref()).collect(),
+        Stmt::Raise { exc, cause } => exc.iter().chain(cause).collect(),
+        Stmt::Import(_)
+        | Stmt::ImportFrom(_)
+        | Stmt::Global(_)
+        | Stmt::Nonlocal(_)
+        | Stmt::Pass
+        | Stmt::Break
+        | Stmt::Continue
+        | Stmt::Comment(_) => Vec::new(),
+    }
+}
+
+/// Values compared against and classes checked by a `case` pattern
+fn pattern_exprs<'s>(pattern: &'s Pattern, out: &mut Vec<&'s Expr>) {
+    match pattern {
+        Pattern::Value(value) => out.push(value),
+        Pattern::Sequence(items) | Pattern::Or(items) => {
+            items.iter().for_each(|p| pattern_exprs(p, out))
+        }
+        Pattern::Mapping { items, .. } => {
+            for (key, value) in items {
+                out.push(key);
+                pattern_exprs(value, out);
+            }
+        }
+        Pattern::Class { cls, args, kwargs } => {
+            out.push(cls);
+            args.iter().for_each(|p| pattern_exprs(p, out));
+            kwargs.iter().for_each(|(_, p)| pattern_exprs(p, out));
+        }
+        Pattern::As { pattern, .. } => pattern_exprs(pattern, out),
+        Pattern::Wildcard | Pattern::Capture(_) | Pattern::Star(_) => {}
+    }
+}
+
+/// Statement lists nested directly inside `stmt`
+pub(crate) fn stmt_blocks(stmt: &Stmt) -> Vec<&[Stmt]> {
+    match stmt {
+        Stmt::FunctionDef(def) => vec![&def.body],
+        Stmt::ClassDef(class) => vec![&class.body],
+        Stmt::If(s) => {
+            let mut blocks = vec![s.body.as_slice()];
+            blocks.extend(s.elifs.iter().map(|e| e.body.as_slice()));
+            blocks.extend(s.orelse.as_deref());
+            blocks
+        }
+        Stmt::While(w) | Stmt::DoWhile(w) => vec![&w.body],
+        Stmt::With(w) => vec![&w.body],
+        Stmt::Try(t) => {
+            let mut blocks = vec![t.body.as_slice()];
+            blocks.extend(t.handlers.iter().map(|h| h.body.as_slice()));
+            blocks.extend(t.orelse.as_deref());
+            blocks.extend(t.finalbody.as_deref());
+            blocks
+        }
+        Stmt::For(f) => vec![&f.body],
+        Stmt::CFor(c) => vec![&c.init, &c.body, &c.step],
+        Stmt::Switch(s) => {
+            let mut blocks: Vec<&[Stmt]> = s.cases.iter().map(|c| c.body.as_slice()).collect();
+            blocks.extend(s.default.as_deref());
+            blocks
+        }
+        Stmt::Match(m) => m.cases.iter().map(|c| c.body.as_slice()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Call `f` on every statement in `body` and in the blocks nested inside it
+pub(crate) fn walk_stmts<'s>(body: &'s [Stmt], f: &mut dyn FnMut(&'s Stmt)) {
+    for stmt in body {
+        f(stmt);
+        for block in stmt_blocks(stmt) {
+            walk_stmts(block, f);
+        }
+    }
+}
+
+/// For each `_` placeholder of a pipeline stage, whether it is evaluated
+/// conditionally. Those in lambdas and in the stages of nested pipelines
+/// belong to them, not to `stage`.
+pub(crate) fn placeholders(stage: &Expr) -> Vec<bool> {
+    let is_placeholder = |e: &Expr| matches!(e, Expr::Name(n) if n == "_");
+    let mut found = Vec::new();
+    let mut nested = Vec::new();
+    walk(stage, false, &mut |e, conditional| match e {
+        _ if is_placeholder(e) => found.push((e as *const Expr, conditional)),
+        Expr::Lambda { body: inner, .. } | Expr::Pipe { stage: inner, .. } => {
+            walk(inner, false, &mut |e, _| {
+                if is_placeholder(e) {
+                    nested.push(e as *const Expr)
+                }
+            })
+        }
+        _ => {}
+    });
+    found
+        .into_iter()
+        .filter(|(e, _)| !nested.contains(e))
+        .map(|(_, conditional)| conditional)
+        .collect()
+}
+
+/// Call `f` on every expression in `body`, including nested blocks
+pub(crate) fn walk_body_exprs<'s>(body: &'s [Stmt], f: &mut dyn FnMut(&'s Expr, bool)) {
+    walk_stmts(body, &mut |stmt| {
+        for e in stmt_exprs(stmt) {
+            walk(e, false, f);
+        }
+    });
+}